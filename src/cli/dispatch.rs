@@ -1,94 +1,317 @@
+use crate::cli::report::DnsChangeReport;
+use crate::cli::report::FailureReport;
+use crate::cli::report::FirewallReport;
+use crate::cli::report::HostnameReport;
+use crate::cli::report::InstanceReport;
+use crate::cli::report::Report;
+use crate::cli::report::RuleReport;
 use crate::cli::Command;
+use crate::cli::Options;
 use crate::cloud::Cloud;
 use crate::cloud::Firewall;
 use crate::cloud::Instance;
+use crate::cloud::InstanceRunningState;
+use crate::cloud::InstanceStateCode;
+use crate::cloud::InstanceStatus;
+use crate::cloud::InstanceType;
+use crate::cloud::Selector;
 use crate::dns::Dns;
 use crate::dns::DnsTarget;
 use crate::dns::DnsZone;
+use crate::future::join_results;
+use crate::future::BoxFuture;
+use crate::iprules::IpEgressRule;
 use crate::iprules::IpIngressRule;
+use crate::iprules::IpProtocol;
+use crate::iprules::IpRuleMeta;
+use crate::iprules::IpSource;
+use crate::wait;
 use failure::Error;
+use futures::future;
+use futures::Future;
+use ipnet::IpNet;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio_core::reactor::Core;
 
-pub fn dispatch<C, D>(cmd: Command, cloud: &C, dns: &D) -> Result<(), Error>
+// Each step runs the cloud and DNS operations it can together on the core
+pub fn dispatch<C, D>(
+    cmd: Command,
+    opts: &Options,
+    cloud: &C,
+    dns: &D,
+    core: &mut Core,
+) -> Result<Report, Error>
 where
     C: Cloud,
     D: Dns,
 {
-    println!("Running command: {:?}", cmd);
+    eprintln!("Running command: {:?}", cmd);
+    if opts.dry_run {
+        eprintln!("Dry run: no changes will be made");
+    }
+
+    let mut report = Report {
+        command: cmd.name().to_owned(),
+        dry_run: opts.dry_run,
+        ..Report::default()
+    };
+
+    run(&cmd, opts, cloud, dns, core, &mut report)?;
+    Ok(report)
+}
 
-    match cmd {
+// Adds the results to the report, so that the steps of up and down share one
+fn run<C, D>(
+    cmd: &Command,
+    opts: &Options,
+    cloud: &C,
+    dns: &D,
+    core: &mut Core,
+    report: &mut Report,
+) -> Result<(), Error>
+where
+    C: Cloud,
+    D: Dns,
+{
+    match *cmd {
         Command::Open {
             ref ip_cidrs,
             ref ip_protocols,
-            ref names,
+            expires_after,
+            additive,
+            egress,
+            ref owner,
+            any_owner,
+            ref selector,
         } => {
-            let desired_rules = {
-                let mut ip_rules = HashSet::new();
-                for ip_cidr in ip_cidrs {
-                    for ip_protocol in ip_protocols {
-                        ip_rules.insert(IpIngressRule(*ip_cidr, *ip_protocol));
-                    }
-                }
-                ip_rules
+            let meta = IpRuleMeta {
+                owner: owner.clone(),
+                expiry: expires_after.map(expiry_after).transpose()?,
+            };
+            let change = FirewallChange::Open {
+                ip_cidrs,
+                ip_protocols,
+                meta: &meta,
+                additive,
             };
 
-            let fws = cloud.list_firewalls(names)?;
-            println!("Found firewalls: {:?}", fws);
+            let fws = find_firewalls(core, cloud, selector, opts)?;
+            change_firewalls(core, report, fws, &change, egress, any_owner, opts.dry_run);
+        }
+        Command::Close {
+            ref only,
+            egress,
+            ref owner,
+            any_owner,
+            ref selector,
+        } => {
+            let meta = IpRuleMeta {
+                owner: owner.clone(),
+                expiry: None,
+            };
+            let change = match *only {
+                Some((ref ip_cidrs, ref ip_protocols)) => FirewallChange::Remove {
+                    ip_cidrs,
+                    ip_protocols,
+                    owner,
+                },
+                None => FirewallChange::Close { meta: &meta },
+            };
 
-            for fw in fws {
-                println!("Opening firewall: {:?}", fw);
-                sync_firewall_rules(fw, &desired_rules)?;
-            }
+            let fws = find_firewalls(core, cloud, selector, opts)?;
+            change_firewalls(core, report, fws, &change, egress, any_owner, opts.dry_run);
         }
-        Command::Close { ref names } => {
-            let desired_rules = HashSet::new();
+        Command::Reap { ref selector } => {
+            let now = SystemTime::now();
 
-            let fws = cloud.list_firewalls(names)?;
-            println!("Found firewalls: {:?}", fws);
+            let fws = find_firewalls(core, cloud, selector, opts)?;
 
             for fw in fws {
-                println!("Closing firewall: {:?}", fw);
-                sync_firewall_rules(fw, &desired_rules)?;
+                eprintln!("Reaping firewall: {:?}", fw);
+                let result = reap_firewall_rules::<_, IpIngressRule>(core, &fw, now, opts.dry_run)
+                    .and_then(|mut rules| {
+                        rules.extend(reap_firewall_rules::<_, IpEgressRule>(
+                            core,
+                            &fw,
+                            now,
+                            opts.dry_run,
+                        )?);
+                        Ok(rules)
+                    });
+                report_firewall(report, &fw, result);
             }
         }
         Command::Start {
             ref instance_type,
-            ref names,
+            wait,
+            dns_wait,
+            ref selector,
         } => {
-            let instances = cloud.list_instances(names)?;
-            println!("Found instances: {:?}", instances);
+            let instances = find_instances(core, cloud, selector, opts)?;
 
+            let mut startable = Vec::new();
             for instance in instances {
-                println!("Starting instance: {:?}", instance);
+                eprintln!("Starting instance: {:?}", instance);
 
-                if let &Some(ref instance_type) = instance_type {
-                    instance.try_ensure_instance_type(instance_type)?;
+                if opts.dry_run {
+                    let result = plan_start(core, dns, &instance, instance_type);
+                    report_instance(report, &instance, result);
+                    continue;
                 }
-                let state = instance.ensure_running()?;
-                println!(
-                    "Instance running with type: {} and address: {:?}",
-                    state.instance_type, state.addr
-                );
 
-                if let Some(fqdn) = instance.fqdn() {
-                    sync_dns(dns, fqdn, Some(state.addr))?;
+                let result = match *instance_type {
+                    Some(ref instance_type) => {
+                        core.run(instance.try_ensure_instance_type(instance_type))
+                    }
+                    None => Ok(()),
+                };
+                match result {
+                    Ok(()) => startable.push(instance),
+                    Err(error) => report_failure(report, instance.id(), instance.name(), error),
+                }
+            }
+
+            let started = match wait {
+                Some(timeout) => {
+                    // Each is bound in DNS as soon as it is running
+                    poll_instances(
+                        core,
+                        report,
+                        "instance to be running",
+                        timeout,
+                        startable,
+                        |instance| instance.poll_running(),
+                        |core, instance, state| bind_running(core, dns, instance, state),
+                    )?
                 }
+                None => {
+                    let requested = request_instances(core, report, startable, |instance| {
+                        instance.request_running()
+                    })?;
+                    let mut started = Vec::new();
+                    for (instance, status) in requested {
+                        match bind_started(core, dns, &instance, status) {
+                            Ok(value) => started.push((instance, value)),
+                            Err(error) => {
+                                report_failure(report, instance.id(), instance.name(), error)
+                            }
+                        }
+                    }
+                    started
+                }
+            };
+            for (instance, value) in wait_for_dns_changes(core, report, dns, dns_wait, started)? {
+                report_instance(report, &instance, Ok(value));
             }
         }
-        Command::Stop { ref names } => {
-            let instances = cloud.list_instances(names)?;
-            println!("Found instances: {:?}", instances);
+        Command::Stop {
+            wait,
+            dns_wait,
+            ref selector,
+        } => {
+            let instances = find_instances(core, cloud, selector, opts)?;
 
+            let mut unbound = Vec::new();
             for instance in instances {
-                println!("Stopping instance: {:?}", instance);
+                eprintln!("Stopping instance: {:?}", instance);
+
+                if opts.dry_run {
+                    let result = plan_stop(core, dns, &instance);
+                    report_instance(report, &instance, result);
+                    continue;
+                }
 
                 // Unbind DNS before stopping
-                if let Some(fqdn) = instance.fqdn() {
-                    sync_dns(dns, fqdn, None)?;
+                match sync_hostnames(core, dns, &instance, None) {
+                    Ok(changes) => unbound.push((instance, changes)),
+                    Err(error) => report_failure(report, instance.id(), instance.name(), error),
+                }
+            }
+
+            let mut stoppable = Vec::new();
+            for (instance, dns_changes) in
+                wait_for_dns_changes(core, report, dns, dns_wait, unbound)?
+            {
+                report.dns_changes.extend(dns_changes);
+                stoppable.push(instance);
+            }
+
+            match wait {
+                Some(timeout) => {
+                    let stopped = poll_instances(
+                        core,
+                        report,
+                        "instance to be stopped",
+                        timeout,
+                        stoppable,
+                        |instance| instance.poll_stopped(),
+                        |core, instance, ()| {
+                            eprintln!("Instance stopped: {:?}", instance);
+                            core.run(instance.get_status())
+                        },
+                    )?;
+                    for (instance, status) in stopped {
+                        report_instance(report, &instance, Ok((status, vec![])));
+                    }
                 }
+                None => {
+                    let requested = request_instances(core, report, stoppable, |instance| {
+                        instance.request_stopped()
+                    })?;
+                    for (instance, status) in requested {
+                        report_instance(report, &instance, Ok((status, vec![])));
+                    }
+                }
+            }
+        }
+        Command::Status { ref selector } => {
+            // A name need only match either a firewall or an instance
+            let (fws, instances) = core.run(
+                cloud
+                    .list_firewalls(selector)
+                    .join(cloud.list_instances(selector)),
+            )?;
+            eprintln!("Found firewalls: {:?}", fws);
+            eprintln!("Found instances: {:?}", instances);
+
+            let fw_resources = describe_resources(&fws, |x| (x.id(), x.name()));
+            let instance_resources = describe_resources(&instances, |x| (x.id(), x.name()));
+            let resources = [&fw_resources[..], &instance_resources[..]].concat();
+            check_matched(selector, "firewalls or instances", &resources)?;
+            if opts.unique {
+                check_unique(selector, "firewalls", &fw_resources)?;
+                check_unique(selector, "instances", &instance_resources)?;
+            }
+
+            for fw in fws {
+                let (rules, egress_rules) =
+                    core.run(fw.describe_ingress_rules().join(fw.describe_egress_rules()))?;
+                eprintln!("Firewall {:?} has rules: {:?}", fw, rules);
+                eprintln!("Firewall {:?} has egress rules: {:?}", fw, egress_rules);
+
+                let mut rule_reports = existing_rule_reports(&rules);
+                rule_reports.extend(existing_rule_reports(&egress_rules));
+                report
+                    .firewalls
+                    .push(FirewallReport::new(&fw, rule_reports));
+            }
 
-                instance.ensure_stopped()?;
-                println!("Instance stopped");
+            for instance in instances {
+                match describe_instance(core, dns, &instance) {
+                    Ok(instance_report) => report.instances.push(instance_report),
+                    Err(error) => report_failure(report, instance.id(), instance.name(), error),
+                }
+            }
+        }
+        Command::Up { ref steps, .. } | Command::Down { ref steps, .. } => {
+            for step in steps {
+                run(step, opts, cloud, dns, core, report)?;
             }
         }
     };
@@ -96,223 +319,2024 @@ where
     Ok(())
 }
 
-fn sync_firewall_rules<F>(fw: F, desired_rules: &HashSet<IpIngressRule>) -> Result<(), Error>
+fn describe_instance<D, I>(core: &mut Core, dns: &D, instance: &I) -> Result<InstanceReport, Error>
 where
-    F: Firewall,
+    D: Dns,
+    I: Instance,
 {
-    println!("Desired rules: {:?}", desired_rules);
+    let status = core.run(instance.get_status())?;
+    eprintln!(
+        "Instance {:?} is {} with type: {} and addresses: {:?}",
+        instance, status.state, status.instance_type, status.addrs
+    );
 
-    let existing_rules = fw.list_ingress_rules()?;
-    println!("Existing rules: {:?}", existing_rules);
+    let mut instance_report = InstanceReport::new(instance, status);
+    for hostname in hostnames(instance)? {
+        let dns_zone = core.run(dns.find_authoritative_zone(hostname.fqdn, hostname.vpc_id))?;
+        let targets = core.run(dns_zone.lookup(hostname.fqdn))?;
+        eprintln!(
+            "Hostname {} in DNS zone {:?} is bound to: {:?}",
+            hostname.fqdn, dns_zone, targets
+        );
+        instance_report
+            .hostnames
+            .push(HostnameReport::new(hostname.fqdn, &dns_zone, targets));
+    }
 
-    let missing_rules = desired_rules - &existing_rules;
-    println!("Adding rules: {:?}", missing_rules);
-    fw.add_ingress_rules(&missing_rules)?;
+    Ok(instance_report)
+}
 
-    let extra_rules = &existing_rules - desired_rules;
-    println!("Removing rules: {:?}", extra_rules);
-    fw.remove_ingress_rules(&extra_rules)?;
+fn find_firewalls<C>(
+    core: &mut Core,
+    cloud: &C,
+    selector: &Selector,
+    opts: &Options,
+) -> Result<Vec<C::Firewall>, Error>
+where
+    C: Cloud,
+{
+    let fws = core.run(cloud.list_firewalls(selector))?;
+    eprintln!("Found firewalls: {:?}", fws);
+    let resources = describe_resources(&fws, |x| (x.id(), x.name()));
+    check_matched(selector, "firewalls", &resources)?;
+    if opts.unique {
+        check_unique(selector, "firewalls", &resources)?;
+    }
+    Ok(fws)
+}
 
-    Ok(())
+fn find_instances<C>(
+    core: &mut Core,
+    cloud: &C,
+    selector: &Selector,
+    opts: &Options,
+) -> Result<Vec<C::Instance>, Error>
+where
+    C: Cloud,
+{
+    let instances = core.run(cloud.list_instances(selector))?;
+    eprintln!("Found instances: {:?}", instances);
+    let resources = describe_resources(&instances, |x| (x.id(), x.name()));
+    check_matched(selector, "instances", &resources)?;
+    if opts.unique {
+        check_unique(selector, "instances", &resources)?;
+    }
+    Ok(instances)
 }
 
-fn sync_dns<D>(dns: &D, fqdn: &str, target_or_none: Option<DnsTarget>) -> Result<(), Error>
+fn describe_resources<'a, T, F>(xs: &'a [T], f: F) -> Vec<(&'a str, &'a str)>
 where
-    D: Dns,
+    F: Fn(&'a T) -> (&'a str, &'a str),
 {
-    let dns_zone = dns.find_authoritative_zone(fqdn)?;
-    println!("Found authoritative DNS zone for {}: {:?}", fqdn, dns_zone);
+    xs.iter().map(f).collect()
+}
 
-    if let Some(target) = target_or_none {
-        dns_zone.bind(fqdn, target)?;
-        println!("Bound hostname: {}", fqdn);
-    } else {
-        dns_zone.unbind(fqdn)?;
-        println!("Unbound hostname: {}", fqdn);
+// Fails if any of the given IDs or names selected nothing, e.g. due to a typo
+fn check_matched(selector: &Selector, kind: &str, resources: &[(&str, &str)]) -> Result<(), Error> {
+    if selector.is_tags_only() {
+        if resources.is_empty() {
+            bail!("no {} match the given tags", kind);
+        }
+        return Ok(());
+    }
+    let unmatched = selector.unmatched_terms(resources);
+    if !unmatched.is_empty() {
+        bail!("no {} match: {}", kind, unmatched.join(", "));
     }
+    Ok(())
+}
 
+fn check_unique(selector: &Selector, kind: &str, resources: &[(&str, &str)]) -> Result<(), Error> {
+    let duplicates = selector.duplicate_names(resources);
+    if !duplicates.is_empty() {
+        bail!(
+            "more than one of the {} match: {}",
+            kind,
+            duplicates.join(", ")
+        );
+    }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::cloud::mem::MemCloud;
-    use crate::cloud::mem::MemInstance;
-    use crate::cloud::InstanceType;
-    use crate::dns::mem::MemDns;
-    use crate::iprules::IpProtocol;
-    use ipnet::IpNet;
+// What open and close do to each firewall, in either direction
+enum FirewallChange<'a> {
+    // the rules replace the managed rules, or if additive, are added to them
+    Open {
+        ip_cidrs: &'a [IpNet],
+        ip_protocols: &'a [IpProtocol],
+        meta: &'a IpRuleMeta,
+        additive: bool,
+    },
+    // only the rules are removed
+    Remove {
+        ip_cidrs: &'a [IpNet],
+        ip_protocols: &'a [IpProtocol],
+        owner: &'a Option<String>,
+    },
+    // all managed rules are removed
+    Close {
+        meta: &'a IpRuleMeta,
+    },
+}
 
-    // TODO(ques_in_main)
+// The direction chooses the rule type, once for all the firewalls
+fn change_firewalls<F>(
+    core: &mut Core,
+    report: &mut Report,
+    fws: Vec<F>,
+    change: &FirewallChange<'_>,
+    egress: bool,
+    any_owner: bool,
+    dry_run: bool,
+) where
+    F: Firewall,
+{
+    if egress {
+        change_firewalls_for::<IpEgressRule, F>(core, report, fws, change, any_owner, dry_run)
+    } else {
+        change_firewalls_for::<IpIngressRule, F>(core, report, fws, change, any_owner, dry_run)
+    }
+}
 
-    #[test]
-    fn test_open_firewall_with_no_existing_rules() {
-        test_open_firewall(
-            &[],
-            &["1.1.0.0/16".parse().unwrap(), "9.9.9.9/32".parse().unwrap()],
-            &["22/tcp".parse().unwrap(), "80/tcp".parse().unwrap()],
+fn change_firewalls_for<R, F>(
+    core: &mut Core,
+    report: &mut Report,
+    fws: Vec<F>,
+    change: &FirewallChange<'_>,
+    any_owner: bool,
+    dry_run: bool,
+) where
+    R: FirewallRule,
+    F: Firewall,
+{
+    for fw in fws {
+        let result = match *change {
+            FirewallChange::Open {
+                ip_cidrs,
+                ip_protocols,
+                meta,
+                additive,
+            } => {
+                eprintln!("Opening firewall: {:?}", fw);
+                let desired_rules = cross_rules::<R>(ip_cidrs, ip_protocols);
+                sync_firewall_rules(
+                    core,
+                    &fw,
+                    &desired_rules,
+                    meta,
+                    additive,
+                    any_owner,
+                    dry_run,
+                )
+            }
+            FirewallChange::Remove {
+                ip_cidrs,
+                ip_protocols,
+                owner,
+            } => {
+                eprintln!("Closing firewall: {:?}", fw);
+                let undesired_rules = cross_rules::<R>(ip_cidrs, ip_protocols);
+                remove_firewall_rules(core, &fw, &undesired_rules, owner, any_owner, dry_run)
+            }
+            FirewallChange::Close { meta } => {
+                eprintln!("Closing firewall: {:?}", fw);
+                let desired_rules = HashSet::<R>::new();
+                sync_firewall_rules(core, &fw, &desired_rules, meta, false, any_owner, dry_run)
+            }
+        };
+        report_firewall(report, &fw, result);
+    }
+}
+
+// Ingress and egress rules are opened, closed and reaped in the same way
+trait FirewallRule: Clone + Hash + Eq + fmt::Debug + Sized {
+    const DIRECTION: &'static str;
+
+    fn new(peer: IpSource, ip_protocol: IpProtocol) -> Self;
+    fn peer(&self) -> &IpSource;
+    fn ip_protocol(&self) -> &IpProtocol;
+    fn is_manageable(&self) -> bool;
+    fn describe<F: Firewall>(fw: &F) -> BoxFuture<HashMap<Self, Option<IpRuleMeta>>>;
+    fn add<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()>;
+    fn update<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()>;
+    fn remove<F: Firewall>(fw: &F, rules: &HashSet<Self>) -> BoxFuture<()>;
+}
+
+impl FirewallRule for IpIngressRule {
+    const DIRECTION: &'static str = "ingress";
+
+    fn new(source: IpSource, ip_protocol: IpProtocol) -> Self {
+        IpIngressRule(source, ip_protocol)
+    }
+
+    fn peer(&self) -> &IpSource {
+        &self.0
+    }
+
+    fn ip_protocol(&self) -> &IpProtocol {
+        &self.1
+    }
+
+    fn is_manageable(&self) -> bool {
+        IpIngressRule::is_manageable(self)
+    }
+
+    fn describe<F: Firewall>(fw: &F) -> BoxFuture<HashMap<Self, Option<IpRuleMeta>>> {
+        fw.describe_ingress_rules()
+    }
+
+    fn add<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()> {
+        fw.add_ingress_rules(rules, meta)
+    }
+
+    fn update<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()> {
+        fw.update_ingress_rules(rules, meta)
+    }
+
+    fn remove<F: Firewall>(fw: &F, rules: &HashSet<Self>) -> BoxFuture<()> {
+        fw.remove_ingress_rules(rules)
+    }
+}
+
+impl FirewallRule for IpEgressRule {
+    const DIRECTION: &'static str = "egress";
+
+    fn new(destination: IpSource, ip_protocol: IpProtocol) -> Self {
+        IpEgressRule(destination, ip_protocol)
+    }
+
+    fn peer(&self) -> &IpSource {
+        &self.0
+    }
+
+    fn ip_protocol(&self) -> &IpProtocol {
+        &self.1
+    }
+
+    fn is_manageable(&self) -> bool {
+        IpEgressRule::is_manageable(self)
+    }
+
+    fn describe<F: Firewall>(fw: &F) -> BoxFuture<HashMap<Self, Option<IpRuleMeta>>> {
+        fw.describe_egress_rules()
+    }
+
+    fn add<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()> {
+        fw.add_egress_rules(rules, meta)
+    }
+
+    fn update<F: Firewall>(fw: &F, rules: &HashSet<Self>, meta: &IpRuleMeta) -> BoxFuture<()> {
+        fw.update_egress_rules(rules, meta)
+    }
+
+    fn remove<F: Firewall>(fw: &F, rules: &HashSet<Self>) -> BoxFuture<()> {
+        fw.remove_egress_rules(rules)
+    }
+}
+
+fn rule_report<R>(change: &'static str, rule: &R, meta: Option<&IpRuleMeta>) -> RuleReport
+where
+    R: FirewallRule,
+{
+    RuleReport::new(change, R::DIRECTION, rule.peer(), rule.ip_protocol(), meta)
+}
+
+fn existing_rule_reports<R>(existing: &HashMap<R, Option<IpRuleMeta>>) -> Vec<RuleReport>
+where
+    R: FirewallRule,
+{
+    existing
+        .iter()
+        .map(|(rule, meta)| rule_report("existing", rule, meta.as_ref()))
+        .collect()
+}
+
+fn cross_rules<R>(ip_cidrs: &[IpNet], ip_protocols: &[IpProtocol]) -> HashSet<R>
+where
+    R: FirewallRule,
+{
+    let mut ip_rules = HashSet::new();
+    for ip_cidr in ip_cidrs {
+        for ip_protocol in ip_protocols.iter().filter(|x| x.applies_to(ip_cidr)) {
+            ip_rules.insert(R::new(IpSource::Cidr(*ip_cidr), ip_protocol.clone()));
+        }
+    }
+    ip_rules
+}
+
+// Whether an existing rule may be modified by a command run by the given owner.
+// Rules that drawbridge could not have created are never modified.
+fn is_managed<R>(
+    rule: &R,
+    existing_meta: &Option<IpRuleMeta>,
+    owner: &Option<String>,
+    any_owner: bool,
+) -> bool
+where
+    R: FirewallRule,
+{
+    match *existing_meta {
+        _ if !rule.is_manageable() => false,
+        _ if any_owner => true,
+        Some(ref existing_meta) => existing_meta.owner == *owner,
+        None => false,
+    }
+}
+
+// Only rules owned by meta.owner are updated or removed, unless any_owner.
+// If additive, existing rules that are not desired are left alone.
+fn sync_firewall_rules<F, R>(
+    core: &mut Core,
+    fw: &F,
+    desired_rules: &HashSet<R>,
+    meta: &IpRuleMeta,
+    additive: bool,
+    any_owner: bool,
+    dry_run: bool,
+) -> Result<Vec<RuleReport>, Error>
+where
+    F: Firewall,
+    R: FirewallRule,
+{
+    eprintln!("Desired rules: {:?} ({:?})", desired_rules, meta);
+
+    let existing = core.run(R::describe(fw))?;
+    eprintln!("Existing rules: {:?}", existing);
+
+    let existing_rules: HashSet<R> = existing.keys().cloned().collect();
+    let managed_rules: HashSet<R> = existing
+        .iter()
+        .filter(|(rule, existing_meta)| {
+            is_managed::<R>(rule, existing_meta, &meta.owner, any_owner)
+        })
+        .map(|(rule, _)| rule.clone())
+        .collect();
+    eprintln!("Unmanaged rules: {:?}", &existing_rules - &managed_rules);
+
+    let missing_rules = desired_rules - &existing_rules;
+    let extra_rules = if additive {
+        HashSet::new()
+    } else {
+        &managed_rules - desired_rules
+    };
+    // e.g. to extend or remove the expiry of a rule that is already open
+    let stale_rules: HashSet<R> = desired_rules
+        .intersection(&managed_rules)
+        .filter(|rule| existing[rule].as_ref() != Some(meta))
+        .cloned()
+        .collect();
+
+    let rule_reports = missing_rules
+        .iter()
+        .map(|rule| rule_report("added", rule, Some(meta)))
+        .chain(
+            stale_rules
+                .iter()
+                .map(|rule| rule_report("updated", rule, Some(meta))),
         )
-        .unwrap();
+        .chain(
+            extra_rules
+                .iter()
+                .map(|rule| rule_report("removed", rule, existing[rule].as_ref())),
+        )
+        .collect();
+
+    if dry_run {
+        eprintln!("Would add rules: {:?}", missing_rules);
+        eprintln!("Would update rules: {:?}", stale_rules);
+        eprintln!("Would remove rules: {:?}", extra_rules);
+        return Ok(rule_reports);
     }
 
-    #[test]
-    fn test_open_firewall_with_some_existing_rules() {
-        test_open_firewall(
-            &[
-                // wrong protocol: will be removed
-                IpIngressRule("9.9.9.9/32".parse().unwrap(), "443/tcp".parse().unwrap()),
-                // wrong CIDR: will be removed
-                IpIngressRule("1.1.1.1/32".parse().unwrap(), "80/tcp".parse().unwrap()),
-                // correct: will be preserved
-                IpIngressRule("1.1.0.0/16".parse().unwrap(), "22/tcp".parse().unwrap()),
-            ],
-            &["1.1.0.0/16".parse().unwrap(), "9.9.9.9/32".parse().unwrap()],
-            &["22/tcp".parse().unwrap(), "80/tcp".parse().unwrap()],
+    eprintln!("Adding rules: {:?}", missing_rules);
+    core.run(R::add(fw, &missing_rules, meta))?;
+
+    eprintln!("Updating rules: {:?}", stale_rules);
+    core.run(R::update(fw, &stale_rules, meta))?;
+
+    eprintln!("Removing rules: {:?}", extra_rules);
+    core.run(R::remove(fw, &extra_rules))?;
+
+    Ok(rule_reports)
+}
+
+fn remove_firewall_rules<F, R>(
+    core: &mut Core,
+    fw: &F,
+    undesired_rules: &HashSet<R>,
+    owner: &Option<String>,
+    any_owner: bool,
+    dry_run: bool,
+) -> Result<Vec<RuleReport>, Error>
+where
+    F: Firewall,
+    R: FirewallRule,
+{
+    eprintln!("Undesired rules: {:?}", undesired_rules);
+
+    let existing = core.run(R::describe(fw))?;
+    eprintln!("Existing rules: {:?}", existing);
+
+    let extra_rules: HashSet<R> = undesired_rules
+        .iter()
+        .filter(|rule| match existing.get(rule) {
+            Some(existing_meta) => is_managed::<R>(rule, existing_meta, owner, any_owner),
+            None => false,
+        })
+        .cloned()
+        .collect();
+
+    let rule_reports = extra_rules
+        .iter()
+        .map(|rule| rule_report("removed", rule, existing[rule].as_ref()))
+        .collect();
+
+    if dry_run {
+        eprintln!("Would remove rules: {:?}", extra_rules);
+        return Ok(rule_reports);
+    }
+
+    eprintln!("Removing rules: {:?}", extra_rules);
+    core.run(R::remove(fw, &extra_rules))?;
+
+    Ok(rule_reports)
+}
+
+fn reap_firewall_rules<F, R>(
+    core: &mut Core,
+    fw: &F,
+    now: SystemTime,
+    dry_run: bool,
+) -> Result<Vec<RuleReport>, Error>
+where
+    F: Firewall,
+    R: FirewallRule,
+{
+    let existing = core.run(R::describe(fw))?;
+    eprintln!("Existing rules: {:?}", existing);
+
+    let expired_rules: HashSet<R> = existing
+        .iter()
+        .filter(|(rule, meta)| match meta {
+            Some(meta) => rule.is_manageable() && meta.is_expired(now),
+            None => false,
+        })
+        .map(|(rule, _)| rule.clone())
+        .collect();
+
+    let rule_reports = expired_rules
+        .iter()
+        .map(|rule| rule_report("removed", rule, existing[rule].as_ref()))
+        .collect();
+
+    if dry_run {
+        eprintln!("Would remove expired rules: {:?}", expired_rules);
+        return Ok(rule_reports);
+    }
+
+    eprintln!("Removing expired rules: {:?}", expired_rules);
+    core.run(R::remove(fw, &expired_rules))?;
+
+    Ok(rule_reports)
+}
+
+// The duration is checked when parsed, so is out of range only if the clock is wrong
+fn expiry_after(duration: Duration) -> Result<SystemTime, Error> {
+    IpRuleMeta::expiry_after(SystemTime::now(), duration).ok_or_else(|| {
+        format_err!(
+            "expiry is out of range: {}",
+            humantime::format_duration(duration)
         )
-        .unwrap();
+    })
+}
+
+fn report_failure(report: &mut Report, id: &str, name: &str, error: Error) {
+    let failure = FailureReport::new(id, name, &error);
+    eprintln!("Failed for {} ({}): {}", name, id, failure.error);
+    report.failures.push(failure);
+}
+
+fn report_firewall<F>(report: &mut Report, fw: &F, result: Result<Vec<RuleReport>, Error>)
+where
+    F: Firewall,
+{
+    match result {
+        Ok(rules) => report.firewalls.push(FirewallReport::new(fw, rules)),
+        Err(error) => report_failure(report, fw.id(), fw.name(), error),
     }
+}
 
-    fn test_open_firewall(
-        existing_rules: &[IpIngressRule],
-        ip_cidrs: &[IpNet],
-        ip_protocols: &[IpProtocol],
-    ) -> Result<(), Error> {
-        let mut expected_rules = HashSet::new();
-        for ip_cidr in ip_cidrs {
-            for ip_protocol in ip_protocols {
-                expected_rules.insert(IpIngressRule(*ip_cidr, *ip_protocol));
+fn report_instance<I>(
+    report: &mut Report,
+    instance: &I,
+    result: Result<(InstanceStatus, Vec<DnsChangeReport>), Error>,
+) where
+    I: Instance,
+{
+    match result {
+        Ok((status, dns_changes)) => {
+            report.instances.push(InstanceReport::new(instance, status));
+            report.dns_changes.extend(dns_changes);
+        }
+        Err(error) => report_failure(report, instance.id(), instance.name(), error),
+    }
+}
+
+// Polls the instances together, rather than waiting for each in turn. After
+// each round, finish is called for those for which poll returned Some, so that
+// it does not hold up polling the others, and runs on the same core. A failure,
+// including a timeout, is reported for the instance, without affecting the
+// others.
+fn poll_instances<I, T, U, P, F>(
+    core: &mut Core,
+    report: &mut Report,
+    what: &str,
+    timeout: Duration,
+    instances: Vec<I>,
+    mut poll: P,
+    mut finish: F,
+) -> Result<Vec<(I, U)>, Error>
+where
+    I: Instance,
+    T: 'static,
+    P: FnMut(&I) -> BoxFuture<Option<T>>,
+    F: FnMut(&mut Core, &I, T) -> Result<U, Error>,
+{
+    let mut pending = instances;
+    let mut finished = Vec::new();
+    let mut backoff = wait::Backoff::new(timeout);
+    loop {
+        let results = core.run(join_results(pending.iter().map(&mut poll)))?;
+        let mut ready = Vec::new();
+        for (instance, result) in mem::take(&mut pending).into_iter().zip(results) {
+            match result {
+                Ok(Some(value)) => ready.push((instance, value)),
+                Ok(None) => pending.push(instance),
+                Err(error) => report_failure(report, instance.id(), instance.name(), error),
             }
         }
+        for (instance, value) in ready {
+            match finish(core, &instance, value) {
+                Ok(value) => finished.push((instance, value)),
+                Err(error) => report_failure(report, instance.id(), instance.name(), error),
+            }
+        }
+
+        if pending.is_empty() {
+            return Ok(finished);
+        }
+        if !backoff.sleep(core)? {
+            for instance in pending {
+                let error = wait::timeout_error(&format!("{}: {:?}", what, instance), timeout);
+                report_failure(report, instance.id(), instance.name(), error);
+            }
+            return Ok(finished);
+        }
+    }
+}
+
+// Requests the state change of all the instances together, without waiting
+// for it, and then gets their statuses together
+fn request_instances<I, R>(
+    core: &mut Core,
+    report: &mut Report,
+    instances: Vec<I>,
+    request: R,
+) -> Result<Vec<(I, InstanceStatus)>, Error>
+where
+    I: Instance,
+    R: FnMut(&I) -> BoxFuture<()>,
+{
+    let results = core.run(join_results(instances.iter().map(request)))?;
+    let mut requested = Vec::new();
+    for (instance, result) in instances.into_iter().zip(results) {
+        match result {
+            Ok(()) => {
+                eprintln!("Requested state change, without waiting: {:?}", instance);
+                requested.push(instance);
+            }
+            Err(error) => report_failure(report, instance.id(), instance.name(), error),
+        }
+    }
+
+    let results = core.run(join_results(requested.iter().map(|x| x.get_status())))?;
+    let mut statuses = Vec::new();
+    for (instance, result) in requested.into_iter().zip(results) {
+        match result {
+            Ok(status) => {
+                eprintln!(
+                    "Instance {:?} is {} with type: {} and addresses: {:?}",
+                    instance, status.state, status.instance_type, status.addrs
+                );
+                statuses.push((instance, status));
+            }
+            Err(error) => report_failure(report, instance.id(), instance.name(), error),
+        }
+    }
+    Ok(statuses)
+}
+
+// Waits for the DNS changes of all the instances together, against a deadline
+// of their own rather than what is left of the instances' timeout. An instance
+// whose changes do not all reach the name servers in time is reported as
+// failed, and the rest are returned.
+fn wait_for_dns_changes<D, I, T>(
+    core: &mut Core,
+    report: &mut Report,
+    dns: &D,
+    dns_wait: Option<Duration>,
+    instances: Vec<(I, (T, Vec<String>))>,
+) -> Result<Vec<(I, T)>, Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    let timeout = match dns_wait {
+        Some(timeout) => timeout,
+        None => {
+            return Ok(instances
+                .into_iter()
+                .map(|(instance, (value, _))| (instance, value))
+                .collect());
+        }
+    };
+
+    let what = "DNS changes to reach all name servers";
+    let mut pending = instances;
+    let mut synced = Vec::new();
+    let mut backoff = wait::Backoff::new(timeout);
+    loop {
+        let polls = pending.iter().map(|(_, (_, change_ids))| -> BoxFuture<_> {
+            let polls: Vec<_> = change_ids.iter().map(|x| dns.poll_change(x)).collect();
+            Box::new(future::join_all(polls))
+        });
+        let results = core.run(join_results(polls))?;
+        for ((instance, (value, change_ids)), result) in
+            mem::take(&mut pending).into_iter().zip(results)
+        {
+            match result {
+                Ok(polled) => {
+                    let still_pending: Vec<String> = change_ids
+                        .into_iter()
+                        .zip(polled)
+                        .filter(|(_, x)| x.is_none())
+                        .map(|(change_id, _)| change_id)
+                        .collect();
+                    if still_pending.is_empty() {
+                        synced.push((instance, value));
+                    } else {
+                        pending.push((instance, (value, still_pending)));
+                    }
+                }
+                Err(error) => report_failure(report, instance.id(), instance.name(), error),
+            }
+        }
+
+        if pending.is_empty() {
+            return Ok(synced);
+        }
+        if !backoff.sleep(core)? {
+            for (instance, _) in pending {
+                let error = wait::timeout_error(&format!("{}: {:?}", what, instance), timeout);
+                report_failure(report, instance.id(), instance.name(), error);
+            }
+            return Ok(synced);
+        }
+    }
+}
+
+// An instance's status and DNS changes, as reported
+type InstanceResult = (InstanceStatus, Vec<DnsChangeReport>);
+
+fn bind_running<D, I>(
+    core: &mut Core,
+    dns: &D,
+    instance: &I,
+    state: InstanceRunningState,
+) -> Result<(InstanceResult, Vec<String>), Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    eprintln!(
+        "Instance {:?} running with type: {} and addresses: {:?}",
+        instance, state.instance_type, state.addrs
+    );
+
+    let addrs = (&state.addrs[..], &state.private_addrs[..]);
+    let (dns_changes, change_ids) = sync_hostnames(core, dns, instance, Some(addrs))?;
+
+    let status = InstanceStatus {
+        state: InstanceStateCode::Running,
+        instance_type: state.instance_type,
+        addrs: state.addrs,
+        private_addrs: state.private_addrs,
+    };
+    Ok(((status, dns_changes), change_ids))
+}
+
+fn plan_start<D, I>(
+    core: &mut Core,
+    dns: &D,
+    instance: &I,
+    instance_type: &Option<InstanceType>,
+) -> Result<(InstanceStatus, Vec<DnsChangeReport>), Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    let status = core.run(instance.get_status())?;
+    eprintln!(
+        "Instance is {} with type: {} and addresses: {:?}",
+        status.state, status.instance_type, status.addrs
+    );
+
+    if let Some(ref instance_type) = *instance_type {
+        if status.instance_type != *instance_type {
+            eprintln!(
+                "Would change instance type: {} -> {}",
+                status.instance_type, instance_type
+            );
+        }
+    }
+
+    if status.state != InstanceStateCode::Running {
+        eprintln!("Would start instance");
+    }
+
+    let mut dns_changes = vec![];
+    for hostname in hostnames(instance)? {
+        let fqdn = hostname.fqdn;
+        let dns_zone = core.run(dns.find_authoritative_zone(fqdn, hostname.vpc_id))?;
+        eprintln!("Found authoritative DNS zone for {}: {:?}", fqdn, dns_zone);
+
+        let existing = core.run(dns_zone.lookup(fqdn))?;
+        let targets = hostname.targets(&status.addrs, &status.private_addrs);
+        // If the addresses are not yet assigned, the targets are unknown
+        if targets.is_empty() {
+            eprintln!(
+                "Would bind hostname: {} -> (addresses assigned on start)",
+                fqdn
+            );
+            dns_changes.push(DnsChangeReport::new(fqdn, &dns_zone, "bind", vec![]));
+        } else if same_targets(&existing, targets) {
+            eprintln!("Hostname already bound: {} -> {:?}", fqdn, existing);
+        } else {
+            eprintln!("Would bind hostname: {} -> {:?}", fqdn, targets);
+            let targets = targets.to_vec();
+            dns_changes.push(DnsChangeReport::new(fqdn, &dns_zone, "bind", targets));
+        }
+    }
+
+    Ok((status, dns_changes))
+}
+
+// The addresses are not known until the instance is running,
+// so the hostname is bound only if it is already running
+fn bind_started<D, I>(
+    core: &mut Core,
+    dns: &D,
+    instance: &I,
+    status: InstanceStatus,
+) -> Result<(InstanceResult, Vec<String>), Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    if status.state != InstanceStateCode::Running {
+        for hostname in hostnames(instance)? {
+            eprintln!(
+                "Hostname not bound, as instance is not yet running: {}",
+                hostname.fqdn
+            );
+        }
+        return Ok(((status, vec![]), vec![]));
+    }
+
+    let addrs = (&status.addrs[..], &status.private_addrs[..]);
+    let (dns_changes, change_ids) = sync_hostnames(core, dns, instance, Some(addrs))?;
+    Ok(((status, dns_changes), change_ids))
+}
+
+fn plan_stop<D, I>(
+    core: &mut Core,
+    dns: &D,
+    instance: &I,
+) -> Result<(InstanceStatus, Vec<DnsChangeReport>), Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    let status = core.run(instance.get_status())?;
+    eprintln!(
+        "Instance is {} with type: {} and addresses: {:?}",
+        status.state, status.instance_type, status.addrs
+    );
+
+    let mut dns_changes = vec![];
+    for hostname in hostnames(instance)? {
+        let fqdn = hostname.fqdn;
+        let dns_zone = core.run(dns.find_authoritative_zone(fqdn, hostname.vpc_id))?;
+        eprintln!("Found authoritative DNS zone for {}: {:?}", fqdn, dns_zone);
+
+        let existing = core.run(dns_zone.lookup(fqdn))?;
+        if !existing.is_empty() {
+            eprintln!("Would unbind hostname: {} -> {:?}", fqdn, existing);
+            dns_changes.push(DnsChangeReport::new(fqdn, &dns_zone, "unbind", existing));
+        }
+    }
+
+    if status.state != InstanceStateCode::Stopped {
+        eprintln!("Would stop instance");
+    }
+
+    Ok((status, dns_changes))
+}
+
+// Order does not matter, as the records are looked up by type
+fn same_targets(xs: &[DnsTarget], ys: &[DnsTarget]) -> bool {
+    xs.len() == ys.len() && xs.iter().all(|x| ys.contains(x))
+}
+
+// A hostname of an instance, and where it is bound
+struct Hostname<'a> {
+    fqdn: &'a str,
+    // set for a private hostname, bound in a private zone of the instance's VPC
+    vpc_id: Option<&'a str>,
+    ttl: u64,
+}
+
+impl<'a> Hostname<'a> {
+    // A private hostname is bound to the private addresses, so that lookups
+    // from within the VPC resolve to them instead of the public ones
+    fn targets<'b>(
+        &self,
+        addrs: &'b [DnsTarget],
+        private_addrs: &'b [DnsTarget],
+    ) -> &'b [DnsTarget] {
+        if self.vpc_id.is_some() {
+            private_addrs
+        } else {
+            addrs
+        }
+    }
+}
+
+fn hostnames<I: Instance>(instance: &I) -> Result<Vec<Hostname<'_>>, Error> {
+    let mut hostnames = vec![];
+    let ttl = instance.dns_options()?.ttl();
+    if let Some(fqdn) = instance.fqdn() {
+        hostnames.push(Hostname {
+            fqdn,
+            vpc_id: None,
+            ttl,
+        });
+    }
+    if let Some(fqdn) = instance.private_fqdn() {
+        let vpc_id = match instance.vpc_id() {
+            Some(vpc_id) => vpc_id,
+            None => bail!(
+                "instance has a private hostname but is not in a VPC: {:?}",
+                instance
+            ),
+        };
+        hostnames.push(Hostname {
+            fqdn,
+            vpc_id: Some(vpc_id),
+            ttl,
+        });
+    }
+    Ok(hostnames)
+}
+
+// Binds each of the instance's hostnames to the public or private addresses
+// given, or unbinds them if none are, without waiting for the changes
+fn sync_hostnames<D, I>(
+    core: &mut Core,
+    dns: &D,
+    instance: &I,
+    addrs_or_none: Option<(&[DnsTarget], &[DnsTarget])>,
+) -> Result<(Vec<DnsChangeReport>, Vec<String>), Error>
+where
+    D: Dns,
+    I: Instance,
+{
+    let mut dns_changes = vec![];
+    let mut change_ids = vec![];
+    for hostname in hostnames(instance)? {
+        let targets_or_none =
+            addrs_or_none.map(|(addrs, private_addrs)| hostname.targets(addrs, private_addrs));
+        let (dns_change, change_id) = sync_dns(core, dns, &hostname, targets_or_none)?;
+        dns_changes.push(dns_change);
+        change_ids.extend(change_id);
+    }
+    Ok((dns_changes, change_ids))
+}
+
+fn sync_dns<D>(
+    core: &mut Core,
+    dns: &D,
+    hostname: &Hostname<'_>,
+    targets_or_none: Option<&[DnsTarget]>,
+) -> Result<(DnsChangeReport, Option<String>), Error>
+where
+    D: Dns,
+{
+    let fqdn = hostname.fqdn;
+    let dns_zone = core.run(dns.find_authoritative_zone(fqdn, hostname.vpc_id))?;
+    eprintln!("Found authoritative DNS zone for {}: {:?}", fqdn, dns_zone);
+
+    if let Some(targets) = targets_or_none {
+        let change_id = core.run(dns_zone.bind(fqdn, targets, hostname.ttl))?;
+        eprintln!("Bound hostname: {}", fqdn);
+        let targets = targets.to_vec();
+        let dns_change = DnsChangeReport::new(fqdn, &dns_zone, "bind", targets);
+        Ok((dns_change, change_id))
+    } else {
+        let existing = core.run(dns_zone.lookup(fqdn))?;
+        let change_id = core.run(dns_zone.unbind(fqdn))?;
+        eprintln!("Unbound hostname: {}", fqdn);
+        let dns_change = DnsChangeReport::new(fqdn, &dns_zone, "unbind", existing);
+        Ok((dns_change, change_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::mem::MemCloud;
+    use crate::cloud::mem::MemInstance;
+    use crate::cloud::mem::MEM_VPC_ID;
+    use crate::cloud::DnsAddress;
+    use crate::cloud::DnsOptions;
+    use crate::dns::mem::MemDns;
+    use crate::dns::DEFAULT_TTL;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    // Each command runs on a core of its own
+    fn dispatch(
+        cmd: Command,
+        opts: &Options,
+        cloud: &MemCloud,
+        dns: &MemDns,
+    ) -> Result<Report, Error> {
+        super::dispatch(cmd, opts, cloud, dns, &mut Core::new()?)
+    }
+
+    // TODO(ques_in_main)
+
+    #[test]
+    fn test_open_firewall_with_no_existing_rules() {
+        test_open_firewall(
+            &[],
+            &["1.1.0.0/16".parse().unwrap(), "9.9.9.9/32".parse().unwrap()],
+            &["22/tcp".parse().unwrap(), "80/tcp".parse().unwrap()],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_open_firewall_with_some_existing_rules() {
+        test_open_firewall(
+            &[
+                // wrong protocol: will be removed
+                IpIngressRule("9.9.9.9/32".parse().unwrap(), "443/tcp".parse().unwrap()),
+                // wrong CIDR: will be removed
+                IpIngressRule("1.1.1.1/32".parse().unwrap(), "80/tcp".parse().unwrap()),
+                // correct: will be preserved
+                IpIngressRule("1.1.0.0/16".parse().unwrap(), "22/tcp".parse().unwrap()),
+            ],
+            &["1.1.0.0/16".parse().unwrap(), "9.9.9.9/32".parse().unwrap()],
+            &["22/tcp".parse().unwrap(), "80/tcp".parse().unwrap()],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_open_firewall_with_other_protocol() {
+        test_open_firewall(
+            &[],
+            &["1.1.0.0/16".parse().unwrap()],
+            &["proto-50".parse().unwrap()],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_open_firewall_for_ping_from_dual_stack_source() {
+        test_open_firewall_for_ping_from_dual_stack_source_impl().unwrap();
+    }
+
+    fn test_open_firewall_for_ping_from_dual_stack_source_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let dns = MemDns::new()?;
+
+        dispatch(
+            Command::Open {
+                ip_cidrs: vec![
+                    "1.1.1.1/32".parse().unwrap(),
+                    "2001:db8::/64".parse().unwrap(),
+                ],
+                ip_protocols: vec![
+                    "echo-request/icmp".parse().unwrap(),
+                    "echo-request/icmpv6".parse().unwrap(),
+                ],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        // each ICMP version is opened only to the addresses it can carry
+        let expected_rules: HashSet<IpIngressRule> = vec![
+            IpIngressRule(
+                "1.1.1.1/32".parse().unwrap(),
+                "echo-request/icmp".parse().unwrap(),
+            ),
+            IpIngressRule(
+                "2001:db8::/64".parse().unwrap(),
+                "echo-request/icmpv6".parse().unwrap(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        Ok(())
+    }
+
+    fn test_open_firewall(
+        existing_rules: &[IpIngressRule],
+        ip_cidrs: &[IpNet],
+        ip_protocols: &[IpProtocol],
+    ) -> Result<(), Error> {
+        let mut expected_rules = HashSet::new();
+        for ip_cidr in ip_cidrs {
+            for ip_protocol in ip_protocols {
+                expected_rules.insert(IpIngressRule(IpSource::Cidr(*ip_cidr), ip_protocol.clone()));
+            }
+        }
+
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        fw.add_ingress_rules(existing_rules, &IpRuleMeta::default())
+            .wait()?;
+
+        let dns = MemDns::new()?;
+
+        let cmd = Command::Open {
+            ip_cidrs: ip_cidrs.to_vec(),
+            ip_protocols: ip_protocols.to_vec(),
+            expires_after: None,
+            additive: false,
+            egress: false,
+            owner: None,
+            any_owner: false,
+            selector: Selector::new(vec!["fw"], vec![]),
+        };
+
+        // test that open command opens the firewall
+        dispatch(cmd, &Options::default(), &cloud, &dns)?;
+
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        // test that close command closes the firewall, and that it is idempotent
+        for _ in 0..2 {
+            dispatch(
+                Command::Close {
+                    only: None,
+                    egress: false,
+                    owner: None,
+                    any_owner: false,
+                    selector: Selector::new(vec!["fw"], vec![]),
+                },
+                &Options::default(),
+                &cloud,
+                &dns,
+            )?;
+
+            assert_eq!(HashSet::new(), fw.list_ingress_rules()?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_and_close_firewall_additively() {
+        test_open_and_close_firewall_additively_impl().unwrap();
+    }
+
+    fn test_open_and_close_firewall_additively_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        // opened by someone else: will be preserved
+        let other_rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(vec![&other_rule], &IpRuleMeta::default())
+            .wait()?;
+
+        let dns = MemDns::new()?;
+
+        let ip_cidrs: Vec<IpNet> = vec!["9.9.9.9/32".parse().unwrap()];
+        let ip_protocols: Vec<IpProtocol> = vec!["22/tcp".parse().unwrap()];
+
+        dispatch(
+            Command::Open {
+                ip_cidrs: ip_cidrs.clone(),
+                ip_protocols: ip_protocols.clone(),
+                expires_after: None,
+                additive: true,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let mut expected_rules = HashSet::new();
+        expected_rules.insert(other_rule.clone());
+        expected_rules.insert(IpIngressRule(
+            IpSource::Cidr(ip_cidrs[0]),
+            ip_protocols[0].clone(),
+        ));
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        // test that close command closes only the given rules, and that it is idempotent
+        for _ in 0..2 {
+            dispatch(
+                Command::Close {
+                    only: Some((ip_cidrs.clone(), ip_protocols.clone())),
+                    egress: false,
+                    owner: None,
+                    any_owner: false,
+                    selector: Selector::new(vec!["fw"], vec![]),
+                },
+                &Options::default(),
+                &cloud,
+                &dns,
+            )?;
+
+            let mut expected_rules = HashSet::new();
+            expected_rules.insert(other_rule.clone());
+            assert_eq!(expected_rules, fw.list_ingress_rules()?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_and_close_firewall_with_owners() {
+        test_open_and_close_firewall_with_owners_impl().unwrap();
+    }
+
+    fn test_open_and_close_firewall_with_owners_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let external_rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_external_ingress_rules(vec![&external_rule])?;
+        let alice_rule = IpIngressRule("2.2.2.2/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(
+            vec![&alice_rule],
+            &IpRuleMeta {
+                owner: Some("alice".to_owned()),
+                expiry: None,
+            },
+        )
+        .wait()?;
+        let bob_rule = IpIngressRule("3.3.3.3/32".parse().unwrap(), "22/tcp".parse().unwrap());
+
+        let dns = MemDns::new()?;
+
+        dispatch(
+            Command::Open {
+                ip_cidrs: vec!["3.3.3.3/32".parse().unwrap()],
+                ip_protocols: vec![bob_rule.1.clone()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: Some("bob".to_owned()),
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let mut expected_rules = HashSet::new();
+        expected_rules.insert(external_rule);
+        expected_rules.insert(alice_rule);
+        expected_rules.insert(bob_rule.clone());
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        dispatch(
+            Command::Close {
+                only: None,
+                egress: false,
+                owner: Some("bob".to_owned()),
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        expected_rules.remove(&bob_rule);
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        dispatch(
+            Command::Close {
+                only: None,
+                egress: false,
+                owner: None,
+                any_owner: true,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(HashSet::new(), fw.list_ingress_rules()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_and_close_firewall_egress() {
+        test_open_and_close_firewall_egress_impl().unwrap();
+    }
+
+    fn test_open_and_close_firewall_egress_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let ingress_rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(vec![&ingress_rule], &IpRuleMeta::default())
+            .wait()?;
+        let default_rule = IpEgressRule("0.0.0.0/0".parse().unwrap(), "all".parse().unwrap());
+        fw.add_external_egress_rules(vec![&default_rule])?;
+
+        let dns = MemDns::new()?;
+
+        // test that open --egress leaves ingress rules and unowned egress rules alone
+        dispatch(
+            Command::Open {
+                ip_cidrs: vec!["192.0.2.0/24".parse().unwrap()],
+                ip_protocols: vec!["443/tcp".parse().unwrap()],
+                expires_after: Some(Duration::from_secs(60)),
+                additive: false,
+                egress: true,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let mirror_rule = IpEgressRule("192.0.2.0/24".parse().unwrap(), "443/tcp".parse().unwrap());
+        let mut expected_egress_rules = HashSet::new();
+        expected_egress_rules.insert(default_rule.clone());
+        expected_egress_rules.insert(mirror_rule);
+        assert_eq!(expected_egress_rules, fw.list_egress_rules()?);
+        let mut expected_rules = HashSet::new();
+        expected_rules.insert(ingress_rule);
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        // test that close --egress removes only the egress rules
+        dispatch(
+            Command::Close {
+                only: None,
+                egress: true,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let mut expected_egress_rules = HashSet::new();
+        expected_egress_rules.insert(default_rule);
+        assert_eq!(expected_egress_rules, fw.list_egress_rules()?);
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reap_firewall_egress() {
+        test_reap_firewall_egress_impl().unwrap();
+    }
+
+    fn test_reap_firewall_egress_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let expired_rule =
+            IpEgressRule("192.0.2.0/24".parse().unwrap(), "443/tcp".parse().unwrap());
+        fw.add_egress_rules(
+            vec![&expired_rule],
+            &IpRuleMeta {
+                owner: None,
+                expiry: Some(SystemTime::now() - Duration::from_secs(60)),
+            },
+        )
+        .wait()?;
+
+        let dns = MemDns::new()?;
+
+        dispatch(
+            Command::Reap {
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(HashSet::new(), fw.list_egress_rules()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_and_close_firewall_preserves_unmanageable_rules() {
+        test_open_and_close_firewall_preserves_unmanageable_rules_impl().unwrap();
+    }
+
+    fn test_open_and_close_firewall_preserves_unmanageable_rules_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let group_rule = IpIngressRule("sg-123".parse().unwrap(), "22/tcp".parse().unwrap());
+        let prefix_list_rule = IpIngressRule("pl-123".parse().unwrap(), "all".parse().unwrap());
+        let unknown_protocol_rule = IpIngressRule(
+            "1.1.1.1/32".parse().unwrap(),
+            IpProtocol::Unknown("tcp from port None to port None".to_owned()),
+        );
+        let unknown_source_rule = IpIngressRule(
+            IpSource::Unknown("missing prefix list".to_owned()),
+            "22/tcp".parse().unwrap(),
+        );
+        let unmanageable_rules = vec![
+            group_rule,
+            prefix_list_rule,
+            unknown_protocol_rule,
+            unknown_source_rule,
+        ];
+        fw.add_external_ingress_rules(&unmanageable_rules)?;
+
+        let dns = MemDns::new()?;
+
+        dispatch(
+            Command::Open {
+                ip_cidrs: vec!["2.2.2.2/32".parse().unwrap()],
+                ip_protocols: vec!["22/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: true,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let mut expected_rules: HashSet<IpIngressRule> =
+            unmanageable_rules.iter().cloned().collect();
+        expected_rules.insert(IpIngressRule(
+            "2.2.2.2/32".parse().unwrap(),
+            "22/tcp".parse().unwrap(),
+        ));
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        dispatch(
+            Command::Close {
+                only: None,
+                egress: false,
+                owner: None,
+                any_owner: true,
+                selector: Selector::new(vec!["fw"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let expected_rules: HashSet<IpIngressRule> = unmanageable_rules.into_iter().collect();
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_firewall_for_duration() {
+        test_open_firewall_for_duration_impl().unwrap();
+    }
+
+    fn test_open_firewall_for_duration_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        // already open without expiry: expiry will be added
+        fw.add_ingress_rules(vec![&rule], &IpRuleMeta::default())
+            .wait()?;
+
+        let dns = MemDns::new()?;
+
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let cmd = Command::Open {
+            ip_cidrs: vec!["1.1.1.1/32".parse().unwrap()],
+            ip_protocols: vec!["22/tcp".parse().unwrap(), "80/tcp".parse().unwrap()],
+            expires_after: Some(Duration::from_secs(60 * 60)),
+            additive: false,
+            egress: false,
+            owner: None,
+            any_owner: false,
+            selector: Selector::new(vec!["fw"], vec![]),
+        };
+        dispatch(cmd, &Options::default(), &cloud, &dns)?;
+
+        let rules = fw.describe_ingress_rules().wait()?;
+        assert_eq!(2, rules.len());
+        for meta in rules.values() {
+            let expiry = meta.as_ref().and_then(|meta| meta.expiry).unwrap();
+            assert!(expiry > before + Duration::from_secs(60 * 60));
+            assert!(expiry <= SystemTime::now() + Duration::from_secs(60 * 60));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reap_removes_only_expired_rules() {
+        test_reap().unwrap();
+    }
+
+    fn test_reap() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let expired_rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        let unexpired_rule =
+            IpIngressRule("2.2.2.2/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        let permanent_rule =
+            IpIngressRule("3.3.3.3/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        let now = SystemTime::now();
+        fw.add_ingress_rules(
+            vec![&expired_rule],
+            &IpRuleMeta {
+                owner: None,
+                expiry: Some(now - Duration::from_secs(60)),
+            },
+        )
+        .wait()?;
+        fw.add_ingress_rules(
+            vec![&unexpired_rule],
+            &IpRuleMeta {
+                owner: None,
+                expiry: Some(now + Duration::from_secs(60)),
+            },
+        )
+        .wait()?;
+        fw.add_ingress_rules(vec![&permanent_rule], &IpRuleMeta::default())
+            .wait()?;
+
+        let dns = MemDns::new()?;
+
+        // test that reap command removes expired rules, and that it is idempotent
+        for _ in 0..2 {
+            dispatch(
+                Command::Reap {
+                    selector: Selector::new(vec!["fw"], vec![]),
+                },
+                &Options::default(),
+                &cloud,
+                &dns,
+            )?;
+
+            let mut expected_rules = HashSet::new();
+            expected_rules.insert(unexpired_rule.clone());
+            expected_rules.insert(permanent_rule.clone());
+            assert_eq!(expected_rules, fw.list_ingress_rules()?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_start_instance_that_is_stopped() {
+        test_start_instance(
+            |cloud| {
+                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
+                inst.poll_stopped().wait()?;
+                Ok(inst)
+            },
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_start_instance_that_is_stopped_with_other_instance_type() {
+        test_start_instance(
+            |cloud| {
+                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
+                inst.poll_stopped().wait()?;
+                Ok(inst)
+            },
+            Some(InstanceType::new("t2.large")),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_start_instance_that_is_already_started() {
+        test_start_instance(
+            |cloud| {
+                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
+                inst.poll_running().wait()?;
+                Ok(inst)
+            },
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_start_instance_that_is_already_started_with_other_instance_type() {
+        let err = test_start_instance(
+            |cloud| {
+                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
+                inst.poll_running().wait()?;
+                Ok(inst)
+            },
+            Some(InstanceType::new("t2.large")),
+        )
+        .unwrap_err();
+        assert_eq!(
+            "instance must be stopped to change its type",
+            err.to_string()
+        );
+    }
+
+    fn test_start_instance<F>(
+        instance_builder: F,
+        instance_type: Option<InstanceType>,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&MemCloud) -> Result<MemInstance, Error>,
+    {
+        let cloud = MemCloud::new()?;
+        let inst = instance_builder(&cloud)?;
+
+        let dns = MemDns::new()?;
+
+        let cmd = Command::Start {
+            instance_type: instance_type.clone(),
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["inst"], vec![]),
+        };
+
+        // test that start command starts the instance
+        let report = dispatch(cmd, &Options::default(), &cloud, &dns)?;
+        if let Some(failure) = report.failures.first() {
+            bail!("{}", failure.error);
+        }
+
+        let running_state = inst.try_get_running_state()?;
+        assert_eq!(true, running_state.is_some()); // i.e. running
+        if let &Some(ref instance_type) = &instance_type {
+            assert_eq!(*instance_type, running_state.unwrap().instance_type);
+        }
+
+        // test that stop command stops the instance, and that it is idempotent
+        for _ in 0..2 {
+            dispatch(
+                Command::Stop {
+                    wait: Some(TIMEOUT),
+                    dns_wait: Some(TIMEOUT),
+                    selector: Selector::new(vec!["inst"], vec![]),
+                },
+                &Options::default(),
+                &cloud,
+                &dns,
+            )?;
+
+            let running_state = inst.try_get_running_state()?;
+            assert_eq!(true, running_state.is_none()); // i.e. stopped
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_up_and_down() {
+        test_up_and_down_impl().unwrap();
+    }
+
+    fn test_up_and_down_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("fw")?;
+        let inst = cloud.create_instance(
+            "inst",
+            Some("inst.example.com"),
+            &InstanceType::new("t2.medium"),
+        )?;
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+        let ip_cidr: IpNet = "1.1.1.1/32".parse()?;
+        let ip_protocol: IpProtocol = "22/tcp".parse()?;
+
+        // test that up starts the instance and opens the firewall, in one report
+        let report = dispatch(
+            Command::Up {
+                target: "devbox".to_owned(),
+                steps: vec![
+                    Command::Start {
+                        instance_type: None,
+                        wait: Some(TIMEOUT),
+                        dns_wait: Some(TIMEOUT),
+                        selector: Selector::new(vec!["inst"], vec![]),
+                    },
+                    Command::Open {
+                        ip_cidrs: vec![ip_cidr],
+                        ip_protocols: vec![ip_protocol.clone()],
+                        expires_after: None,
+                        additive: false,
+                        egress: false,
+                        owner: None,
+                        any_owner: false,
+                        selector: Selector::new(vec!["fw"], vec![]),
+                    },
+                ],
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+        assert_eq!("up", report.command);
+        assert_eq!(1, report.instances.len());
+        assert_eq!(1, report.firewalls.len());
+        let running_state = inst.try_get_running_state()?.unwrap();
+        assert_eq!(running_state.addrs, zone.lookup("inst.example.com").wait()?);
+        let expected_rules: HashSet<_> = vec![IpIngressRule(IpSource::Cidr(ip_cidr), ip_protocol)]
+            .into_iter()
+            .collect();
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+
+        // test that down closes the firewall and stops the instance
+        dispatch(
+            Command::Down {
+                target: "devbox".to_owned(),
+                steps: vec![
+                    Command::Close {
+                        only: None,
+                        egress: false,
+                        owner: None,
+                        any_owner: false,
+                        selector: Selector::new(vec!["fw"], vec![]),
+                    },
+                    Command::Stop {
+                        wait: Some(TIMEOUT),
+                        dns_wait: Some(TIMEOUT),
+                        selector: Selector::new(vec!["inst"], vec![]),
+                    },
+                ],
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+        assert!(inst.try_get_running_state()?.is_none());
+        assert!(fw.list_ingress_rules()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_start_and_stop_instance_without_waiting() {
+        test_start_and_stop_without_waiting().unwrap();
+    }
+
+    fn test_start_and_stop_without_waiting() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let inst =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+
+        let report = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: None,
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        // the in-memory instance is running as soon as it is requested, so DNS is bound
+        let running_state = inst.try_get_running_state()?.unwrap();
+        assert_eq!(running_state.addrs, zone.lookup("x.example.com").wait()?);
+        assert_eq!(InstanceStateCode::Running, report.instances[0].status.state);
+
+        dispatch(
+            Command::Stop {
+                wait: None,
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(None, inst.try_get_running_state()?);
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            zone.lookup("x.example.com").wait()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_private_hostname_to_private_zone() {
+        test_bind_split_horizon().unwrap();
+    }
+
+    fn test_bind_split_horizon() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let inst = cloud.create_instance_with_private_fqdn(
+            "x",
+            Some("x.example.com"),
+            Some("x.example.com"),
+            &InstanceType::new("t2.medium"),
+        )?;
+
+        let dns = MemDns::new()?;
+        let public_zone = dns.create_dns_zone("example.com")?;
+        let private_zone = dns.create_private_dns_zone("example.com", MEM_VPC_ID)?;
+        // a private zone of another VPC is not used
+        let other_zone = dns.create_private_dns_zone("example.com", "vpc-2")?;
+
+        let report = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        let running_state = inst.try_get_running_state()?.unwrap();
+        assert_ne!(running_state.addrs, running_state.private_addrs);
+        assert_eq!(
+            running_state.addrs,
+            public_zone.lookup("x.example.com").wait()?
+        );
+        assert_eq!(
+            running_state.private_addrs,
+            private_zone.lookup("x.example.com").wait()?
+        );
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            other_zone.lookup("x.example.com").wait()?
+        );
+        assert_eq!(2, report.dns_changes.len());
+
+        dispatch(
+            Command::Stop {
+                wait: Some(TIMEOUT),
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            public_zone.lookup("x.example.com").wait()?
+        );
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            private_zone.lookup("x.example.com").wait()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_with_dns_options() {
+        test_bind_dns_options().unwrap();
+    }
 
+    fn test_bind_dns_options() -> Result<(), Error> {
         let cloud = MemCloud::new()?;
-        let fw = cloud.create_firewall("fw")?;
-        fw.add_ingress_rules(existing_rules)?;
+        let inst =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+        inst.set_dns_options(DnsOptions {
+            ttl: Some(300),
+            address: DnsAddress::Private,
+            ..Default::default()
+        })?;
 
         let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
 
-        let cmd = Command::Open {
-            ip_cidrs: ip_cidrs.to_vec(),
-            ip_protocols: ip_protocols.to_vec(),
-            names: vec!["fw".to_owned()],
-        };
-
-        // test that open command opens the firewall
-        dispatch(cmd, &cloud, &dns)?;
-
-        assert_eq!(expected_rules, fw.list_ingress_rules()?);
-
-        // test that close command closes the firewall, and that it is idempotent
-        for _ in 0..2 {
-            dispatch(
-                Command::Close {
-                    names: vec!["fw".to_owned()],
-                },
-                &cloud,
-                &dns,
-            )?;
+        dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
 
-            assert_eq!(HashSet::new(), fw.list_ingress_rules()?);
-        }
+        let running_state = inst.try_get_running_state()?.unwrap();
+        assert_eq!(
+            running_state.private_addrs,
+            zone.lookup("x.example.com").wait()?
+        );
+        assert_eq!(Some(300), zone.lookup_ttl("x.example.com"));
 
         Ok(())
     }
 
     #[test]
-    fn test_start_instance_that_is_stopped() {
-        test_start_instance(
-            |cloud| {
-                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
-                inst.ensure_stopped()?;
-                Ok(inst)
-            },
-            None,
-        )
-        .unwrap();
+    fn test_start_instances_despite_failure() {
+        test_start_despite_failure().unwrap();
     }
 
-    #[test]
-    fn test_start_instance_that_is_stopped_with_other_instance_type() {
-        test_start_instance(
-            |cloud| {
-                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
-                inst.ensure_stopped()?;
-                Ok(inst)
+    fn test_start_despite_failure() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let inst_x =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+        let inst_y =
+            cloud.create_instance("y", Some("y.example.com"), &InstanceType::new("t2.medium"))?;
+        // the type of a running instance cannot be changed
+        inst_x.poll_running().wait()?;
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+
+        let report = dispatch(
+            Command::Start {
+                instance_type: Some(InstanceType::new("t2.large")),
+                wait: Some(TIMEOUT),
+                dns_wait: None,
+                selector: Selector::new(vec!["x", "y"], vec![]),
             },
-            Some(InstanceType::new("t2.large")),
-        )
-        .unwrap();
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(1, report.failures.len());
+        assert_eq!(inst_x.id(), report.failures[0].id);
+        assert_eq!(
+            "instance must be stopped to change its type",
+            report.failures[0].error
+        );
+        assert!(!report.failures[0].timed_out);
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            zone.lookup("x.example.com").wait()?
+        );
+
+        let running_state = inst_y.try_get_running_state()?.unwrap();
+        assert_eq!(running_state.addrs, zone.lookup("y.example.com").wait()?);
+        assert_eq!(1, report.instances.len());
+        assert_eq!(inst_y.id(), report.instances[0].id);
+
+        Ok(())
     }
 
     #[test]
-    fn test_start_instance_that_is_already_started() {
-        test_start_instance(
-            |cloud| {
-                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
-                inst.ensure_running()?;
-                Ok(inst)
-            },
-            None,
-        )
-        .unwrap();
+    fn test_start_instances_despite_invalid_dns_tags() {
+        test_start_despite_invalid_dns_tags().unwrap();
     }
 
-    #[test]
-    fn test_start_instance_that_is_already_started_with_other_instance_type() {
-        let err = test_start_instance(
-            |cloud| {
-                let inst = cloud.create_instance("inst", None, &InstanceType::new("t2.medium"))?;
-                inst.ensure_running()?;
-                Ok(inst)
+    fn test_start_despite_invalid_dns_tags() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let inst_x =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+        let inst_y =
+            cloud.create_instance("y", Some("y.example.com"), &InstanceType::new("t2.medium"))?;
+        inst_x.set_invalid_dns_options("DnsTtl is not a number of seconds: 1m")?;
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+
+        let select = || Selector::new(vec!["x", "y"], vec![]);
+        let report = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: None,
+                selector: select(),
             },
-            Some(InstanceType::new("t2.large")),
-        )
-        .unwrap_err();
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(1, report.failures.len());
+        assert_eq!(inst_x.id(), report.failures[0].id);
         assert_eq!(
-            "instance must be stopped to change its type",
-            err.to_string()
+            "DnsTtl is not a number of seconds: 1m",
+            report.failures[0].error
         );
+        let running_state = inst_y.try_get_running_state()?.unwrap();
+        assert_eq!(running_state.addrs, zone.lookup("y.example.com").wait()?);
+        assert_eq!(1, report.instances.len());
+        assert_eq!(inst_y.id(), report.instances[0].id);
+
+        // the status of the others is still reported
+        let report = dispatch(
+            Command::Status { selector: select() },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+        assert_eq!(1, report.failures.len());
+        assert_eq!(inst_x.id(), report.failures[0].id);
+        assert_eq!(1, report.instances.len());
+        assert_eq!(inst_y.id(), report.instances[0].id);
+
+        Ok(())
     }
 
-    fn test_start_instance<F>(
-        instance_builder: F,
-        instance_type: Option<InstanceType>,
-    ) -> Result<(), Error>
-    where
-        F: FnOnce(&MemCloud) -> Result<MemInstance, Error>,
-    {
+    #[test]
+    fn test_start_instances_with_dns_changes_not_in_sync() {
+        test_start_with_dns_changes_not_in_sync().unwrap();
+    }
+
+    fn test_start_with_dns_changes_not_in_sync() -> Result<(), Error> {
         let cloud = MemCloud::new()?;
-        let inst = instance_builder(&cloud)?;
+        let inst_x =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+        let inst_y =
+            cloud.create_instance("y", Some("y.example.com"), &InstanceType::new("t2.medium"))?;
 
         let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+        dns.set_changes_in_sync(false);
 
-        let cmd = Command::Start {
-            instance_type: instance_type.clone(),
-            names: vec!["inst".to_owned()],
-        };
-
-        // test that start command starts the instance
-        dispatch(cmd, &cloud, &dns)?;
+        let report = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: Some(Duration::from_millis(10)),
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
 
-        let running_state = inst.try_get_running_state()?;
-        assert_eq!(true, running_state.is_some()); // i.e. running
-        if let &Some(ref instance_type) = &instance_type {
-            assert_eq!(*instance_type, running_state.unwrap().instance_type);
+        // both are started and bound, but time out waiting for DNS
+        for inst in &[&inst_x, &inst_y] {
+            let running_state = inst.try_get_running_state()?.unwrap();
+            assert_eq!(
+                running_state.addrs,
+                zone.lookup(inst.fqdn().unwrap()).wait()?
+            );
         }
-
-        // test that stop command stops the instance, and that it is idempotent
-        for _ in 0..2 {
-            dispatch(
-                Command::Stop {
-                    names: vec!["inst".to_owned()],
-                },
-                &cloud,
-                &dns,
-            )?;
-
-            let running_state = inst.try_get_running_state()?;
-            assert_eq!(true, running_state.is_none()); // i.e. stopped
+        assert_eq!(2, report.failures.len());
+        for failure in &report.failures {
+            assert!(failure.timed_out);
+            assert!(failure
+                .error
+                .contains("DNS changes to reach all name servers"));
         }
+        assert!(report.instances.is_empty());
 
         Ok(())
     }
@@ -353,6 +2377,410 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn test_select_by_glob_tag_and_id() {
+        test_select_by_glob_tag_and_id_impl().unwrap();
+    }
+
+    fn test_select_by_glob_tag_and_id_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let web_staging = cloud.create_firewall("web-1")?;
+        web_staging.add_tag("env", "staging")?;
+        let web_prod = cloud.create_firewall("web-2")?;
+        web_prod.add_tag("env", "prod")?;
+        let db_staging = cloud.create_firewall("db-1")?;
+        db_staging.add_tag("env", "staging")?;
+
+        let dns = MemDns::new()?;
+
+        dispatch(
+            Command::Open {
+                ip_cidrs: vec!["1.1.1.1/32".parse().unwrap()],
+                ip_protocols: vec!["22/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(
+                    vec!["web-*"],
+                    vec![("env".to_owned(), "staging".to_owned())],
+                ),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(1, web_staging.list_ingress_rules()?.len());
+        assert_eq!(0, web_prod.list_ingress_rules()?.len());
+        assert_eq!(0, db_staging.list_ingress_rules()?.len());
+
+        let inst_x = cloud.create_instance("x", None, &InstanceType::new("t2.medium"))?;
+        inst_x.add_tag("env", "staging")?;
+        let inst_y = cloud.create_instance("y", None, &InstanceType::new("t2.medium"))?;
+
+        dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: Some(TIMEOUT),
+                selector: Selector::new(vec![inst_y.id()], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert!(inst_x.try_get_running_state()?.is_none());
+        assert!(inst_y.try_get_running_state()?.is_some());
+
+        // tags alone select every resource with those tags
+        dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: Some(TIMEOUT),
+                selector: Selector::new(
+                    Vec::<String>::new(),
+                    vec![("env".to_owned(), "staging".to_owned())],
+                ),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert!(inst_x.try_get_running_state()?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmatched_and_duplicate_names() {
+        test_unmatched_and_duplicate_names_impl().unwrap();
+    }
+
+    fn test_unmatched_and_duplicate_names_impl() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("x")?;
+        let inst = cloud.create_instance("x", None, &InstanceType::new("t2.medium"))?;
+        cloud.create_instance("y", None, &InstanceType::new("t2.medium"))?;
+        cloud.create_instance("y", None, &InstanceType::new("t2.medium"))?;
+
+        let dns = MemDns::new()?;
+
+        // a typo fails before any changes are made
+        let err = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: Some(TIMEOUT),
+                selector: Selector::new(vec!["x", "z", "w-*"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )
+        .unwrap_err();
+        assert_eq!("no instances match: z, w-*", err.to_string());
+        assert!(inst.try_get_running_state()?.is_none());
+
+        // an ID of the wrong kind matches nothing
+        let err = dispatch(
+            Command::Reap {
+                selector: Selector::new(vec![fw.id(), inst.id()], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("no firewalls match: {}", inst.id()),
+            err.to_string()
+        );
+
+        // a name need only match one kind for status
+        dispatch(
+            Command::Status {
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        // duplicates are only an error if requested
+        let cmd = || Command::Stop {
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["y"], vec![]),
+        };
+        dispatch(cmd(), &Options::default(), &cloud, &dns)?;
+        let opts = Options {
+            unique: true,
+            ..Options::default()
+        };
+        let err = dispatch(cmd(), &opts, &cloud, &dns).unwrap_err();
+        assert_eq!("more than one of the instances match: y", err.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_does_not_change_anything() {
+        test_status().unwrap();
+    }
+
+    fn test_status() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("x")?;
+        let rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(vec![&rule], &IpRuleMeta::default())
+            .wait()?;
+        let inst =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+        let running_state = inst.poll_running().wait()?.unwrap();
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+        zone.bind("x.example.com", &running_state.addrs, DEFAULT_TTL)
+            .wait()?;
+
+        let cmd = Command::Status {
+            selector: Selector::new(vec!["x"], vec![]),
+        };
+
+        let report = dispatch(cmd, &Options::default(), &cloud, &dns)?;
+
+        let mut expected_rules = HashSet::new();
+        expected_rules.insert(rule.clone());
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+        assert_eq!(Some(running_state.clone()), inst.try_get_running_state()?);
+        assert_eq!(running_state.addrs, zone.lookup("x.example.com").wait()?);
+
+        assert_eq!(1, report.instances.len());
+        let hostnames = &report.instances[0].hostnames;
+        assert_eq!(1, hostnames.len());
+        assert_eq!("x.example.com", hostnames[0].fqdn);
+        assert_eq!("example.com", hostnames[0].zone);
+        assert_eq!(running_state.addrs, hostnames[0].targets);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_describes_changes() {
+        test_report().unwrap();
+    }
+
+    fn test_report() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("x")?;
+        let old_rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(vec![&old_rule], &IpRuleMeta::default())
+            .wait()?;
+        let inst =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+
+        let dns = MemDns::new()?;
+        dns.create_dns_zone("example.com")?;
+
+        let report = dispatch(
+            Command::Open {
+                ip_cidrs: vec!["9.9.9.9/32".parse().unwrap()],
+                ip_protocols: vec!["22/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: Some("alice".to_owned()),
+                any_owner: true,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!("open", report.command);
+        assert_eq!(1, report.firewalls.len());
+        assert_eq!(fw.id(), report.firewalls[0].id);
+        let mut changes: Vec<_> = report.firewalls[0]
+            .rules
+            .iter()
+            .map(|x| (x.change, x.peer.as_str(), x.owner.as_deref()))
+            .collect();
+        changes.sort();
+        assert_eq!(
+            vec![
+                ("added", "9.9.9.9/32", Some("alice")),
+                ("removed", "1.1.1.1/32", None),
+            ],
+            changes
+        );
+
+        let report = dispatch(
+            Command::Start {
+                instance_type: None,
+                wait: Some(TIMEOUT),
+                dns_wait: Some(TIMEOUT),
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            &Options::default(),
+            &cloud,
+            &dns,
+        )?;
+
+        assert_eq!(1, report.instances.len());
+        assert_eq!(InstanceStateCode::Running, report.instances[0].status.state);
+        assert_eq!(1, report.dns_changes.len());
+        assert_eq!("bind", report.dns_changes[0].change);
+        assert_eq!("example.com", report.dns_changes[0].zone);
+        assert_eq!(
+            report.instances[0].status.addrs,
+            report.dns_changes[0].targets
+        );
+
+        let json = serde_json::to_value(&report)?;
+        assert_eq!("t2.medium", json["instances"][0]["instance_type"]);
+        assert_eq!("A", json["dns_changes"][0]["targets"][0]["type"]);
+
+        let text = report.to_string();
+        assert!(text.starts_with(&format!("Instance x ({}): running t2.medium", inst.id())));
+        assert!(text.contains("Bound hostname x.example.com in example.com: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_does_not_change_anything() {
+        test_dry_run().unwrap();
+    }
+
+    fn test_dry_run() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let fw = cloud.create_firewall("x")?;
+        let rule = IpIngressRule("1.1.1.1/32".parse().unwrap(), "22/tcp".parse().unwrap());
+        fw.add_ingress_rules(vec![&rule], &IpRuleMeta::default())
+            .wait()?;
+        let inst =
+            cloud.create_instance("x", Some("x.example.com"), &InstanceType::new("t2.medium"))?;
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+
+        let opts = Options {
+            dry_run: true,
+            ..Options::default()
+        };
+        let cmds = vec![
+            Command::Open {
+                ip_cidrs: vec!["9.9.9.9/32".parse().unwrap()],
+                ip_protocols: vec!["80/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Command::Close {
+                only: None,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Command::Start {
+                instance_type: Some(InstanceType::new("t2.large")),
+                wait: Some(TIMEOUT),
+                dns_wait: Some(TIMEOUT),
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        ];
+        for cmd in cmds {
+            dispatch(cmd, &opts, &cloud, &dns)?;
+        }
+
+        let mut expected_rules = HashSet::new();
+        expected_rules.insert(rule.clone());
+        assert_eq!(expected_rules, fw.list_ingress_rules()?);
+        assert_eq!(None, inst.try_get_running_state()?);
+        assert_eq!(
+            InstanceType::new("t2.medium"),
+            inst.get_status().wait()?.instance_type
+        );
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            zone.lookup("x.example.com").wait()?
+        );
+
+        // a running instance should not be stopped or unbound
+        let running_state = inst.poll_running().wait()?.unwrap();
+        zone.bind("x.example.com", &running_state.addrs, DEFAULT_TTL)
+            .wait()?;
+        let cmd = Command::Stop {
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["x"], vec![]),
+        };
+        dispatch(cmd, &opts, &cloud, &dns)?;
+
+        assert_eq!(Some(running_state.clone()), inst.try_get_running_state()?);
+        assert_eq!(running_state.addrs, zone.lookup("x.example.com").wait()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_dual_stack_hostname() {
+        test_bind_dual_stack_dns().unwrap();
+    }
+
+    fn test_bind_dual_stack_dns() -> Result<(), Error> {
+        let cloud = MemCloud::new()?;
+        let inst = cloud.create_instance(
+            "inst",
+            Some("inst.example.com"),
+            &InstanceType::new("t2.medium"),
+        )?;
+        inst.assign_ipv6_addr("2001:db8::1".parse().unwrap())?;
+
+        let dns = MemDns::new()?;
+        let zone = dns.create_dns_zone("example.com")?;
+
+        let cmd = Command::Start {
+            instance_type: None,
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["inst"], vec![]),
+        };
+
+        // test that start command binds both A and AAAA records
+        dispatch(cmd, &Options::default(), &cloud, &dns)?;
+
+        let targets = zone.lookup("inst.example.com").wait()?;
+        assert_eq!(2, targets.len());
+        assert!(targets.iter().any(|x| x.record_type() == "A"));
+        assert!(targets.contains(&DnsTarget::Aaaa("2001:db8::1".parse().unwrap())));
+
+        // test that stop command unbinds both records
+        let cmd = Command::Stop {
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["inst"], vec![]),
+        };
+        dispatch(cmd, &Options::default(), &cloud, &dns)?;
+
+        assert_eq!(
+            Vec::<DnsTarget>::new(),
+            zone.lookup("inst.example.com").wait()?
+        );
+
+        Ok(())
+    }
+
     fn test_bind_dns(
         inst_fqdn: &str,
         zone_fqdn: &str,
@@ -371,32 +2799,43 @@ mod tests {
 
         let cmd = Command::Start {
             instance_type: None,
-            names: vec!["inst".to_owned()],
+            wait: Some(TIMEOUT),
+            dns_wait: Some(TIMEOUT),
+            selector: Selector::new(vec!["inst"], vec![]),
         };
 
         // test that start command binds the DNS
-        dispatch(cmd, &cloud, &dns)?;
+        dispatch(cmd, &Options::default(), &cloud, &dns)?;
 
         let running_state = inst.try_get_running_state()?;
         assert_eq!(true, running_state.is_some()); // i.e. running
-        assert_eq!(Some(running_state.unwrap().addr), zone.lookup(inst_fqdn)?);
+        assert_eq!(running_state.unwrap().addrs, zone.lookup(inst_fqdn).wait()?);
         for other_zone in &other_zones {
-            assert_eq!(None, other_zone.lookup(inst_fqdn)?);
+            assert_eq!(
+                Vec::<DnsTarget>::new(),
+                other_zone.lookup(inst_fqdn).wait()?
+            );
         }
 
         // test that stop command unbinds the DNS, and that it is idempotent
         for _ in 0..2 {
             dispatch(
                 Command::Stop {
-                    names: vec!["inst".to_owned()],
+                    wait: Some(TIMEOUT),
+                    dns_wait: Some(TIMEOUT),
+                    selector: Selector::new(vec!["inst"], vec![]),
                 },
+                &Options::default(),
                 &cloud,
                 &dns,
             )?;
 
-            assert_eq!(None, zone.lookup(inst_fqdn)?);
+            assert_eq!(Vec::<DnsTarget>::new(), zone.lookup(inst_fqdn).wait()?);
             for other_zone in &other_zones {
-                assert_eq!(None, other_zone.lookup(inst_fqdn)?);
+                assert_eq!(
+                    Vec::<DnsTarget>::new(),
+                    other_zone.lookup(inst_fqdn).wait()?
+                );
             }
         }
 
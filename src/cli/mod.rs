@@ -1,28 +1,110 @@
 mod dispatch;
 mod parse;
+mod report;
 
 pub use crate::cli::dispatch::dispatch;
 pub use crate::cli::parse::parse_from_safe;
+pub use crate::cli::report::describe_error;
+pub use crate::cli::report::ErrorReport;
+pub use crate::cli::report::Report;
 
+use crate::cloud::aws::Regions;
 use crate::cloud::InstanceType;
+use crate::cloud::Selector;
 use crate::iprules::IpProtocol;
 use ipnet::IpNet;
+use std::time::Duration;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Command {
     Open {
         ip_cidrs: Vec<IpNet>,
         ip_protocols: Vec<IpProtocol>,
-        names: Vec<String>,
+        expires_after: Option<Duration>,
+        // if set, rules not in the given set are left alone
+        additive: bool,
+        // if set, the rules allow outbound traffic to ip_cidrs, rather than inbound traffic
+        egress: bool,
+        owner: Option<String>,
+        // if set, rules of other owners (or not created by drawbridge) may be removed
+        any_owner: bool,
+        selector: Selector,
     },
     Close {
-        names: Vec<String>,
+        // if set, only these rules are removed, and other rules are left alone
+        only: Option<(Vec<IpNet>, Vec<IpProtocol>)>,
+        // if set, egress rules are removed, rather than ingress rules
+        egress: bool,
+        owner: Option<String>,
+        // if set, rules of other owners (or not created by drawbridge) may be removed
+        any_owner: bool,
+        selector: Selector,
     },
     Start {
         instance_type: Option<InstanceType>,
-        names: Vec<String>,
+        // how long to wait for the instance to be running, or if not set, do not wait
+        wait: Option<Duration>,
+        // how long to wait for DNS changes to reach all name servers, or if not set, do not wait
+        dns_wait: Option<Duration>,
+        selector: Selector,
     },
     Stop {
-        names: Vec<String>,
+        // how long to wait for the instance to be stopped, or if not set, do not wait
+        wait: Option<Duration>,
+        // how long to wait for DNS changes to reach all name servers, or if not set, do not wait
+        dns_wait: Option<Duration>,
+        selector: Selector,
     },
+    Status {
+        selector: Selector,
+    },
+    Reap {
+        selector: Selector,
+    },
+    // A target from the config file, expanded into start and open
+    Up {
+        target: String,
+        steps: Vec<Command>,
+    },
+    // A target from the config file, expanded into close and stop
+    Down {
+        target: String,
+        steps: Vec<Command>,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Open { .. } => "open",
+            Command::Close { .. } => "close",
+            Command::Start { .. } => "start",
+            Command::Stop { .. } => "stop",
+            Command::Status { .. } => "status",
+            Command::Reap { .. } => "reap",
+            Command::Up { .. } => "up",
+            Command::Down { .. } => "down",
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Options {
+    pub dry_run: bool,
+    // if set, a name that matches more than one firewall or instance is an error
+    pub unique: bool,
+    pub output: OutputFormat,
+    pub regions: Regions,
+    // the AWS profiles for firewalls and instances, and for DNS, respectively,
+    // or if not set, the default credentials
+    pub profile: Option<String>,
+    pub dns_profile: Option<String>,
+}
+
+// Progress is always printed to stderr, so that stdout holds only the result
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
 }
@@ -1,15 +1,23 @@
 use crate::cli::Command;
+use crate::cli::Options;
+use crate::cli::OutputFormat;
+use crate::cloud::aws::Regions;
 use crate::cloud::InstanceType;
+use crate::cloud::Selector;
+use crate::config::Config;
 use crate::iprules::IpProtocol;
+use crate::iprules::IpRuleMeta;
 use clap::App;
 use clap::AppSettings;
 use clap::Arg;
+use clap::ArgMatches;
 use clap::SubCommand;
 use failure::Error;
 use failure::ResultExt;
 use futures;
 use futures::Future;
 use futures::Stream;
+use hyper::client::HttpConnector;
 use hyper::Client;
 use hyper::StatusCode;
 use ipnet::IpNet;
@@ -17,104 +25,212 @@ use ipnet::Ipv4Net;
 use ipnet::Ipv6Net;
 use std::ffi::OsString;
 use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use std::path::Path;
 use std::str;
 use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
 use tokio_core::reactor::Core;
 
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 fn define_app<'a, 'b>() -> App<'a, 'b> {
     let open_command = SubCommand::with_name("open")
         .setting(AppSettings::DeriveDisplayOrder)
         .arg(
             Arg::with_name("name")
-                .help("Names of firewalls to open.\n")
-                .required(true)
+                .help("Names or IDs of firewalls to open, where names may contain * and ?.\n")
+                .required_unless("tag")
                 .multiple(true)
                 .index(1),
         )
+        .arg(tag_arg())
         .arg(
-            Arg::with_name("protocol")
-                .help(
-                    "Protocol to allow through the firewall. Examples:\n\
-                     * ssh\n\
-                     * mosh\n\
-                     * http\n\
-                     * https\n\
-                     * 22/tcp\n\
-                     * 60000-61000/udp\n\
-                     ",
-                )
-                .next_line_help(true)
-                .short("p")
-                .long("protocol")
-                .takes_value(true)
+            protocol_arg(
+                "Protocol to allow through the firewall. Aliases may be defined in the \
+                 [aliases] section of the config file. Examples:\n\
+                 * ssh, mosh, http, https, rdp, postgres, jupyter, wireguard, ping\n\
+                 * 22/tcp\n\
+                 * 60000-61000/udp\n\
+                 * icmp, echo-request/icmp, 3:4/icmp, icmpv6\n\
+                 * all\n\
+                 ",
+            )
+            .required(true),
+        )
+        .arg(
+            source_arg(
+                "Source IP address (or CIDR network) to allow through the firewall.\n\
+                 Examples:\n\
+                 * self (alias for your IPv4 and IPv6 addresses, as indicated by \
+                 checkip.amazonaws.com and api6.ipify.org)\n\
+                 * 192.0.2.1\n\
+                 * 192.0.2.0/24\n\
+                 ",
+            )
+            .required(true),
+        )
+        .arg(ipv6_prefix_arg())
+        .arg(for_arg())
+        .arg(
+            Arg::with_name("add")
+                .help("Add the given rules without removing the other rules on the firewall.\n")
+                .long("add"),
+        )
+        .arg(egress_arg(
+            "Allow outbound traffic to the --source addresses, rather than inbound traffic \
+             from them, e.g. to reach a package mirror during maintenance.\n",
+        ))
+        .arg(owner_arg())
+        .arg(any_owner_arg());
+
+    let close_command = SubCommand::with_name("close")
+        .setting(AppSettings::DeriveDisplayOrder)
+        .arg(
+            Arg::with_name("name")
+                .help("Names or IDs of firewalls to close, where names may contain * and ?.\n")
+                .required_unless("tag")
                 .multiple(true)
-                .require_delimiter(true)
-                .required(true),
+                .index(1),
         )
+        .arg(tag_arg())
         .arg(
-            Arg::with_name("source")
+            Arg::with_name("only")
                 .help(
-                    "Source IP address (or CIDR network) to allow through the firewall.\n\
-                     Examples:\n\
-                     * self (alias for your IPv4 address, as indicated by checkip.amazonaws.com)\n\
-                     * 192.0.2.1\n\
-                     * 192.0.2.0/24\n\
-                     ",
+                    "Remove only the given rules, leaving the other rules on the firewall \
+                     alone. Requires --protocol and --source.\n",
                 )
-                .next_line_help(true)
-                .short("s")
-                .long("source")
-                .takes_value(true)
-                .multiple(true)
-                .require_delimiter(true)
-                .required(true),
-        );
+                .long("only")
+                .requires_all(&["protocol", "source"]),
+        )
+        .arg(protocol_arg("Protocol to remove from the firewall, as for open.\n").requires("only"))
+        .arg(
+            source_arg(
+                "Source IP address (or CIDR network) to remove from the firewall, as for open.\n",
+            )
+            .requires("only"),
+        )
+        .arg(ipv6_prefix_arg())
+        .arg(egress_arg(
+            "Remove egress rules, rather than ingress rules.\n",
+        ))
+        .arg(owner_arg())
+        .arg(any_owner_arg());
 
-    let close_command = SubCommand::with_name("close")
+    let start_command = SubCommand::with_name("start")
         .setting(AppSettings::DeriveDisplayOrder)
         .arg(
             Arg::with_name("name")
-                .help("Names of firewalls to close.\n")
-                .required(true)
+                .help("Names or IDs of instances to start, where names may contain * and ?.\n")
+                .required_unless("tag")
                 .multiple(true)
                 .index(1),
-        );
+        )
+        .arg(tag_arg())
+        .arg(instance_type_arg())
+        .arg(timeout_arg(
+            "How long to wait for the instances to be running, and then for DNS changes to \
+             reach all name servers, e.g. 5m. Defaults to 10m. On timeout, the exit code \
+             is 124.\n",
+        ))
+        .arg(no_wait_arg(
+            "Request that the instances start, without waiting for them to be running. \
+             DNS is bound only for instances that are already running.\n",
+        ))
+        .arg(no_dns_wait_arg());
 
-    let start_command = SubCommand::with_name("start")
+    let stop_command = SubCommand::with_name("stop")
         .setting(AppSettings::DeriveDisplayOrder)
         .arg(
             Arg::with_name("name")
-                .help("Names of instances to start.\n")
-                .required(true)
+                .help("Names or IDs of instances to stop, where names may contain * and ?.\n")
+                .required_unless("tag")
                 .multiple(true)
                 .index(1),
         )
+        .arg(tag_arg())
+        .arg(timeout_arg(
+            "How long to wait for DNS changes to reach all name servers, and then for the \
+             instances to be stopped, e.g. 5m. Defaults to 10m. On timeout, the exit code \
+             is 124.\n",
+        ))
+        .arg(no_wait_arg(
+            "Request that the instances stop, without waiting for them to be stopped, or for \
+             DNS changes to reach all name servers.\n",
+        ))
+        .arg(no_dns_wait_arg());
+
+    let reap_command = SubCommand::with_name("reap")
+        .setting(AppSettings::DeriveDisplayOrder)
         .arg(
-            Arg::with_name("instance-type")
+            Arg::with_name("name")
                 .help(
-                    "Desired instance type. Note that changing the instance type typically \
-                     requires the instance to be stopped. Examples:\n\
-                     * t2.nano\n\
-                     * m3.medium\n\
-                     * c5.large\n\
-                     ",
+                    "Names or IDs of firewalls from which to remove expired rules, where names may contain * and ?.\n",
                 )
-                .next_line_help(true)
-                .short("t")
-                .long("instance-type")
-                .takes_value(true),
-        );
+                .required_unless("tag")
+                .multiple(true)
+                .index(1),
+        )
+        .arg(tag_arg());
 
-    let stop_command = SubCommand::with_name("stop")
+    let up_command = SubCommand::with_name("up")
+        .setting(AppSettings::DeriveDisplayOrder)
+        .arg(target_arg(
+            "Name of a target in the [targets] section of the config file, whose instances \
+             are started and whose firewalls are opened. The options below override those \
+             of the target.\n",
+        ))
+        .arg(protocol_arg(
+            "Protocol to allow through the firewalls, as for open. Defaults to the protocols \
+             of the target.\n",
+        ))
+        .arg(source_arg(
+            "Source IP address (or CIDR network) to allow through the firewalls, as for open. \
+             Defaults to the sources of the target.\n",
+        ))
+        .arg(ipv6_prefix_arg())
+        .arg(for_arg())
+        .arg(owner_arg())
+        .arg(instance_type_arg())
+        .arg(timeout_arg(
+            "How long to wait for the instances to be running, and then for DNS changes to \
+             reach all name servers, as for start.\n",
+        ))
+        .arg(no_wait_arg(
+            "Request that the instances start, without waiting for them to be running, as \
+             for start.\n",
+        ))
+        .arg(no_dns_wait_arg());
+
+    let down_command = SubCommand::with_name("down")
+        .setting(AppSettings::DeriveDisplayOrder)
+        .arg(target_arg(
+            "Name of a target in the [targets] section of the config file, whose firewalls \
+             are closed and whose instances are stopped.\n",
+        ))
+        .arg(owner_arg())
+        .arg(timeout_arg(
+            "How long to wait for DNS changes to reach all name servers, and then for the \
+             instances to be stopped, as for stop.\n",
+        ))
+        .arg(no_wait_arg(
+            "Request that the instances stop, without waiting for them to be stopped, as for \
+             stop.\n",
+        ))
+        .arg(no_dns_wait_arg());
+
+    let status_command = SubCommand::with_name("status")
         .setting(AppSettings::DeriveDisplayOrder)
         .arg(
             Arg::with_name("name")
-                .help("Names of instances to stop.\n")
-                .required(true)
+                .help(
+                    "Names or IDs of firewalls and instances to report on, where names may contain * and ?.\n",
+                )
+                .required_unless("tag")
                 .multiple(true)
                 .index(1),
-        );
+        )
+        .arg(tag_arg());
 
     App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -123,142 +239,659 @@ fn define_app<'a, 'b>() -> App<'a, 'b> {
         .setting(AppSettings::GlobalVersion)
         .setting(AppSettings::VersionlessSubcommands)
         .setting(AppSettings::DeriveDisplayOrder)
+        .arg(
+            Arg::with_name("dry-run")
+                .help(
+                    "Print the changes that would be made to firewalls, instances and DNS, \
+                     without making them.\n",
+                )
+                .long("dry-run")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("unique")
+                .help(
+                    "Fail if a name matches more than one firewall or instance, e.g. due to \
+                     duplicate Name tags.\n",
+                )
+                .long("unique")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("Format of the result printed to stdout. Progress is printed to stderr.\n")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("region")
+                .help(
+                    "AWS region of the firewalls and instances, which may be given more than \
+                     once. Defaults to $AWS_DEFAULT_REGION.\n",
+                )
+                .long("region")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("all-regions")
+                .help("Find firewalls and instances in all regions enabled for the account.\n")
+                .long("all-regions")
+                .conflicts_with("region")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help(
+                    "AWS profile in ~/.aws/config or ~/.aws/credentials, which may assume a \
+                     role. Defaults to the default credentials.\n",
+                )
+                .long("profile")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dns-profile")
+                .help(
+                    "AWS profile for DNS, e.g. if the hosted zones are in another account. \
+                     Defaults to the profile for firewalls and instances.\n",
+                )
+                .long("dns-profile")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help(
+                    "Path to the config file. Defaults to ~/.config/drawbridge/config.toml, \
+                     if it exists.\n",
+                )
+                .long("config")
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(open_command)
         .subcommand(close_command)
         .subcommand(start_command)
         .subcommand(stop_command)
+        .subcommand(reap_command)
+        .subcommand(status_command)
+        .subcommand(up_command)
+        .subcommand(down_command)
+}
+
+fn target_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("target").help(help).required(true).index(1)
+}
+
+fn instance_type_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("instance-type")
+        .help(
+            "Desired instance type. Note that changing the instance type typically \
+             requires the instance to be stopped. Examples:\n\
+             * t2.nano\n\
+             * m3.medium\n\
+             * c5.large\n\
+             ",
+        )
+        .next_line_help(true)
+        .short("t")
+        .long("instance-type")
+        .takes_value(true)
+}
+
+fn for_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("for")
+        .help(
+            "Duration after which the opened rules expire, and are removed by the \
+             reap command. Examples:\n\
+             * 30m\n\
+             * 2h\n\
+             * 1day\n\
+             ",
+        )
+        .next_line_help(true)
+        .long("for")
+        .takes_value(true)
+}
+
+fn protocol_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("protocol")
+        .help(help)
+        .next_line_help(true)
+        .short("p")
+        .long("protocol")
+        .takes_value(true)
+        .multiple(true)
+        .require_delimiter(true)
+}
+
+fn source_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("source")
+        .help(help)
+        .next_line_help(true)
+        .short("s")
+        .long("source")
+        .takes_value(true)
+        .multiple(true)
+        .require_delimiter(true)
+}
+
+fn ipv6_prefix_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("ipv6-prefix")
+        .help(
+            "Prefix length to use for your IPv6 address when substituting self, e.g. 64 to \
+             allow your whole IPv6 network. Defaults to 128.\n",
+        )
+        .long("ipv6-prefix")
+        .takes_value(true)
+}
+
+fn tag_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("tag")
+        .help(
+            "Select only resources with the given tag, as KEY=VALUE. The value may contain \
+             the wildcards * and ?. May be given more than once, in which case all tags \
+             must match. If no names are given, all resources with the tags are selected.\n",
+        )
+        .long("tag")
+        .takes_value(true)
+        .number_of_values(1)
+        .multiple(true)
+}
+
+fn timeout_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("timeout")
+        .help(help)
+        .long("timeout")
+        .takes_value(true)
+}
+
+fn no_wait_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("no-wait")
+        .help(help)
+        .long("no-wait")
+        .conflicts_with("timeout")
+}
+
+fn no_dns_wait_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("no-dns-wait")
+        .help(
+            "Do not wait for DNS changes to reach all name servers, which typically takes \
+             under a minute.\n",
+        )
+        .long("no-dns-wait")
+}
+
+fn egress_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("egress").help(help).long("egress")
+}
+
+fn owner_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("owner")
+        .help(
+            "Owner recorded on the rules that are added. Only rules created by drawbridge \
+             with the same owner are updated or removed.\n",
+        )
+        .long("owner")
+        .takes_value(true)
 }
 
-pub fn parse_from_safe<I, T>(args: I) -> Result<Command, Error>
+fn any_owner_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("any-owner")
+        .help(
+            "Also update or remove rules with other owners, including rules not created by \
+             drawbridge.\n",
+        )
+        .long("any-owner")
+}
+
+// The core looks up own IP addresses, for self
+pub fn parse_from_safe<I, T>(args: I, core: &mut Core) -> Result<(Command, Options), Error>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
 {
     let app = define_app();
     let matches = app.get_matches_from_safe(args)?;
+    let config = Config::load(value_of_anywhere(&matches, "config").map(Path::new))?;
+    parse_matches(&matches, &config, core)
+}
 
+fn parse_matches(
+    matches: &ArgMatches<'_>,
+    config: &Config,
+    core: &mut Core,
+) -> Result<(Command, Options), Error> {
     let cmd = if let Some(matches) = matches.subcommand_matches("open") {
-        let ip_protocols = matches
-            .values_of("protocol")
-            .expect("required")
-            .map(|x| {
-                let y = match x {
-                    "ssh" => "22/tcp",
-                    "mosh" => "60000-61000/udp",
-                    "http" => "80/tcp",
-                    "https" => "443/tcp",
-                    x => x,
-                };
-                if y != x {
-                    println!("Substituted: {} -> {}", x, y);
-                }
-                IpProtocol::from_str(y).with_context(|_e| format!("not a protocol: {}", y))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        let include_own_ip_addr = matches
-            .values_of("source")
-            .expect("required")
-            .any(|x| x == "self");
-
-        let mut ip_cidrs = matches
-            .values_of("source")
-            .expect("required")
-            .filter(|&x| x != "self")
-            .map(|x| {
-                if x.contains('/') {
-                    IpNet::from_str(x).with_context(|_e| format!("not an IP network: {}", x))
-                } else {
-                    IpAddr::from_str(x)
-                        .with_context(|_e| format!("not an IP address: {}", x))
-                        .map(|addr| match addr {
-                            IpAddr::V4(addr) => {
-                                IpNet::V4(Ipv4Net::new(addr, 32).expect("32 is OK"))
-                            }
-                            IpAddr::V6(addr) => {
-                                IpNet::V6(Ipv6Net::new(addr, 128).expect("128 is OK"))
-                            }
-                        })
-                }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        if include_own_ip_addr {
-            let own_ip_addr = find_own_ip_addr()?;
-            let own_ip_cidr = IpNet::V4(Ipv4Net::new(own_ip_addr, 32).expect("32 is OK"));
-            println!("Substituted: self -> {}", own_ip_cidr);
-            ip_cidrs.push(own_ip_cidr);
-        }
-
-        let names: Vec<String> = matches
-            .values_of("name")
-            .expect("required")
-            .map(str::to_owned)
-            .collect();
+        let ip_protocols = parse_ip_protocols(values_or(matches, "protocol", &[]), config)?;
+        let ip_cidrs = parse_ip_cidrs(values_or(matches, "source", &[]), matches, core)?;
+        let expires_after = parse_expires_after(matches)?;
+        let selector = parse_selector(matches)?;
 
         Command::Open {
             ip_protocols,
             ip_cidrs,
-            names,
+            expires_after,
+            additive: matches.is_present("add"),
+            egress: matches.is_present("egress"),
+            owner: parse_owner(matches)?,
+            any_owner: matches.is_present("any-owner"),
+            selector,
         }
     } else if let Some(matches) = matches.subcommand_matches("close") {
-        let names: Vec<String> = matches
-            .values_of("name")
-            .expect("required")
-            .map(str::to_owned)
-            .collect();
+        let only = if matches.is_present("only") {
+            let ip_protocols = parse_ip_protocols(values_or(matches, "protocol", &[]), config)?;
+            let ip_cidrs = parse_ip_cidrs(values_or(matches, "source", &[]), matches, core)?;
+            Some((ip_cidrs, ip_protocols))
+        } else {
+            None
+        };
+
+        let selector = parse_selector(matches)?;
 
-        Command::Close { names }
+        Command::Close {
+            only,
+            egress: matches.is_present("egress"),
+            owner: parse_owner(matches)?,
+            any_owner: matches.is_present("any-owner"),
+            selector,
+        }
     } else if let Some(matches) = matches.subcommand_matches("start") {
         let instance_type = matches.value_of("instance-type").map(InstanceType::new);
-        let names: Vec<String> = matches
-            .values_of("name")
-            .expect("required")
-            .map(str::to_owned)
-            .collect();
+        let (wait, dns_wait) = parse_wait(matches)?;
+        let selector = parse_selector(matches)?;
 
         Command::Start {
             instance_type,
-            names,
+            wait,
+            dns_wait,
+            selector,
         }
     } else if let Some(matches) = matches.subcommand_matches("stop") {
-        let names: Vec<String> = matches
-            .values_of("name")
-            .expect("required")
-            .map(str::to_owned)
-            .collect();
+        let (wait, dns_wait) = parse_wait(matches)?;
+        let selector = parse_selector(matches)?;
+
+        Command::Stop {
+            wait,
+            dns_wait,
+            selector,
+        }
+    } else if let Some(matches) = matches.subcommand_matches("reap") {
+        let selector = parse_selector(matches)?;
+
+        Command::Reap { selector }
+    } else if let Some(matches) = matches.subcommand_matches("status") {
+        let selector = parse_selector(matches)?;
+
+        Command::Status { selector }
+    } else if let Some(matches) = matches.subcommand_matches("up") {
+        let name = matches.value_of("target").expect("required");
+        let target = config.target(name)?;
+        let mut steps = Vec::new();
 
-        Command::Stop { names }
+        if !target.instances.is_empty() {
+            let instance_type = matches
+                .value_of("instance-type")
+                .or(target.instance_type.as_deref())
+                .map(InstanceType::new);
+            let (wait, dns_wait) = parse_wait(matches)?;
+
+            steps.push(Command::Start {
+                instance_type,
+                wait,
+                dns_wait,
+                selector: Selector::new(target.instances.clone(), vec![]),
+            });
+        }
+
+        if !target.firewalls.is_empty() {
+            let protocols = values_or(matches, "protocol", &target.protocols);
+            if protocols.is_empty() {
+                bail!("target has no protocols, so give --protocol: {}", name);
+            }
+            let sources = values_or(matches, "source", &target.sources);
+            if sources.is_empty() {
+                bail!("target has no sources, so give --source: {}", name);
+            }
+
+            steps.push(Command::Open {
+                ip_protocols: parse_ip_protocols(protocols, config)?,
+                ip_cidrs: parse_ip_cidrs(sources, matches, core)?,
+                expires_after: parse_expires_after(matches)?,
+                additive: false,
+                egress: false,
+                owner: parse_owner(matches)?,
+                any_owner: false,
+                selector: Selector::new(target.firewalls.clone(), vec![]),
+            });
+        }
+
+        if steps.is_empty() {
+            bail!("target has no instances or firewalls: {}", name);
+        }
+        Command::Up {
+            target: name.to_owned(),
+            steps,
+        }
+    } else if let Some(matches) = matches.subcommand_matches("down") {
+        let name = matches.value_of("target").expect("required");
+        let target = config.target(name)?;
+        let mut steps = Vec::new();
+
+        // Close before stopping, the reverse of up
+        if !target.firewalls.is_empty() {
+            steps.push(Command::Close {
+                only: None,
+                egress: false,
+                owner: parse_owner(matches)?,
+                any_owner: false,
+                selector: Selector::new(target.firewalls.clone(), vec![]),
+            });
+        }
+
+        if !target.instances.is_empty() {
+            let (wait, dns_wait) = parse_wait(matches)?;
+
+            steps.push(Command::Stop {
+                wait,
+                dns_wait,
+                selector: Selector::new(target.instances.clone(), vec![]),
+            });
+        }
+
+        if steps.is_empty() {
+            bail!("target has no instances or firewalls: {}", name);
+        }
+        Command::Down {
+            target: name.to_owned(),
+            steps,
+        }
     } else {
         unreachable!()
     };
 
-    Ok(cmd)
+    // The target's region and profile are used unless given on the command line
+    let target = match cmd {
+        Command::Up { ref target, .. } | Command::Down { ref target, .. } => {
+            Some(config.target(target)?)
+        }
+        _ => None,
+    };
+
+    let profile = value_of_anywhere(matches, "profile")
+        .or_else(|| target.and_then(|target| target.profile.as_deref()))
+        .or_else(|| config.profile());
+    let opts = Options {
+        dry_run: is_present_anywhere(matches, "dry-run"),
+        unique: is_present_anywhere(matches, "unique"),
+        output: match value_of_anywhere(matches, "output") {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        },
+        regions: if is_present_anywhere(matches, "all-regions") {
+            Regions::All
+        } else {
+            match values_of_anywhere(matches, "region") {
+                Some(names) => Regions::Named(names),
+                None => match target.and_then(|target| target.region.clone()) {
+                    Some(name) => Regions::Named(vec![name]),
+                    None => Regions::Default,
+                },
+            }
+        },
+        profile: profile.map(str::to_owned),
+        dns_profile: value_of_anywhere(matches, "dns-profile")
+            .or_else(|| config.dns_profile())
+            .or(profile)
+            .map(str::to_owned),
+    };
+
+    Ok((cmd, opts))
+}
+
+// Global args may be given either before or after the subcommand
+fn is_present_anywhere(matches: &ArgMatches<'_>, name: &str) -> bool {
+    matches.is_present(name)
+        || match matches.subcommand() {
+            (_, Some(matches)) => matches.is_present(name),
+            (_, None) => false,
+        }
+}
+
+fn value_of_anywhere<'a>(matches: &'a ArgMatches<'_>, name: &str) -> Option<&'a str> {
+    matches
+        .value_of(name)
+        .or_else(|| match matches.subcommand() {
+            (_, Some(matches)) => matches.value_of(name),
+            (_, None) => None,
+        })
 }
 
-fn find_own_ip_addr() -> Result<Ipv4Addr, Error> {
-    let mut core = Core::new().context("failed to create core reactor")?;
+fn values_of_anywhere(matches: &ArgMatches<'_>, name: &str) -> Option<Vec<String>> {
+    let values = matches
+        .values_of(name)
+        .or_else(|| match matches.subcommand() {
+            (_, Some(matches)) => matches.values_of(name),
+            (_, None) => None,
+        })?;
+    Some(values.map(str::to_owned).collect())
+}
+
+// Values given on the command line take the place of those in the config file
+fn values_or<'a>(matches: &'a ArgMatches<'_>, name: &str, default: &'a [String]) -> Vec<&'a str> {
+    match matches.values_of(name) {
+        Some(values) => values.collect(),
+        None => default.iter().map(String::as_str).collect(),
+    }
+}
+
+// The expiry is stored as an RFC 3339 timestamp, so cannot be after 9999
+fn parse_expires_after(matches: &ArgMatches<'_>) -> Result<Option<Duration>, Error> {
+    match matches.value_of("for") {
+        Some(x) => {
+            let duration =
+                humantime::parse_duration(x).with_context(|_e| format!("not a duration: {}", x))?;
+            if IpRuleMeta::expiry_after(SystemTime::now(), duration).is_none() {
+                bail!("duration would expire after 9999-12-31: {}", x);
+            }
+            Ok(Some(duration))
+        }
+        None => Ok(None),
+    }
+}
+
+// How long to wait for the instance, and for DNS, respectively
+fn parse_wait(matches: &ArgMatches<'_>) -> Result<(Option<Duration>, Option<Duration>), Error> {
+    if matches.is_present("no-wait") {
+        return Ok((None, None));
+    }
+    let timeout = match matches.value_of("timeout") {
+        Some(x) => {
+            humantime::parse_duration(x).with_context(|_e| format!("not a duration: {}", x))?
+        }
+        None => DEFAULT_WAIT_TIMEOUT,
+    };
+    if matches.is_present("no-dns-wait") {
+        Ok((Some(timeout), None))
+    } else {
+        Ok((Some(timeout), Some(timeout)))
+    }
+}
+
+fn parse_selector(matches: &ArgMatches<'_>) -> Result<Selector, Error> {
+    let terms = matches.values_of("name").into_iter().flatten();
+    let tags = matches
+        .values_of("tag")
+        .into_iter()
+        .flatten()
+        .map(|x| match x.find('=') {
+            Some(i) if i > 0 => Ok((x[..i].to_owned(), x[i + 1..].to_owned())),
+            _ => Err(format_err!("tag must be KEY=VALUE: {}", x)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Selector::new(terms, tags))
+}
+
+fn parse_owner(matches: &ArgMatches<'_>) -> Result<Option<String>, Error> {
+    match matches.value_of("owner") {
+        Some(x) if IpRuleMeta::is_valid_owner(x) => Ok(Some(x.to_owned())),
+        Some(x) => bail!(
+            "owner must be letters, digits and any of ._-:/()#,@[]+;{{}}!$*: {}",
+            x
+        ),
+        None => Ok(None),
+    }
+}
+
+fn parse_ip_protocols(xs: Vec<&str>, config: &Config) -> Result<Vec<IpProtocol>, Error> {
+    let mut ip_protocols = Vec::new();
+    for x in xs {
+        expand_ip_protocol(x, config, 0, &mut ip_protocols)?;
+    }
+    Ok(ip_protocols)
+}
+
+// Aliases may refer to other aliases, so bound the depth in case of a cycle
+fn expand_ip_protocol(
+    x: &str,
+    config: &Config,
+    depth: usize,
+    ip_protocols: &mut Vec<IpProtocol>,
+) -> Result<(), Error> {
+    match config.protocol_alias(x) {
+        Some(_) if depth >= 8 => bail!("protocol alias nested too deeply (cyclic?): {}", x),
+        Some(y) => {
+            eprintln!("Substituted: {} -> {}", x, y);
+            for y in y.split(',').map(str::trim) {
+                expand_ip_protocol(y, config, depth + 1, ip_protocols)?;
+            }
+        }
+        None => {
+            let ip_protocol =
+                IpProtocol::from_str(x).with_context(|_e| format!("not a protocol: {}", x))?;
+            if !ip_protocols.contains(&ip_protocol) {
+                ip_protocols.push(ip_protocol);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The matches give the IPv6 prefix length, for self
+fn parse_ip_cidrs(
+    xs: Vec<&str>,
+    matches: &ArgMatches<'_>,
+    core: &mut Core,
+) -> Result<Vec<IpNet>, Error> {
+    let include_own_ip_addr = xs.contains(&"self");
+
+    let mut ip_cidrs = xs
+        .into_iter()
+        .filter(|&x| x != "self")
+        .map(|x| {
+            if x.contains('/') {
+                IpNet::from_str(x).with_context(|_e| format!("not an IP network: {}", x))
+            } else {
+                IpAddr::from_str(x)
+                    .with_context(|_e| format!("not an IP address: {}", x))
+                    .map(|addr| match addr {
+                        IpAddr::V4(addr) => IpNet::V4(Ipv4Net::new(addr, 32).expect("32 is OK")),
+                        IpAddr::V6(addr) => IpNet::V6(Ipv6Net::new(addr, 128).expect("128 is OK")),
+                    })
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if include_own_ip_addr {
+        let ipv6_prefix_len = match matches.value_of("ipv6-prefix") {
+            Some(x) => u8::from_str(x)
+                .ok()
+                .filter(|&len| len <= 128)
+                .ok_or_else(|| format_err!("not an IPv6 prefix length: {}", x))?,
+            None => 128,
+        };
+        let own_ip_cidrs = find_own_ip_cidrs(core, ipv6_prefix_len)?;
+        let own_ip_cidr_strs: Vec<String> = own_ip_cidrs.iter().map(IpNet::to_string).collect();
+        eprintln!("Substituted: self -> {}", own_ip_cidr_strs.join(","));
+        ip_cidrs.extend(own_ip_cidrs);
+    }
+
+    Ok(ip_cidrs)
+}
+
+// Either address may be missing, e.g. on an IPv4-only or IPv6-only network,
+// so both are looked up together and either may fail
+fn find_own_ip_cidrs(core: &mut Core, ipv6_prefix_len: u8) -> Result<Vec<IpNet>, Error> {
     let client = Client::new(&core.handle());
-    let uri = "http://checkip.amazonaws.com/".parse().expect("valid URL");
-    let (status, body) = core
-        .run(
-            client
-                .get(uri)
-                .and_then(|res| (futures::finished(res.status()), res.body().concat2())),
-        )
-        .context("failed to contact checkip service")?;
-    let content = str::from_utf8(&*body).context("expected checkip to return UTF8")?;
-    if status != StatusCode::Ok {
-        bail!("checkip service returned {}: {}", status, content);
-    }
-    let ip_addr = Ipv4Addr::from_str(content.trim_right())
-        .with_context(|_e| format!("expected checkip to return IP address: {}", content))?;
-    Ok(ip_addr)
+    // This hostname only has an A record, so the request is made over IPv4
+    let ipv4_uri = "http://checkip.amazonaws.com/";
+    // This hostname only has an AAAA record, so the request is made over IPv6
+    let ipv6_uri = "http://api6.ipify.org/";
+    let (ipv4_result, ipv6_result) = core.run(
+        find_own_ip_addr(&client, ipv4_uri)
+            .then(Ok::<_, Error>)
+            .join(find_own_ip_addr(&client, ipv6_uri).then(Ok)),
+    )?;
+    let mut ip_cidrs = Vec::new();
+
+    match ipv4_result {
+        Ok(IpAddr::V4(addr)) => ip_cidrs.push(IpNet::V4(Ipv4Net::new(addr, 32).expect("32 is OK"))),
+        Ok(addr) => eprintln!(
+            "Could not find own IPv4 address: expected {} to return IPv4 address: {}",
+            ipv4_uri, addr
+        ),
+        Err(err) => eprintln!("Could not find own IPv4 address: {}", err),
+    }
+
+    match ipv6_result {
+        Ok(IpAddr::V6(addr)) => {
+            let ip_cidr = Ipv6Net::new(addr, ipv6_prefix_len)
+                .context("invalid IPv6 prefix length")?
+                .trunc();
+            ip_cidrs.push(IpNet::V6(ip_cidr))
+        }
+        Ok(addr) => eprintln!(
+            "Could not find own IPv6 address: expected {} to return IPv6 address: {}",
+            ipv6_uri, addr
+        ),
+        Err(err) => eprintln!("Could not find own IPv6 address: {}", err),
+    }
+
+    if ip_cidrs.is_empty() {
+        bail!("failed to find own IPv4 or IPv6 address");
+    }
+    Ok(ip_cidrs)
+}
+
+fn find_own_ip_addr(
+    client: &Client<HttpConnector>,
+    uri: &'static str,
+) -> impl Future<Item = IpAddr, Error = Error> {
+    client
+        .get(uri.parse().expect("valid URL"))
+        .and_then(|res| (futures::finished(res.status()), res.body().concat2()))
+        .then(move |result| -> Result<IpAddr, Error> {
+            let (status, body) = result.with_context(|_e| format!("failed to contact {}", uri))?;
+            let content = str::from_utf8(&*body)
+                .with_context(|_e| format!("expected {} to return UTF8", uri))?;
+            if status != StatusCode::Ok {
+                bail!("{} returned {}: {}", uri, status, content);
+            }
+            let ip_addr = IpAddr::from_str(content.trim_end())
+                .with_context(|_e| format!("expected {} to return IP address: {}", uri, content))?;
+            Ok(ip_addr)
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
 
     #[test]
     fn test_parse_open() {
@@ -281,23 +914,209 @@ mod tests {
                     "::ffff:1.1.1.1/128".parse().unwrap(),
                 ],
                 ip_protocols: vec!["22/tcp".parse().unwrap()],
-                names: vec!["x".to_owned(), "y".to_owned()],
+                expires_after: None,
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_open_for() {
+        test_parse(
+            &[
+                "drawbridge",
+                "open",
+                "--protocol",
+                "22/tcp",
+                "--source",
+                "1.1.1.1",
+                "--for",
+                "2h",
+                "x",
+            ],
+            Command::Open {
+                ip_cidrs: vec!["1.1.1.1/32".parse().unwrap()],
+                ip_protocols: vec!["22/tcp".parse().unwrap()],
+                expires_after: Some(Duration::from_secs(2 * 60 * 60)),
+                additive: false,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
             },
         )
         .unwrap();
     }
 
+    #[test]
+    fn test_parse_open_for_too_long() {
+        for duration in &["10000years", "584542046090years"] {
+            let err = parse(&[
+                "drawbridge",
+                "open",
+                "--protocol",
+                "22/tcp",
+                "--source",
+                "1.1.1.1",
+                "--for",
+                duration,
+                "x",
+            ])
+            .unwrap_err();
+            assert_eq!(
+                format!("duration would expire after 9999-12-31: {}", duration),
+                err.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_open_invalid_ipv6_prefix() {
+        let err = parse(&[
+            "drawbridge",
+            "open",
+            "--protocol",
+            "22/tcp",
+            "--source",
+            "self",
+            "--ipv6-prefix",
+            "129",
+            "x",
+        ])
+        .unwrap_err();
+        assert_eq!("not an IPv6 prefix length: 129", err.to_string());
+    }
+
     #[test]
     fn test_parse_close() {
         test_parse(
             &["drawbridge", "close", "x", "y"],
             Command::Close {
-                names: vec!["x".to_owned(), "y".to_owned()],
+                only: None,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x", "y"], vec![]),
             },
         )
         .unwrap();
     }
 
+    #[test]
+    fn test_parse_open_egress() {
+        test_parse(
+            &[
+                "drawbridge",
+                "open",
+                "--egress",
+                "--protocol",
+                "443/tcp",
+                "--source",
+                "192.0.2.0/24",
+                "x",
+            ],
+            Command::Open {
+                ip_cidrs: vec!["192.0.2.0/24".parse().unwrap()],
+                ip_protocols: vec!["443/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: false,
+                egress: true,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_open_add() {
+        test_parse(
+            &[
+                "drawbridge",
+                "open",
+                "--add",
+                "--protocol",
+                "22/tcp",
+                "--source",
+                "1.1.1.1",
+                "x",
+            ],
+            Command::Open {
+                ip_cidrs: vec!["1.1.1.1/32".parse().unwrap()],
+                ip_protocols: vec!["22/tcp".parse().unwrap()],
+                expires_after: None,
+                additive: true,
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_close_only() {
+        test_parse(
+            &[
+                "drawbridge",
+                "close",
+                "--only",
+                "--protocol",
+                "22/tcp",
+                "--source",
+                "1.1.1.1",
+                "x",
+            ],
+            Command::Close {
+                only: Some((
+                    vec!["1.1.1.1/32".parse().unwrap()],
+                    vec!["22/tcp".parse().unwrap()],
+                )),
+                egress: false,
+                owner: None,
+                any_owner: false,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_close_owner() {
+        test_parse(
+            &[
+                "drawbridge",
+                "close",
+                "--owner",
+                "alice",
+                "--any-owner",
+                "x",
+            ],
+            Command::Close {
+                only: None,
+                egress: false,
+                owner: Some("alice".to_owned()),
+                any_owner: true,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+        assert!(parse(&["drawbridge", "close", "--owner", "a b", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_close_only_requires_rules() {
+        assert!(parse(&["drawbridge", "close", "--only", "x"]).is_err());
+        assert!(parse(&["drawbridge", "close", "--protocol", "ssh", "x"]).is_err());
+    }
+
     #[test]
     fn test_parse_start() {
         test_parse(
@@ -311,7 +1130,9 @@ mod tests {
             ],
             Command::Start {
                 instance_type: Some(InstanceType::new("m3.medium")),
-                names: vec!["x".to_owned(), "y".to_owned()],
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                selector: Selector::new(vec!["x", "y"], vec![]),
             },
         )
         .unwrap();
@@ -322,15 +1143,453 @@ mod tests {
         test_parse(
             &["drawbridge", "stop", "x", "y"],
             Command::Stop {
-                names: vec!["x".to_owned(), "y".to_owned()],
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_reap() {
+        test_parse(
+            &["drawbridge", "reap", "x", "y"],
+            Command::Reap {
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_status() {
+        test_parse(
+            &["drawbridge", "status", "x", "y"],
+            Command::Status {
+                selector: Selector::new(vec!["x", "y"], vec![]),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        test_parse(
+            &[
+                "drawbridge",
+                "stop",
+                "--tag",
+                "env=staging",
+                "--tag",
+                "team=web",
+                "web-*",
+                "i-0123abcd",
+            ],
+            Command::Stop {
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                selector: Selector::new(
+                    vec!["web-*", "i-0123abcd"],
+                    vec![
+                        ("env".to_owned(), "staging".to_owned()),
+                        ("team".to_owned(), "web".to_owned()),
+                    ],
+                ),
+            },
+        )
+        .unwrap();
+        test_parse(
+            &["drawbridge", "stop", "--tag", "env=staging"],
+            Command::Stop {
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                selector: Selector::new(
+                    Vec::<String>::new(),
+                    vec![("env".to_owned(), "staging".to_owned())],
+                ),
             },
         )
         .unwrap();
+        assert!(parse(&["drawbridge", "stop"]).is_err());
+        assert!(parse(&["drawbridge", "stop", "--tag", "env", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_wait() {
+        test_parse(
+            &["drawbridge", "start", "x", "--timeout", "90s"],
+            Command::Start {
+                instance_type: None,
+                wait: Some(Duration::from_secs(90)),
+                dns_wait: Some(Duration::from_secs(90)),
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+        test_parse(
+            &["drawbridge", "stop", "x", "--no-wait"],
+            Command::Stop {
+                wait: None,
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+        test_parse(
+            &["drawbridge", "stop", "x", "--no-dns-wait"],
+            Command::Stop {
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: None,
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+        )
+        .unwrap();
+        assert!(parse(&["drawbridge", "stop", "x", "--no-wait", "--timeout", "1m"]).is_err());
+        assert!(parse(&["drawbridge", "stop", "x", "--timeout", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_unique() {
+        test_parse_with_options(
+            &["drawbridge", "--unique", "stop", "x"],
+            Command::Stop {
+                wait: Some(DEFAULT_WAIT_TIMEOUT),
+                dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Options {
+                unique: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_parse_output() {
+        test_parse_with_options(
+            &["drawbridge", "status", "x", "--output", "json"],
+            Command::Status {
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Options {
+                output: OutputFormat::Json,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert!(parse(&["drawbridge", "--output", "yaml", "status", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        test_parse_with_options(
+            &[
+                "drawbridge",
+                "status",
+                "x",
+                "--region",
+                "eu-west-1",
+                "--region",
+                "us-east-1",
+            ],
+            Command::Status {
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Options {
+                regions: Regions::Named(vec!["eu-west-1".to_owned(), "us-east-1".to_owned()]),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        test_parse_with_options(
+            &["drawbridge", "status", "x", "--all-regions"],
+            Command::Status {
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Options {
+                regions: Regions::All,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert!(parse(&[
+            "drawbridge",
+            "--region",
+            "eu-west-1",
+            "--all-regions",
+            "status",
+            "x"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        test_parse_with_options(
+            &["drawbridge", "--profile", "dev", "status", "x"],
+            Command::Status {
+                selector: Selector::new(vec!["x"], vec![]),
+            },
+            Options {
+                profile: Some("dev".to_owned()),
+                dns_profile: Some("dev".to_owned()),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        let config: Config = toml::from_str("dns_profile = \"dns\"").unwrap();
+        let args = ["drawbridge", "status", "x", "--profile", "dev"];
+        let (_, opts) = parse_with_config(&args, &config).unwrap();
+        assert_eq!(Some("dev"), opts.profile.as_deref());
+        assert_eq!(Some("dns"), opts.dns_profile.as_deref());
+    }
+
+    #[test]
+    fn test_parse_dry_run() {
+        for args in &[
+            &["drawbridge", "--dry-run", "stop", "x"],
+            &["drawbridge", "stop", "--dry-run", "x"],
+        ] {
+            test_parse_with_options(
+                *args,
+                Command::Stop {
+                    wait: Some(DEFAULT_WAIT_TIMEOUT),
+                    dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                    selector: Selector::new(vec!["x"], vec![]),
+                },
+                Options {
+                    dry_run: true,
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_protocol_aliases() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            dev = "ssh, 8080-8090/tcp, mosh"
+            loop = "loop"
+            "#,
+        )
+        .unwrap();
+        let args = [
+            "drawbridge",
+            "open",
+            "--protocol",
+            "dev,22/tcp,rdp",
+            "--source",
+            "1.1.1.1",
+            "x",
+        ];
+        let (cmd, _) = parse_with_config(&args, &config).unwrap();
+        match cmd {
+            Command::Open { ip_protocols, .. } => assert_eq!(
+                vec![
+                    "22/tcp".parse::<IpProtocol>().unwrap(),
+                    "8080-8090/tcp".parse().unwrap(),
+                    "60000-61000/udp".parse().unwrap(),
+                    "3389/tcp".parse().unwrap(),
+                ],
+                ip_protocols
+            ),
+            cmd => panic!("unexpected command: {:?}", cmd),
+        }
+
+        let args = [
+            "drawbridge",
+            "open",
+            "--protocol",
+            "loop",
+            "--source",
+            "1.1.1.1",
+            "x",
+        ];
+        let err = parse_with_config(&args, &config).unwrap_err();
+        assert_eq!(
+            "protocol alias nested too deeply (cyclic?): loop",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_up_and_down() {
+        let config: Config = toml::from_str(
+            r#"
+            [targets.devbox]
+            instances = ["devbox"]
+            firewalls = ["devbox-fw"]
+            protocols = ["ssh", "mosh"]
+            sources = ["1.1.1.1"]
+            instance_type = "t3.large"
+            region = "eu-west-1"
+            profile = "dev"
+            "#,
+        )
+        .unwrap();
+
+        let args = ["drawbridge", "up", "devbox"];
+        let (cmd, opts) = parse_with_config(&args, &config).unwrap();
+        assert_eq!(
+            Command::Up {
+                target: "devbox".to_owned(),
+                steps: vec![
+                    Command::Start {
+                        instance_type: Some(InstanceType::new("t3.large")),
+                        wait: Some(DEFAULT_WAIT_TIMEOUT),
+                        dns_wait: Some(DEFAULT_WAIT_TIMEOUT),
+                        selector: Selector::new(vec!["devbox"], vec![]),
+                    },
+                    Command::Open {
+                        ip_protocols: vec![
+                            IpProtocol::from_str("22/tcp").unwrap(),
+                            IpProtocol::from_str("60000-61000/udp").unwrap(),
+                        ],
+                        ip_cidrs: vec![IpNet::from_str("1.1.1.1/32").unwrap()],
+                        expires_after: None,
+                        additive: false,
+                        egress: false,
+                        owner: None,
+                        any_owner: false,
+                        selector: Selector::new(vec!["devbox-fw"], vec![]),
+                    },
+                ],
+            },
+            cmd
+        );
+        assert_eq!(
+            Options {
+                regions: Regions::Named(vec!["eu-west-1".to_owned()]),
+                profile: Some("dev".to_owned()),
+                dns_profile: Some("dev".to_owned()),
+                ..Options::default()
+            },
+            opts
+        );
+
+        let args = ["drawbridge", "down", "devbox", "--no-wait"];
+        let (cmd, _) = parse_with_config(&args, &config).unwrap();
+        assert_eq!(
+            Command::Down {
+                target: "devbox".to_owned(),
+                steps: vec![
+                    Command::Close {
+                        only: None,
+                        egress: false,
+                        owner: None,
+                        any_owner: false,
+                        selector: Selector::new(vec!["devbox-fw"], vec![]),
+                    },
+                    Command::Stop {
+                        wait: None,
+                        dns_wait: None,
+                        selector: Selector::new(vec!["devbox"], vec![]),
+                    },
+                ],
+            },
+            cmd
+        );
+
+        let err = parse_with_config(&["drawbridge", "up", "other"], &config).unwrap_err();
+        assert_eq!("no such target in the config file: other", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_up_overrides_target() {
+        let config: Config = toml::from_str(
+            r#"
+            [targets.devbox]
+            instances = ["devbox"]
+            firewalls = ["devbox-fw"]
+            protocols = ["ssh"]
+            sources = ["1.1.1.1"]
+            instance_type = "t3.large"
+            region = "eu-west-1"
+            profile = "dev"
+
+            [targets.fw]
+            firewalls = ["fw"]
+            "#,
+        )
+        .unwrap();
+
+        let args = [
+            "drawbridge",
+            "--region",
+            "us-east-1",
+            "--profile",
+            "prod",
+            "up",
+            "devbox",
+            "-p",
+            "https",
+            "-s",
+            "2.2.2.2",
+            "-t",
+            "m5.large",
+        ];
+        let (cmd, opts) = parse_with_config(&args, &config).unwrap();
+        let steps = match cmd {
+            Command::Up { steps, .. } => steps,
+            _ => panic!("expected up: {:?}", cmd),
+        };
+        match steps[0] {
+            Command::Start {
+                ref instance_type, ..
+            } => assert_eq!(&Some(InstanceType::new("m5.large")), instance_type),
+            _ => panic!("expected start: {:?}", steps[0]),
+        }
+        match steps[1] {
+            Command::Open {
+                ref ip_protocols,
+                ref ip_cidrs,
+                ..
+            } => {
+                assert_eq!(
+                    &vec![IpProtocol::from_str("443/tcp").unwrap()],
+                    ip_protocols
+                );
+                assert_eq!(&vec![IpNet::from_str("2.2.2.2/32").unwrap()], ip_cidrs);
+            }
+            _ => panic!("expected open: {:?}", steps[1]),
+        }
+        assert_eq!(Regions::Named(vec!["us-east-1".to_owned()]), opts.regions);
+        assert_eq!(Some("prod"), opts.profile.as_deref());
+
+        // A target with firewalls but no protocols needs them on the command line
+        let err = parse_with_config(&["drawbridge", "up", "fw"], &config).unwrap_err();
+        assert_eq!(
+            "target has no protocols, so give --protocol: fw",
+            err.to_string()
+        );
+        let args = ["drawbridge", "up", "fw", "-p", "ssh", "-s", "1.1.1.1"];
+        assert!(parse_with_config(&args, &config).is_ok());
     }
 
     fn test_parse(args: &[&str], cmd: Command) -> Result<(), Error> {
-        let actual_cmd = parse_from_safe(args)?;
+        test_parse_with_options(args, cmd, Options::default())
+    }
+
+    fn test_parse_with_options(args: &[&str], cmd: Command, opts: Options) -> Result<(), Error> {
+        let (actual_cmd, actual_opts) = parse(args)?;
         assert_eq!(cmd, actual_cmd);
+        assert_eq!(opts, actual_opts);
         Ok(())
     }
+
+    // Ignore any config file in the environment
+    fn parse(args: &[&str]) -> Result<(Command, Options), Error> {
+        parse_with_config(args, &Config::default())
+    }
+
+    fn parse_with_config(args: &[&str], config: &Config) -> Result<(Command, Options), Error> {
+        let matches = define_app().get_matches_from_safe(args)?;
+        parse_matches(&matches, config, &mut Core::new()?)
+    }
 }
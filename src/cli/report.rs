@@ -0,0 +1,272 @@
+use crate::cloud::Firewall;
+use crate::cloud::Instance;
+use crate::cloud::InstanceStatus;
+use crate::dns::DnsTarget;
+use crate::dns::DnsZone;
+use crate::iprules::IpProtocol;
+use crate::iprules::IpRuleMeta;
+use crate::iprules::IpSource;
+use crate::wait;
+use failure::Error;
+use serde::Serialize;
+use std::fmt;
+
+// The result of a command, printed as JSON with --output json
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub command: String,
+    // if set, the changes were not made
+    pub dry_run: bool,
+    pub firewalls: Vec<FirewallReport>,
+    pub instances: Vec<InstanceReport>,
+    pub dns_changes: Vec<DnsChangeReport>,
+    // the firewalls and instances for which the command failed,
+    // which do not stop it being applied to the others
+    pub failures: Vec<FailureReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FirewallReport {
+    pub id: String,
+    pub name: String,
+    pub rules: Vec<RuleReport>,
+}
+
+impl FirewallReport {
+    pub fn new<F: Firewall>(fw: &F, rules: Vec<RuleReport>) -> FirewallReport {
+        FirewallReport {
+            id: fw.id().to_owned(),
+            name: fw.name().to_owned(),
+            rules,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleReport {
+    // one of "added", "updated", "removed" or, for status, "existing"
+    pub change: &'static str,
+    // "ingress" or "egress"
+    pub direction: &'static str,
+    // the source of an ingress rule, or the destination of an egress rule
+    pub peer: String,
+    pub protocol: String,
+    pub owner: Option<String>,
+    pub expires: Option<String>,
+    // whether drawbridge created the rule
+    pub managed: bool,
+}
+
+impl RuleReport {
+    pub fn new(
+        change: &'static str,
+        direction: &'static str,
+        peer: &IpSource,
+        protocol: &IpProtocol,
+        meta: Option<&IpRuleMeta>,
+    ) -> RuleReport {
+        RuleReport {
+            change,
+            direction,
+            peer: peer.to_string(),
+            protocol: protocol.to_string(),
+            owner: meta.and_then(|meta| meta.owner.clone()),
+            expires: meta
+                .and_then(|meta| meta.expiry)
+                .map(|expiry| humantime::format_rfc3339_seconds(expiry).to_string()),
+            managed: meta.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstanceReport {
+    pub id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub status: InstanceStatus,
+    // for status, what the instance's hostnames are bound to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hostnames: Vec<HostnameReport>,
+}
+
+impl InstanceReport {
+    pub fn new<I: Instance>(instance: &I, status: InstanceStatus) -> InstanceReport {
+        InstanceReport {
+            id: instance.id().to_owned(),
+            name: instance.name().to_owned(),
+            status,
+            hostnames: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostnameReport {
+    pub fqdn: String,
+    pub zone: String,
+    // empty if the hostname is not bound
+    pub targets: Vec<DnsTarget>,
+}
+
+impl HostnameReport {
+    pub fn new<Z: DnsZone>(fqdn: &str, dns_zone: &Z, targets: Vec<DnsTarget>) -> HostnameReport {
+        HostnameReport {
+            fqdn: fqdn.to_owned(),
+            zone: dns_zone.name().to_owned(),
+            targets,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DnsChangeReport {
+    pub fqdn: String,
+    pub zone: String,
+    // "bind" or "unbind"
+    pub change: &'static str,
+    pub targets: Vec<DnsTarget>,
+}
+
+impl DnsChangeReport {
+    pub fn new<Z: DnsZone>(
+        fqdn: &str,
+        dns_zone: &Z,
+        change: &'static str,
+        targets: Vec<DnsTarget>,
+    ) -> DnsChangeReport {
+        DnsChangeReport {
+            fqdn: fqdn.to_owned(),
+            zone: dns_zone.name().to_owned(),
+            change,
+            targets,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailureReport {
+    pub id: String,
+    pub name: String,
+    pub error: String,
+    pub timed_out: bool,
+}
+
+impl FailureReport {
+    pub fn new(id: &str, name: &str, error: &Error) -> FailureReport {
+        FailureReport {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            error: describe_error(error),
+            timed_out: wait::is_timeout(error),
+        }
+    }
+}
+
+// Printed with --output json in place of the report, if the command fails as a whole
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub command: String,
+    pub error: String,
+    pub timed_out: bool,
+}
+
+impl ErrorReport {
+    pub fn new(command: &str, error: &Error) -> ErrorReport {
+        ErrorReport {
+            command: command.to_owned(),
+            error: describe_error(error),
+            timed_out: wait::is_timeout(error),
+        }
+    }
+}
+
+// e.g. failed to start instance: i-123: RequestLimitExceeded (after 5 attempts)
+pub fn describe_error(error: &Error) -> String {
+    let messages: Vec<String> = error.causes().map(|x| x.to_string()).collect();
+    messages.join(": ")
+}
+
+// The summary printed with --output text, one line per change or resource
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dry_run {
+            writeln!(f, "Dry run: these changes were not made")?;
+        }
+        for fw in &self.firewalls {
+            writeln!(f, "Firewall {} ({}):", fw.name, fw.id)?;
+            for rule in &fw.rules {
+                write!(
+                    f,
+                    "  {} {} rule: {} {}",
+                    rule.change, rule.direction, rule.peer, rule.protocol
+                )?;
+                if let Some(ref owner) = rule.owner {
+                    write!(f, " owned by {}", owner)?;
+                }
+                if let Some(ref expires) = rule.expires {
+                    write!(f, " expiring {}", expires)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        for instance in &self.instances {
+            writeln!(
+                f,
+                "Instance {} ({}): {} {} {}",
+                instance.name,
+                instance.id,
+                instance.status.state,
+                instance.status.instance_type,
+                format_targets(&instance.status.addrs)
+            )?;
+            for hostname in &instance.hostnames {
+                writeln!(
+                    f,
+                    "  {} in {}: {}",
+                    hostname.fqdn,
+                    hostname.zone,
+                    format_targets(&hostname.targets)
+                )?;
+            }
+        }
+        for dns_change in &self.dns_changes {
+            let change = if dns_change.change == "bind" {
+                "Bound"
+            } else {
+                "Unbound"
+            };
+            writeln!(
+                f,
+                "{} hostname {} in {}: {}",
+                change,
+                dns_change.fqdn,
+                dns_change.zone,
+                format_targets(&dns_change.targets)
+            )?;
+        }
+        for failure in &self.failures {
+            writeln!(
+                f,
+                "Failed for {} ({}): {}",
+                failure.name, failure.id, failure.error
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_targets(targets: &[DnsTarget]) -> String {
+    if targets.is_empty() {
+        return "-".to_owned();
+    }
+    let targets: Vec<String> = targets
+        .iter()
+        .map(|target| match *target {
+            DnsTarget::A(ref addr) => addr.to_string(),
+            DnsTarget::Aaaa(ref addr) => addr.to_string(),
+            DnsTarget::Cname(ref name) => name.clone(),
+        })
+        .collect();
+    targets.join(", ")
+}
@@ -1,12 +1,21 @@
 use crate::cloud::Firewall;
+use crate::future::BoxFuture;
+use crate::iprules::IpEgressRule;
 use crate::iprules::IpIngressRule;
 use crate::iprules::IpPortRange;
 use crate::iprules::IpProtocol;
+use crate::iprules::IpRuleMeta;
+use crate::iprules::IpSource;
+use crate::retry::retry;
 use failure::Error;
 use failure::ResultExt;
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
 use ipnet::IpNet;
 use ipnet::Ipv4Net;
 use ipnet::Ipv6Net;
+use rusoto_ec2::AuthorizeSecurityGroupEgressRequest;
 use rusoto_ec2::AuthorizeSecurityGroupIngressRequest;
 use rusoto_ec2::DescribeSecurityGroupsRequest;
 use rusoto_ec2::Ec2;
@@ -14,62 +23,105 @@ use rusoto_ec2::Filter;
 use rusoto_ec2::IpPermission;
 use rusoto_ec2::IpRange;
 use rusoto_ec2::Ipv6Range;
+use rusoto_ec2::PrefixListId;
+use rusoto_ec2::RevokeSecurityGroupEgressRequest;
 use rusoto_ec2::RevokeSecurityGroupIngressRequest;
 use rusoto_ec2::SecurityGroup;
-use std::collections::HashSet;
+use rusoto_ec2::UpdateSecurityGroupRuleDescriptionsEgressRequest;
+use rusoto_ec2::UpdateSecurityGroupRuleDescriptionsIngressRequest;
+use rusoto_ec2::UserIdGroupPair;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::hash::Hash;
 use std::rc::Rc;
 use std::str::FromStr;
+use tokio_core::reactor::Handle;
 
+#[derive(Clone)]
 pub struct AwsFirewall {
     id: String,
     name: String,
+    region: String,
     client: Rc<dyn Ec2>,
+    handle: Handle,
 }
 
 impl AwsFirewall {
-    pub(super) fn list(client: &Rc<dyn Ec2>, filter: Filter) -> Result<Vec<AwsFirewall>, Error> {
+    pub(super) fn list(
+        client: &Rc<dyn Ec2>,
+        handle: &Handle,
+        region: &str,
+        filters: Vec<Filter>,
+    ) -> BoxFuture<Vec<AwsFirewall>> {
         let req = DescribeSecurityGroupsRequest {
-            filters: Some(vec![filter]),
+            filters: Some(filters),
             ..Default::default()
         };
-        let resp = client
-            .describe_security_groups(&req)
-            .sync()
-            .with_context(|_e| format!("failed to describe security groups: {:?}", req))?;
-        let mut values: Vec<AwsFirewall> = Vec::new();
-        for sg in resp.security_groups.unwrap() {
-            let value = AwsFirewall {
-                id: sg.group_id.unwrap(),
-                name: sg.group_name.unwrap(),
-                client: Rc::clone(client),
-            };
-            values.push(value);
-        }
-        Ok(values)
+        let client = Rc::clone(client);
+        let handle = handle.clone();
+        let region = region.to_owned();
+        Box::new(future::loop_fn(
+            (req, Vec::new()),
+            move |(mut req, mut values): (_, Vec<AwsFirewall>)| {
+                let (ec2, attempt) = (Rc::clone(&client), req.clone());
+                let (client, handle, region) = (Rc::clone(&client), handle.clone(), region.clone());
+                retry(&handle, move || ec2.describe_security_groups(&attempt)).then(
+                    move |result| -> Result<_, Error> {
+                        let resp = result.with_context(|_e| {
+                            format!("failed to describe security groups: {:?}", req)
+                        })?;
+                        for sg in resp.security_groups.unwrap_or_default() {
+                            let value = AwsFirewall {
+                                id: sg.group_id.unwrap(),
+                                name: sg.group_name.unwrap(),
+                                region: region.clone(),
+                                client: Rc::clone(&client),
+                                handle: handle.clone(),
+                            };
+                            values.push(value);
+                        }
+                        // The results are paginated if there are many
+                        match resp.next_token {
+                            Some(next_token) => {
+                                req.next_token = Some(next_token);
+                                Ok(Loop::Continue((req, values)))
+                            }
+                            None => Ok(Loop::Break(values)),
+                        }
+                    },
+                )
+            },
+        ))
     }
 
-    fn get_state(&self) -> Result<SecurityGroup, Error> {
+    fn get_state(&self) -> BoxFuture<SecurityGroup> {
         let req = DescribeSecurityGroupsRequest {
             group_ids: Some(vec![self.id.clone()]),
             ..Default::default()
         };
-        let resp = self
-            .client
-            .describe_security_groups(&req)
-            .sync()
-            .with_context(|_e| format!("failed to describe security group: {:?}", self))?;
-        resp.security_groups
-            .unwrap()
-            .into_iter()
-            .next()
-            .ok_or_else(|| format_err!("failed to find security group: {:?}", self))
+        let client = Rc::clone(&self.client);
+        let fw = self.clone();
+        Box::new(
+            retry(&self.handle, move || client.describe_security_groups(&req)).then(
+                move |result| -> Result<_, Error> {
+                    let resp = result.with_context(|_e| {
+                        format!("failed to describe security group: {:?}", fw)
+                    })?;
+                    resp.security_groups
+                        .unwrap()
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| format_err!("failed to find security group: {:?}", fw))
+                },
+            ),
+        )
     }
 }
 
 impl fmt::Debug for AwsFirewall {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", self.name, self.id)
+        write!(f, "{} ({} in {})", self.name, self.id, self.region)
     }
 }
 
@@ -82,114 +134,496 @@ impl Firewall for AwsFirewall {
         &self.name
     }
 
-    fn list_ingress_rules(&self) -> Result<HashSet<IpIngressRule>, Error> {
-        let mut rules = HashSet::new();
-
-        let sg = self.get_state()?;
-        for ip_permission in sg.ip_permissions.unwrap() {
-            let ip_port_range = IpPortRange(
-                ip_permission.from_port.unwrap() as u16,
-                ip_permission.to_port.unwrap() as u16,
-            );
-            let ip_protocol = match ip_permission.ip_protocol.unwrap().as_ref() {
-                "tcp" => IpProtocol::Tcp(ip_port_range),
-                "udp" => IpProtocol::Udp(ip_port_range),
-                x => bail!("unknown protocol: {}", x),
-            };
-            for ip_range in ip_permission.ip_ranges.unwrap() {
-                let ip_cidr_str = &ip_range.cidr_ip.unwrap();
-                let ip_cidr = Ipv4Net::from_str(ip_cidr_str)
-                    .with_context(|_e| format!("not an IPv4 network: {}", ip_cidr_str))?;
-                rules.insert(IpIngressRule(IpNet::V4(ip_cidr), ip_protocol));
-            }
-            for ip_range in ip_permission.ipv_6_ranges.unwrap() {
-                let ip_cidr_str = &ip_range.cidr_ipv_6.unwrap();
-                let ip_cidr = Ipv6Net::from_str(ip_cidr_str)
-                    .with_context(|_e| format!("not an IPv6 network: {}", ip_cidr_str))?;
-                rules.insert(IpIngressRule(IpNet::V6(ip_cidr), ip_protocol));
-            }
-        }
-
-        Ok(rules)
+    fn describe_ingress_rules(&self) -> BoxFuture<HashMap<IpIngressRule, Option<IpRuleMeta>>> {
+        Box::new(
+            self.get_state().map(|sg| {
+                from_ip_permissions(sg.ip_permissions.unwrap_or_default(), IpIngressRule)
+            }),
+        )
     }
 
-    fn add_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    fn add_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>,
     {
-        let ip_permissions: Vec<IpPermission> = rules.into_iter().map(to_ip_permission).collect();
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, Some(meta)))
+            .collect();
         if ip_permissions.is_empty() {
-            return Ok(());
+            return Box::new(future::ok(()));
         }
         let req = AuthorizeSecurityGroupIngressRequest {
             group_id: Some(self.id.clone()),
             ip_permissions: Some(ip_permissions),
             ..Default::default()
         };
-        self.client
-            .authorize_security_group_ingress(&req)
-            .sync()
-            .with_context(|_e| {
-                format!(
-                    "failed to authorize ingress for security group: {}",
-                    self.name
-                )
-            })?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.authorize_security_group_ingress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!("failed to authorize ingress for security group: {}", name)
+                })?;
+                Ok(())
+            }),
+        )
     }
 
-    fn remove_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    fn update_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>,
     {
-        let ip_permissions: Vec<IpPermission> = rules.into_iter().map(to_ip_permission).collect();
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, Some(meta)))
+            .collect();
         if ip_permissions.is_empty() {
-            return Ok(());
+            return Box::new(future::ok(()));
+        }
+        let req = UpdateSecurityGroupRuleDescriptionsIngressRequest {
+            group_id: Some(self.id.clone()),
+            ip_permissions,
+            ..Default::default()
+        };
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.update_security_group_rule_descriptions_ingress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!(
+                        "failed to update ingress descriptions for security group: {}",
+                        name
+                    )
+                })?;
+                Ok(())
+            }),
+        )
+    }
+
+    fn remove_ingress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpIngressRule>,
+    {
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, None))
+            .collect();
+        if ip_permissions.is_empty() {
+            return Box::new(future::ok(()));
         }
         let req = RevokeSecurityGroupIngressRequest {
             group_id: Some(self.id.clone()),
             ip_permissions: Some(ip_permissions),
             ..Default::default()
         };
-        self.client
-            .revoke_security_group_ingress(&req)
-            .sync()
-            .with_context(|_e| {
-                format!("failed to revoke ingress for security group: {}", self.name)
-            })?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.revoke_security_group_ingress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!("failed to revoke ingress for security group: {}", name)
+                })?;
+                Ok(())
+            }),
+        )
+    }
+
+    fn describe_egress_rules(&self) -> BoxFuture<HashMap<IpEgressRule, Option<IpRuleMeta>>> {
+        Box::new(self.get_state().map(|sg| {
+            from_ip_permissions(sg.ip_permissions_egress.unwrap_or_default(), IpEgressRule)
+        }))
+    }
+
+    fn add_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, Some(meta)))
+            .collect();
+        if ip_permissions.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let req = AuthorizeSecurityGroupEgressRequest {
+            group_id: self.id.clone(),
+            ip_permissions: Some(ip_permissions),
+            ..Default::default()
+        };
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.authorize_security_group_egress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!("failed to authorize egress for security group: {}", name)
+                })?;
+                Ok(())
+            }),
+        )
+    }
+
+    fn update_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, Some(meta)))
+            .collect();
+        if ip_permissions.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let req = UpdateSecurityGroupRuleDescriptionsEgressRequest {
+            group_id: Some(self.id.clone()),
+            ip_permissions,
+            ..Default::default()
+        };
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.update_security_group_rule_descriptions_egress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!(
+                        "failed to update egress descriptions for security group: {}",
+                        name
+                    )
+                })?;
+                Ok(())
+            }),
+        )
+    }
+
+    fn remove_egress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let ip_permissions: Vec<IpPermission> = rules
+            .into_iter()
+            .map(|rule| to_ip_permission(&rule.0, &rule.1, None))
+            .collect();
+        if ip_permissions.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        let req = RevokeSecurityGroupEgressRequest {
+            group_id: self.id.clone(),
+            ip_permissions: Some(ip_permissions),
+            ..Default::default()
+        };
+        let client = Rc::clone(&self.client);
+        let name = self.name.clone();
+        Box::new(
+            retry(&self.handle, move || {
+                client.revoke_security_group_egress(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                result.with_context(|_e| {
+                    format!("failed to revoke egress for security group: {}", name)
+                })?;
+                Ok(())
+            }),
+        )
     }
 }
 
-fn to_ip_permission(rule: &IpIngressRule) -> IpPermission {
-    let &IpIngressRule(ref ip_cidr, ref ip_protocol) = rule;
-    let (ip_protocol, from_port, to_port) = match ip_protocol {
-        &IpProtocol::Tcp(IpPortRange(from, to)) => ("tcp", from, to),
-        &IpProtocol::Udp(IpPortRange(from, to)) => ("udp", from, to),
+// Ingress and egress permissions have the same structure, with the
+// source of an ingress rule being the destination of an egress rule.
+// Entries that cannot be modelled are kept as unknown, so that they are left alone.
+fn from_ip_permissions<R, F>(
+    ip_permissions: Vec<IpPermission>,
+    rule: F,
+) -> HashMap<R, Option<IpRuleMeta>>
+where
+    R: Hash + Eq,
+    F: Fn(IpSource, IpProtocol) -> R,
+{
+    let mut rules = HashMap::new();
+    for ip_permission in ip_permissions {
+        let ip_protocol = from_ip_permission(&ip_permission);
+        for ip_range in ip_permission.ip_ranges.unwrap_or_default() {
+            let source = match ip_range.cidr_ip {
+                Some(ref x) => match Ipv4Net::from_str(x) {
+                    Ok(ip_cidr) => IpSource::Cidr(IpNet::V4(ip_cidr)),
+                    Err(_) => IpSource::Unknown(x.clone()),
+                },
+                None => IpSource::Unknown("missing IPv4 range".to_owned()),
+            };
+            let meta = parse_meta(ip_range.description);
+            rules.insert(rule(source, ip_protocol.clone()), meta);
+        }
+        for ip_range in ip_permission.ipv_6_ranges.unwrap_or_default() {
+            let source = match ip_range.cidr_ipv_6 {
+                Some(ref x) => match Ipv6Net::from_str(x) {
+                    Ok(ip_cidr) => IpSource::Cidr(IpNet::V6(ip_cidr)),
+                    Err(_) => IpSource::Unknown(x.clone()),
+                },
+                None => IpSource::Unknown("missing IPv6 range".to_owned()),
+            };
+            let meta = parse_meta(ip_range.description);
+            rules.insert(rule(source, ip_protocol.clone()), meta);
+        }
+        for group_pair in ip_permission.user_id_group_pairs.unwrap_or_default() {
+            let meta = parse_meta(group_pair.description);
+            let source = match group_pair.group_id {
+                Some(group_id) => IpSource::Group {
+                    group_id,
+                    user_id: group_pair.user_id,
+                },
+                None => IpSource::Unknown("missing security group".to_owned()),
+            };
+            rules.insert(rule(source, ip_protocol.clone()), meta);
+        }
+        for prefix_list in ip_permission.prefix_list_ids.unwrap_or_default() {
+            let meta = parse_meta(prefix_list.description);
+            let source = match prefix_list.prefix_list_id {
+                Some(prefix_list_id) => IpSource::PrefixList(prefix_list_id),
+                None => IpSource::Unknown("missing prefix list".to_owned()),
+            };
+            rules.insert(rule(source, ip_protocol.clone()), meta);
+        }
+    }
+    rules
+}
+
+fn parse_meta(description: Option<String>) -> Option<IpRuleMeta> {
+    description.and_then(|description| IpRuleMeta::from_str(&description).ok())
+}
+
+// For ICMP, the ports are the type and code, where -1 means any
+fn from_ip_permission(ip_permission: &IpPermission) -> IpProtocol {
+    let ports = (ip_permission.from_port, ip_permission.to_port);
+    let name = ip_permission
+        .ip_protocol
+        .as_ref()
+        .map_or("", String::as_str);
+    match (name, ports) {
+        ("tcp", (Some(from), Some(to))) => match from_port_range(from, to) {
+            Some(range) => IpProtocol::Tcp(range),
+            None => unknown_protocol(ip_permission),
+        },
+        ("udp", (Some(from), Some(to))) => match from_port_range(from, to) {
+            Some(range) => IpProtocol::Udp(range),
+            None => unknown_protocol(ip_permission),
+        },
+        ("icmp", (from, to)) => IpProtocol::Icmp {
+            icmp_type: from_icmp_port(from),
+            code: from_icmp_port(to),
+        },
+        ("icmpv6", (from, to)) | ("58", (from, to)) => IpProtocol::Icmpv6 {
+            icmp_type: from_icmp_port(from),
+            code: from_icmp_port(to),
+        },
+        ("-1", _) => IpProtocol::All,
+        ("tcp", _) | ("udp", _) => unknown_protocol(ip_permission),
+        (x, _) => match x.parse() {
+            Ok(number) => IpProtocol::Other(number),
+            Err(_) => unknown_protocol(ip_permission),
+        },
+    }
+}
+
+fn from_port_range(from: i64, to: i64) -> Option<IpPortRange> {
+    Some(IpPortRange(
+        u16::try_from(from).ok()?,
+        u16::try_from(to).ok()?,
+    ))
+}
+
+fn unknown_protocol(ip_permission: &IpPermission) -> IpProtocol {
+    IpProtocol::Unknown(format!(
+        "{} from port {:?} to port {:?}",
+        ip_permission
+            .ip_protocol
+            .as_ref()
+            .map_or("?", String::as_str),
+        ip_permission.from_port,
+        ip_permission.to_port
+    ))
+}
+
+fn from_icmp_port(port: Option<i64>) -> Option<u8> {
+    match port {
+        Some(port) if port >= 0 => Some(port as u8),
+        _ => None,
+    }
+}
+
+fn to_icmp_port(x: Option<u8>) -> Option<i64> {
+    Some(x.map_or(-1, i64::from))
+}
+
+fn to_ip_permission(
+    source: &IpSource,
+    ip_protocol: &IpProtocol,
+    meta: Option<&IpRuleMeta>,
+) -> IpPermission {
+    let (ip_protocol, from_port, to_port) = match *ip_protocol {
+        IpProtocol::Tcp(IpPortRange(from, to)) => {
+            ("tcp".to_owned(), Some(from.into()), Some(to.into()))
+        }
+        IpProtocol::Udp(IpPortRange(from, to)) => {
+            ("udp".to_owned(), Some(from.into()), Some(to.into()))
+        }
+        IpProtocol::Icmp { icmp_type, code } => (
+            "icmp".to_owned(),
+            to_icmp_port(icmp_type),
+            to_icmp_port(code),
+        ),
+        IpProtocol::Icmpv6 { icmp_type, code } => (
+            "icmpv6".to_owned(),
+            to_icmp_port(icmp_type),
+            to_icmp_port(code),
+        ),
+        IpProtocol::All => ("-1".to_owned(), None, None),
+        IpProtocol::Other(number) => (number.to_string(), None, None),
+        IpProtocol::Unknown(_) => unreachable!("unknown protocols are never changed"),
     };
-    let (ip_ranges, ipv_6_ranges) = match *ip_cidr {
-        IpNet::V4(ipv4_cidr) => (
-            Some(vec![IpRange {
+    let description = meta.map(IpRuleMeta::to_string);
+    let mut ip_permission = IpPermission {
+        ip_protocol: Some(ip_protocol),
+        from_port,
+        to_port,
+        ..Default::default()
+    };
+    match *source {
+        IpSource::Cidr(IpNet::V4(ipv4_cidr)) => {
+            ip_permission.ip_ranges = Some(vec![IpRange {
                 cidr_ip: Some(ipv4_cidr.to_string()),
-                ..Default::default()
-            }]),
-            None,
-        ),
-        IpNet::V6(ipv6_cidr) => (
-            None,
-            Some(vec![Ipv6Range {
+                description,
+            }]);
+        }
+        IpSource::Cidr(IpNet::V6(ipv6_cidr)) => {
+            ip_permission.ipv_6_ranges = Some(vec![Ipv6Range {
                 cidr_ipv_6: Some(ipv6_cidr.to_string()),
+                description,
+            }]);
+        }
+        IpSource::Group {
+            ref group_id,
+            ref user_id,
+        } => {
+            ip_permission.user_id_group_pairs = Some(vec![UserIdGroupPair {
+                group_id: Some(group_id.clone()),
+                user_id: user_id.clone(),
+                description,
                 ..Default::default()
-            }]),
-        ),
-    };
-    IpPermission {
-        ip_protocol: Some(ip_protocol.to_owned()),
-        from_port: Some(from_port.into()),
-        to_port: Some(to_port.into()),
-        ip_ranges: ip_ranges,
-        ipv_6_ranges: ipv_6_ranges,
-        prefix_list_ids: None,
-        user_id_group_pairs: None,
+            }]);
+        }
+        IpSource::PrefixList(ref prefix_list_id) => {
+            ip_permission.prefix_list_ids = Some(vec![PrefixListId {
+                prefix_list_id: Some(prefix_list_id.clone()),
+                description,
+            }]);
+        }
+        IpSource::Unknown(_) => unreachable!("unknown sources are never changed"),
+    }
+    ip_permission
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_permission_round_trip() {
+        for ip_protocol in &[
+            "22/tcp",
+            "60000-61000/udp",
+            "icmp",
+            "echo-request/icmp",
+            "destination-unreachable:4/icmp",
+            "icmpv6",
+            "echo-request/icmpv6",
+            "all",
+            "proto-50",
+        ] {
+            test_round_trip("192.0.2.0/24", ip_protocol, None);
+        }
+        for source in &[
+            "192.0.2.0/24",
+            "2001:db8::/32",
+            "sg-123",
+            "123456789012/sg-123",
+            "pl-123",
+        ] {
+            test_round_trip(source, "22/tcp", Some(IpRuleMeta::default()));
+        }
+    }
+
+    #[test]
+    fn test_unknown_ip_permissions_are_kept_as_unmanageable() {
+        let ip_range = |cidr_ip: &str| IpRange {
+            cidr_ip: Some(cidr_ip.to_owned()),
+            description: None,
+        };
+        let ip_permissions = vec![
+            // tcp without ports
+            IpPermission {
+                ip_protocol: Some("tcp".to_owned()),
+                ip_ranges: Some(vec![ip_range("192.0.2.0/24")]),
+                ..Default::default()
+            },
+            // a protocol name that is not a number
+            IpPermission {
+                ip_protocol: Some("gre".to_owned()),
+                ipv_6_ranges: Some(vec![Ipv6Range {
+                    cidr_ipv_6: Some("2001:db8::/32".to_owned()),
+                    description: None,
+                }]),
+                ..Default::default()
+            },
+            // missing protocol and prefix list ID
+            IpPermission {
+                prefix_list_ids: Some(vec![PrefixListId {
+                    prefix_list_id: None,
+                    description: None,
+                }]),
+                ..Default::default()
+            },
+            // a CIDR that cannot be parsed
+            IpPermission {
+                ip_protocol: Some("tcp".to_owned()),
+                from_port: Some(22),
+                to_port: Some(22),
+                ip_ranges: Some(vec![ip_range("192.0.2.0/99")]),
+                ..Default::default()
+            },
+        ];
+        let rules = from_ip_permissions(ip_permissions, IpIngressRule);
+        assert_eq!(4, rules.len());
+        assert!(rules.keys().all(|rule| !rule.is_manageable()));
+        assert!(rules.contains_key(&IpIngressRule(
+            "192.0.2.0/24".parse().unwrap(),
+            IpProtocol::Unknown("tcp from port None to port None".to_owned()),
+        )));
+        assert!(rules.contains_key(&IpIngressRule(
+            IpSource::Unknown("192.0.2.0/99".to_owned()),
+            "22/tcp".parse().unwrap(),
+        )));
+    }
+
+    fn test_round_trip(source: &str, ip_protocol: &str, meta: Option<IpRuleMeta>) {
+        let source: IpSource = source.parse().unwrap();
+        let ip_protocol: IpProtocol = ip_protocol.parse().unwrap();
+        let ip_permission = to_ip_permission(&source, &ip_protocol, meta.as_ref());
+        let rules = from_ip_permissions(vec![ip_permission], IpEgressRule);
+        let mut expected_rules = HashMap::new();
+        expected_rules.insert(IpEgressRule(source, ip_protocol), meta);
+        assert_eq!(expected_rules, rules);
     }
 }
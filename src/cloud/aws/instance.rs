@@ -1,10 +1,20 @@
 use crate::cloud::aws::tags::TagFinder;
+use crate::cloud::DnsAddress;
+use crate::cloud::DnsOptions;
+use crate::cloud::DnsRecordType;
 use crate::cloud::Instance;
 use crate::cloud::InstanceRunningState;
+use crate::cloud::InstanceStateCode;
+use crate::cloud::InstanceStatus;
 use crate::cloud::InstanceType;
 use crate::dns::DnsTarget;
+use crate::future::BoxFuture;
+use crate::retry::retry;
 use failure::Error;
 use failure::ResultExt;
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
 use rusoto_ec2::AttributeValue;
 use rusoto_ec2::DescribeInstancesRequest;
 use rusoto_ec2::Ec2;
@@ -14,88 +24,119 @@ use rusoto_ec2::StartInstancesRequest;
 use rusoto_ec2::StopInstancesRequest;
 use std::fmt;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::rc::Rc;
 use std::str::FromStr;
-use std::thread;
-use std::time::Duration;
+use tokio_core::reactor::Handle;
 
+#[derive(Clone)]
 pub struct AwsInstance {
     id: String,
     name: String,
     fqdn: Option<String>,
+    private_fqdn: Option<String>,
+    vpc_id: Option<String>,
+    // tags parsed into DNS options when used, so that invalid tags fail only this instance
+    dns_ttl: Option<String>,
+    dns_record_type: Option<String>,
+    dns_target: Option<String>,
+    region: String,
     client: Rc<dyn Ec2>,
+    handle: Handle,
 }
 
 impl AwsInstance {
-    pub(super) fn list(client: &Rc<dyn Ec2>, filter: Filter) -> Result<Vec<AwsInstance>, Error> {
+    pub(super) fn list(
+        client: &Rc<dyn Ec2>,
+        handle: &Handle,
+        region: &str,
+        filters: Vec<Filter>,
+    ) -> BoxFuture<Vec<AwsInstance>> {
         let req = DescribeInstancesRequest {
-            filters: Some(vec![filter]),
+            filters: Some(filters),
             ..Default::default()
         };
-        let resp = client
-            .describe_instances(&req)
-            .sync()
-            .with_context(|_e| format!("failed to describe instances: {:?}", req))?;
-        let mut values: Vec<AwsInstance> = Vec::new();
-        for r in resp.reservations.unwrap() {
-            for i in r.instances.unwrap() {
-                let id = i.instance_id.unwrap();
-                let tags = i.tags.unwrap();
-                let name = tags
-                    .find_tag("Name")
-                    .ok_or_else(|| format_err!("expected instance to have Name tag: {}", id))?;
-                let fqdn = tags.find_tag("Fqdn");
-                let value = AwsInstance {
-                    id: id,
-                    name: name.to_owned(),
-                    fqdn: fqdn.map(str::to_owned),
-                    client: Rc::clone(client),
-                };
-                values.push(value);
-            }
-        }
-        Ok(values)
+        let client = Rc::clone(client);
+        let handle = handle.clone();
+        let region = region.to_owned();
+        Box::new(future::loop_fn(
+            (req, Vec::new()),
+            move |(mut req, mut values): (_, Vec<AwsInstance>)| {
+                let (ec2, attempt) = (Rc::clone(&client), req.clone());
+                let (client, handle, region) = (Rc::clone(&client), handle.clone(), region.clone());
+                retry(&handle, move || ec2.describe_instances(&attempt)).then(
+                    move |result| -> Result<_, Error> {
+                        let resp = result.with_context(|_e| {
+                            format!("failed to describe instances: {:?}", req)
+                        })?;
+                        for r in resp.reservations.unwrap_or_default() {
+                            for i in r.instances.unwrap() {
+                                let id = i.instance_id.unwrap();
+                                let tags = i.tags.unwrap();
+                                let name = tags.find_tag("Name").ok_or_else(|| {
+                                    format_err!("expected instance to have Name tag: {}", id)
+                                })?;
+                                let fqdn = tags.find_tag("Fqdn");
+                                let private_fqdn = tags.find_tag("PrivateFqdn");
+                                let value = AwsInstance {
+                                    id: id,
+                                    name: name.to_owned(),
+                                    fqdn: fqdn.map(str::to_owned),
+                                    private_fqdn: private_fqdn.map(str::to_owned),
+                                    vpc_id: i.vpc_id,
+                                    dns_ttl: tags.find_tag("DnsTtl").map(str::to_owned),
+                                    dns_record_type: tags
+                                        .find_tag("DnsRecordType")
+                                        .map(str::to_owned),
+                                    dns_target: tags.find_tag("DnsTarget").map(str::to_owned),
+                                    region: region.clone(),
+                                    client: Rc::clone(&client),
+                                    handle: handle.clone(),
+                                };
+                                values.push(value);
+                            }
+                        }
+                        // The results are paginated if there are many
+                        match resp.next_token {
+                            Some(next_token) => {
+                                req.next_token = Some(next_token);
+                                Ok(Loop::Continue((req, values)))
+                            }
+                            None => Ok(Loop::Break(values)),
+                        }
+                    },
+                )
+            },
+        ))
     }
 
-    fn get_state(&self) -> Result<InstanceState, Error> {
+    fn get_state(&self) -> BoxFuture<InstanceState> {
         let req = DescribeInstancesRequest {
             instance_ids: Some(vec![self.id.clone()]),
             ..Default::default()
         };
-        let resp = self
-            .client
-            .describe_instances(&req)
-            .sync()
-            .with_context(|_e| format!("failed to describe instance: {:?}", self))?;
-        let i = resp
-            .reservations
-            .unwrap()
-            .into_iter()
-            .next()
-            .and_then(|r| r.instances.unwrap().into_iter().next())
-            .ok_or_else(|| format_err!("failed to find instance: {:?}", self))?;
-        let instance_state_code = (i.state.unwrap().code.unwrap() as u8).into();
-        let instance_type = InstanceType(i.instance_type.unwrap());
-        let ebs_optimized = i.ebs_optimized.unwrap();
-        let public_ipv4_addr = match i.public_ip_address {
-            Some(ip_addr_str) => {
-                let ip_addr = Ipv4Addr::from_str(&ip_addr_str)
-                    .with_context(|_e| format!("not an IP address: {}", ip_addr_str))?;
-                Some(ip_addr)
-            }
-            None => None,
-        };
-        let public_dns_name = i.public_dns_name;
-        Ok(InstanceState {
-            instance_state_code,
-            instance_type,
-            ebs_optimized,
-            public_ipv4_addr,
-            public_dns_name,
-        })
+        let client = Rc::clone(&self.client);
+        let instance = self.clone();
+        Box::new(
+            retry(&self.handle, move || client.describe_instances(&req)).then(
+                move |result| -> Result<_, Error> {
+                    let resp = result.with_context(|_e| {
+                        format!("failed to describe instance: {:?}", instance)
+                    })?;
+                    let i = resp
+                        .reservations
+                        .unwrap()
+                        .into_iter()
+                        .next()
+                        .and_then(|r| r.instances.unwrap().into_iter().next())
+                        .ok_or_else(|| format_err!("failed to find instance: {:?}", instance))?;
+                    InstanceState::from_instance(i)
+                },
+            ),
+        )
     }
 
-    fn change_instance_type(&self, instance_type: &InstanceType) -> Result<(), Error> {
+    fn change_instance_type(&self, instance_type: &InstanceType) -> BoxFuture<()> {
         let req = ModifyInstanceAttributeRequest {
             instance_id: self.id.clone(),
             instance_type: Some(AttributeValue {
@@ -103,46 +144,61 @@ impl AwsInstance {
             }),
             ..Default::default()
         };
-        self.client
-            .modify_instance_attribute(&req)
-            .sync()
-            .with_context(|_e| {
-                format!(
-                    "failed to change instance type to {}: {}",
-                    instance_type, self.id
-                )
-            })?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let (id, instance_type) = (self.id.clone(), instance_type.clone());
+        Box::new(
+            retry(&self.handle, move || client.modify_instance_attribute(&req)).then(
+                move |result| -> Result<_, Error> {
+                    result.with_context(|_e| {
+                        format!(
+                            "failed to change instance type to {}: {}",
+                            instance_type, id
+                        )
+                    })?;
+                    Ok(())
+                },
+            ),
+        )
     }
 
-    fn request_start(&self) -> Result<(), Error> {
+    fn request_start(&self) -> BoxFuture<()> {
         let req = StartInstancesRequest {
             instance_ids: vec![self.id.clone()],
             ..Default::default()
         };
-        self.client
-            .start_instances(&req)
-            .sync()
-            .with_context(|_e| format!("failed to start instance: {}", self.id))?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let id = self.id.clone();
+        Box::new(
+            retry(&self.handle, move || client.start_instances(&req)).then(
+                move |result| -> Result<_, Error> {
+                    result.with_context(|_e| format!("failed to start instance: {}", id))?;
+                    Ok(())
+                },
+            ),
+        )
     }
 
-    fn request_stop(&self) -> Result<(), Error> {
+    fn request_stop(&self) -> BoxFuture<()> {
         let req = StopInstancesRequest {
             instance_ids: vec![self.id.clone()],
             ..Default::default()
         };
-        self.client
-            .stop_instances(&req)
-            .sync()
-            .with_context(|_e| format!("failed to stop instance: {}", self.id))?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let id = self.id.clone();
+        Box::new(
+            retry(&self.handle, move || client.stop_instances(&req)).then(
+                move |result| -> Result<_, Error> {
+                    result.with_context(|_e| format!("failed to stop instance: {}", id))?;
+                    Ok(())
+                },
+            ),
+        )
     }
 }
 
 impl fmt::Debug for AwsInstance {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ({})", self.name, self.id)
+        write!(f, "{} ({} in {})", self.name, self.id, self.region)
     }
 }
 
@@ -159,70 +215,168 @@ impl Instance for AwsInstance {
         self.fqdn.as_ref().map(String::as_ref)
     }
 
-    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> Result<(), Error> {
-        let state = self.get_state()?;
-        println!("Instance state: {:?}", state);
-        if state.instance_type == *instance_type {
-            Ok(())
-        } else if state.instance_state_code == InstanceStateCode::Stopped {
-            self.change_instance_type(instance_type)?;
-            Ok(())
-        } else {
-            Err(format_err!("instance must be stopped to change its type"))
-        }
+    fn private_fqdn(&self) -> Option<&str> {
+        self.private_fqdn.as_deref()
+    }
+
+    fn vpc_id(&self) -> Option<&str> {
+        self.vpc_id.as_deref()
     }
 
-    fn ensure_running(&self) -> Result<InstanceRunningState, Error> {
-        loop {
-            let state = self.get_state()?;
-            println!("Instance state: {:?}", state);
+    fn dns_options(&self) -> Result<DnsOptions, Error> {
+        let dns_options = DnsOptions::from_tags(
+            self.dns_ttl.as_deref(),
+            self.dns_record_type.as_deref(),
+            self.dns_target.as_deref(),
+        )
+        .with_context(|_e| format!("invalid DNS tags on instance: {}", self.id))?;
+        Ok(dns_options)
+    }
+
+    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> BoxFuture<()> {
+        let instance = self.clone();
+        let instance_type = instance_type.clone();
+        Box::new(self.get_state().and_then(move |state| -> BoxFuture<_> {
+            eprintln!("Instance state: {:?}", state);
+            if state.instance_type == instance_type {
+                Box::new(future::ok(()))
+            } else if state.instance_state_code == InstanceStateCode::Stopped {
+                instance.change_instance_type(&instance_type)
+            } else {
+                Box::new(future::err(format_err!(
+                    "instance must be stopped to change its type"
+                )))
+            }
+        }))
+    }
+
+    fn poll_running(&self) -> BoxFuture<Option<InstanceRunningState>> {
+        let instance = self.clone();
+        Box::new(self.get_state().and_then(move |state| -> BoxFuture<_> {
+            eprintln!("Instance state: {:?} {:?}", instance, state);
             match state.instance_state_code {
-                InstanceStateCode::Pending | InstanceStateCode::Stopping => (),
-                InstanceStateCode::Running => {
-                    let addr = {
-                        if let Some(public_dns_name) = state.public_dns_name {
-                            // Prefer the DNS name if it exists,
-                            // because AWS will resolve it to an internal IP where possible.
-                            Ok(DnsTarget::Cname(public_dns_name))
-                        } else if let Some(public_ipv4_addr) = state.public_ipv4_addr {
-                            // DNS names are probably disabled for this VPC.
-                            // Use the IPv4 address instead.
-                            Ok(DnsTarget::A(public_ipv4_addr))
-                        } else {
-                            Err(format_err!(
-                                "expected running instance to have IPv4 address: {:?}",
-                                state
-                            ))
-                        }
-                    }?;
-                    return Ok(InstanceRunningState {
-                        instance_type: state.instance_type,
-                        addr,
-                    });
+                InstanceStateCode::Pending | InstanceStateCode::Stopping => {
+                    Box::new(future::ok(None))
                 }
-                InstanceStateCode::Stopped => self.request_start()?,
-                InstanceStateCode::Terminating => bail!("instance is terminating"),
-                InstanceStateCode::Terminated => bail!("instance is terminated"),
-                InstanceStateCode::Unknown(x) => bail!("instance is in unknown state: {}", x),
+                InstanceStateCode::Running => Box::new(future::result(
+                    instance
+                        .dns_options()
+                        .and_then(|dns_options| state.running_addrs(&dns_options))
+                        .map(|addrs| {
+                            Some(InstanceRunningState {
+                                instance_type: state.instance_type.clone(),
+                                addrs,
+                                private_addrs: state.private_addrs(),
+                            })
+                        }),
+                )),
+                InstanceStateCode::Stopped => Box::new(instance.request_start().map(|()| None)),
+                InstanceStateCode::Terminating => {
+                    Box::new(future::err(format_err!("instance is terminating")))
+                }
+                InstanceStateCode::Terminated => {
+                    Box::new(future::err(format_err!("instance is terminated")))
+                }
+                InstanceStateCode::Unknown(x) => Box::new(future::err(format_err!(
+                    "instance is in unknown state: {}",
+                    x
+                ))),
             }
-            thread::sleep(Duration::from_secs(1));
-        }
+        }))
     }
 
-    fn ensure_stopped(&self) -> Result<(), Error> {
-        loop {
-            let state = self.get_state()?;
-            println!("Instance state: {:?}", state);
+    fn poll_stopped(&self) -> BoxFuture<Option<()>> {
+        let instance = self.clone();
+        Box::new(self.get_state().and_then(move |state| -> BoxFuture<_> {
+            eprintln!("Instance state: {:?} {:?}", instance, state);
             match state.instance_state_code {
-                InstanceStateCode::Pending | InstanceStateCode::Stopping => (),
-                InstanceStateCode::Running => self.request_stop()?,
-                InstanceStateCode::Stopped => return Ok(()),
-                InstanceStateCode::Terminating => bail!("instance is terminating"),
-                InstanceStateCode::Terminated => bail!("instance is terminated"),
-                InstanceStateCode::Unknown(x) => bail!("instance is in unknown state: {}", x),
+                InstanceStateCode::Pending | InstanceStateCode::Stopping => {
+                    Box::new(future::ok(None))
+                }
+                InstanceStateCode::Running => Box::new(instance.request_stop().map(|()| None)),
+                InstanceStateCode::Stopped => Box::new(future::ok(Some(()))),
+                InstanceStateCode::Terminating => {
+                    Box::new(future::err(format_err!("instance is terminating")))
+                }
+                InstanceStateCode::Terminated => {
+                    Box::new(future::err(format_err!("instance is terminated")))
+                }
+                InstanceStateCode::Unknown(x) => Box::new(future::err(format_err!(
+                    "instance is in unknown state: {}",
+                    x
+                ))),
             }
-            thread::sleep(Duration::from_secs(1));
-        }
+        }))
+    }
+
+    fn request_running(&self) -> BoxFuture<()> {
+        let instance = self.clone();
+        Box::new(self.get_state().and_then(move |state| -> BoxFuture<_> {
+            eprintln!("Instance state: {:?}", state);
+            match state.instance_state_code {
+                InstanceStateCode::Pending | InstanceStateCode::Running => Box::new(future::ok(())),
+                InstanceStateCode::Stopped => instance.request_start(),
+                // EC2 rejects a start request until the instance has stopped
+                InstanceStateCode::Stopping => Box::new(future::err(format_err!(
+                    "instance is stopping, so cannot be started yet"
+                ))),
+                InstanceStateCode::Terminating => {
+                    Box::new(future::err(format_err!("instance is terminating")))
+                }
+                InstanceStateCode::Terminated => {
+                    Box::new(future::err(format_err!("instance is terminated")))
+                }
+                InstanceStateCode::Unknown(x) => Box::new(future::err(format_err!(
+                    "instance is in unknown state: {}",
+                    x
+                ))),
+            }
+        }))
+    }
+
+    fn request_stopped(&self) -> BoxFuture<()> {
+        let instance = self.clone();
+        Box::new(self.get_state().and_then(move |state| -> BoxFuture<_> {
+            eprintln!("Instance state: {:?}", state);
+            match state.instance_state_code {
+                InstanceStateCode::Stopping | InstanceStateCode::Stopped => {
+                    Box::new(future::ok(()))
+                }
+                InstanceStateCode::Running => instance.request_stop(),
+                // EC2 rejects a stop request until the instance is running
+                InstanceStateCode::Pending => Box::new(future::err(format_err!(
+                    "instance is pending, so cannot be stopped yet"
+                ))),
+                InstanceStateCode::Terminating => {
+                    Box::new(future::err(format_err!("instance is terminating")))
+                }
+                InstanceStateCode::Terminated => {
+                    Box::new(future::err(format_err!("instance is terminated")))
+                }
+                InstanceStateCode::Unknown(x) => Box::new(future::err(format_err!(
+                    "instance is in unknown state: {}",
+                    x
+                ))),
+            }
+        }))
+    }
+
+    fn get_status(&self) -> BoxFuture<InstanceStatus> {
+        let dns_options = self.dns_options();
+        Box::new(self.get_state().and_then(move |state| -> Result<_, Error> {
+            let (addrs, private_addrs) = match state.instance_state_code {
+                InstanceStateCode::Running => {
+                    (state.running_addrs(&dns_options?)?, state.private_addrs())
+                }
+                _ => (Vec::new(), Vec::new()),
+            };
+            Ok(InstanceStatus {
+                state: state.instance_state_code,
+                instance_type: state.instance_type,
+                addrs,
+                private_addrs,
+            })
+        }))
     }
 }
 
@@ -232,18 +386,118 @@ struct InstanceState {
     instance_type: InstanceType,
     ebs_optimized: bool,
     public_ipv4_addr: Option<Ipv4Addr>,
+    private_ipv4_addr: Option<Ipv4Addr>,
+    ipv6_addrs: Vec<Ipv6Addr>,
+    has_elastic_ip: bool,
     public_dns_name: Option<String>,
+    private_dns_name: Option<String>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum InstanceStateCode {
-    Pending,
-    Running,
-    Terminating, // called "shutting-down" by AWS
-    Terminated,
-    Stopping,
-    Stopped,
-    Unknown(u8),
+impl InstanceState {
+    fn from_instance(i: rusoto_ec2::Instance) -> Result<InstanceState, Error> {
+        let instance_state_code = (i.state.unwrap().code.unwrap() as u8).into();
+        let instance_type = InstanceType(i.instance_type.unwrap());
+        let ebs_optimized = i.ebs_optimized.unwrap();
+        let public_ipv4_addr = match i.public_ip_address {
+            Some(ip_addr_str) => {
+                let ip_addr = Ipv4Addr::from_str(&ip_addr_str)
+                    .with_context(|_e| format!("not an IP address: {}", ip_addr_str))?;
+                Some(ip_addr)
+            }
+            None => None,
+        };
+        let private_ipv4_addr = match i.private_ip_address {
+            Some(ip_addr_str) => {
+                let ip_addr = Ipv4Addr::from_str(&ip_addr_str)
+                    .with_context(|_e| format!("not an IP address: {}", ip_addr_str))?;
+                Some(ip_addr)
+            }
+            None => None,
+        };
+        let mut ipv6_addrs = Vec::new();
+        let mut has_elastic_ip = false;
+        for ni in i.network_interfaces.unwrap_or_default() {
+            // Addresses from the pool that AWS assigns at start are owned by "amazon"
+            if let Some(ip_owner_id) = ni.association.and_then(|x| x.ip_owner_id) {
+                has_elastic_ip |= ip_owner_id != "amazon";
+            }
+            for ip_addr in ni.ipv_6_addresses.unwrap_or_default() {
+                if let Some(ip_addr_str) = ip_addr.ipv_6_address {
+                    let ip_addr = Ipv6Addr::from_str(&ip_addr_str)
+                        .with_context(|_e| format!("not an IP address: {}", ip_addr_str))?;
+                    ipv6_addrs.push(ip_addr);
+                }
+            }
+        }
+        let public_dns_name = i.public_dns_name;
+        let private_dns_name = i.private_dns_name;
+        Ok(InstanceState {
+            instance_state_code,
+            instance_type,
+            ebs_optimized,
+            public_ipv4_addr,
+            private_ipv4_addr,
+            ipv6_addrs,
+            has_elastic_ip,
+            public_dns_name,
+            private_dns_name,
+        })
+    }
+
+    // Only IPv4 addresses are private, as IPv6 addresses in a VPC are global
+    fn private_addrs(&self) -> Vec<DnsTarget> {
+        self.private_ipv4_addr
+            .map(DnsTarget::A)
+            .into_iter()
+            .collect()
+    }
+
+    // The targets for the public hostname
+    fn running_addrs(&self, options: &DnsOptions) -> Result<Vec<DnsTarget>, Error> {
+        let (ipv4_addr, dns_name, ipv6_addrs) = match options.address {
+            DnsAddress::Public => (
+                self.public_ipv4_addr,
+                &self.public_dns_name,
+                &self.ipv6_addrs[..],
+            ),
+            DnsAddress::Private => (self.private_ipv4_addr, &self.private_dns_name, &[][..]),
+            DnsAddress::ElasticIp if self.has_elastic_ip => (
+                self.public_ipv4_addr,
+                &self.public_dns_name,
+                &self.ipv6_addrs[..],
+            ),
+            DnsAddress::ElasticIp => {
+                bail!("expected running instance to have Elastic IP: {:?}", self)
+            }
+        };
+        // AWS gives an empty DNS name if DNS names are disabled for the VPC
+        let dns_name = dns_name.as_ref().filter(|x| !x.is_empty());
+        let mut addrs: Vec<DnsTarget> = ipv4_addr.map(DnsTarget::A).into_iter().collect();
+        addrs.extend(ipv6_addrs.iter().cloned().map(DnsTarget::Aaaa));
+
+        match (options.record_type, dns_name) {
+            (Some(DnsRecordType::Cname), Some(dns_name)) => {
+                Ok(vec![DnsTarget::Cname(dns_name.clone())])
+            }
+            (Some(DnsRecordType::Cname), None) => Err(format_err!(
+                "expected running instance to have DNS name: {:?}",
+                self
+            )),
+            // The AWS DNS name does not resolve to IPv6 addresses,
+            // and a CNAME cannot coexist with an AAAA record.
+            // Use the IPv4 and IPv6 addresses instead.
+            (None, Some(dns_name)) if ipv6_addrs.is_empty() => {
+                // Prefer the DNS name if it exists,
+                // because AWS will resolve it to an internal IP where possible.
+                Ok(vec![DnsTarget::Cname(dns_name.clone())])
+            }
+            _ if !addrs.is_empty() => Ok(addrs),
+            _ => Err(format_err!(
+                "expected running instance to have IP address: {:?}",
+                self
+            )),
+        }
+    }
 }
 
 // TODO: probably should be TryFrom, without the Unknown state
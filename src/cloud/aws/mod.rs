@@ -1,31 +1,125 @@
 use crate::cloud::aws::firewall::AwsFirewall;
 use crate::cloud::aws::instance::AwsInstance;
 use crate::cloud::Cloud;
+use crate::cloud::Firewall;
+use crate::cloud::Instance;
+use crate::cloud::Selector;
+use crate::credentials::ProfileCredentials;
+use crate::future::BoxFuture;
+use crate::retry::retry;
 use failure::Error;
 use failure::ResultExt;
+use futures::future;
+use futures::Future;
+use rusoto_core::DefaultCredentialsProvider;
+use rusoto_core::HttpClient;
 use rusoto_core::Region;
+use rusoto_ec2::DescribeRegionsRequest;
 use rusoto_ec2::Ec2;
 use rusoto_ec2::Ec2Client;
 use rusoto_ec2::Filter;
 use std::env;
 use std::rc::Rc;
 use std::str::FromStr;
+use tokio_core::reactor::Core;
+use tokio_core::reactor::Handle;
 
 mod firewall;
 mod instance;
 mod tags;
 
+// Which regions firewalls and instances are found in
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Regions {
+    // as given by AWS_DEFAULT_REGION
+    #[default]
+    Default,
+    Named(Vec<String>),
+    // all regions enabled for the account
+    All,
+}
+
+// One client per region, as each region has its own EC2 endpoint.
+// All make their requests on the reactor of the given core.
 pub struct AwsCloud {
-    client: Rc<dyn Ec2>,
+    clients: Vec<(String, Rc<dyn Ec2>)>,
+    handle: Handle,
 }
 
 impl AwsCloud {
-    pub fn new() -> Result<AwsCloud, Error> {
-        let region = AwsCloud::default_region()?;
-        let ec2 = Ec2Client::simple(region);
-        Ok(AwsCloud {
-            client: Rc::new(ec2),
-        })
+    // Without a profile's credentials, the default credentials are used
+    pub fn new(
+        core: &mut Core,
+        regions: &Regions,
+        credentials: Option<ProfileCredentials>,
+    ) -> Result<AwsCloud, Error> {
+        let handle = core.handle();
+        let regions = match *regions {
+            Regions::Default => vec![AwsCloud::default_region()?],
+            Regions::Named(ref names) => names
+                .iter()
+                .map(|name| {
+                    Region::from_str(name)
+                        .with_context(|_e| format!("region is invalid: {}", name))
+                        .map_err(Error::from)
+                })
+                .collect::<Result<Vec<Region>, Error>>()?,
+            Regions::All => AwsCloud::list_regions(core, &credentials)?,
+        };
+        let clients = regions
+            .into_iter()
+            .map(|region| {
+                let name = region.name().to_owned();
+                let ec2 = AwsCloud::client(&handle, &credentials, region)?;
+                Ok((name, ec2))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(AwsCloud { clients, handle })
+    }
+
+    // The regions are listed from the default region, or us-east-1 if there is none
+    fn list_regions(
+        core: &mut Core,
+        credentials: &Option<ProfileCredentials>,
+    ) -> Result<Vec<Region>, Error> {
+        let handle = core.handle();
+        let region = AwsCloud::default_region().unwrap_or(Region::UsEast1);
+        let ec2 = AwsCloud::client(&handle, credentials, region)?;
+        let req = DescribeRegionsRequest {
+            ..Default::default()
+        };
+        let resp = core
+            .run(retry(&handle, move || ec2.describe_regions(&req)))
+            .context("failed to describe regions")?;
+        let mut regions = Vec::new();
+        for r in resp.regions.unwrap_or_default() {
+            let name = r
+                .region_name
+                .ok_or_else(|| format_err!("expected region to have a name"))?;
+            let endpoint = r.endpoint.unwrap_or_default();
+            // Regions newer than rusoto are reached through their endpoints
+            let region = Region::from_str(&name).unwrap_or(Region::Custom { name, endpoint });
+            regions.push(region);
+        }
+        Ok(regions)
+    }
+
+    fn client(
+        handle: &Handle,
+        credentials: &Option<ProfileCredentials>,
+        region: Region,
+    ) -> Result<Rc<dyn Ec2>, Error> {
+        let dispatcher = HttpClient::new(handle)?;
+        let ec2: Rc<dyn Ec2> = match *credentials {
+            Some(ref credentials) => {
+                Rc::new(Ec2Client::new(dispatcher, credentials.clone(), region))
+            }
+            None => {
+                let credentials = DefaultCredentialsProvider::new(handle)?;
+                Rc::new(Ec2Client::new(dispatcher, credentials, region))
+            }
+        };
+        Ok(ec2)
     }
 
     fn default_region() -> Result<Region, Error> {
@@ -41,30 +135,78 @@ impl Cloud for AwsCloud {
     type Firewall = AwsFirewall;
     type Instance = AwsInstance;
 
-    fn list_firewalls<'a, N, S>(&self, names: N) -> Result<Vec<AwsFirewall>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a,
-    {
-        AwsFirewall::list(&self.client, build_filter(names))
+    // The regions, and the requests within each, are listed together
+    fn list_firewalls(&self, selector: &Selector) -> BoxFuture<Vec<AwsFirewall>> {
+        let mut lists = Vec::new();
+        for (region, client) in &self.clients {
+            for filters in build_filters(selector, "sg-", "group-id") {
+                lists.push(AwsFirewall::list(client, &self.handle, region, filters));
+            }
+        }
+        Box::new(future::join_all(lists).map(|lists| {
+            let mut values: Vec<AwsFirewall> = Vec::new();
+            for value in lists.into_iter().flatten() {
+                if !values.iter().any(|x| x.id() == value.id()) {
+                    values.push(value);
+                }
+            }
+            values
+        }))
     }
 
-    fn list_instances<'a, N, S>(&self, names: N) -> Result<Vec<AwsInstance>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a,
-    {
-        AwsInstance::list(&self.client, build_filter(names))
+    fn list_instances(&self, selector: &Selector) -> BoxFuture<Vec<AwsInstance>> {
+        let mut lists = Vec::new();
+        for (region, client) in &self.clients {
+            for filters in build_filters(selector, "i-", "instance-id") {
+                lists.push(AwsInstance::list(client, &self.handle, region, filters));
+            }
+        }
+        Box::new(future::join_all(lists).map(|lists| {
+            let mut values: Vec<AwsInstance> = Vec::new();
+            for value in lists.into_iter().flatten() {
+                if !values.iter().any(|x| x.id() == value.id()) {
+                    values.push(value);
+                }
+            }
+            values
+        }))
+    }
+}
+
+// EC2 filters are combined with AND, but IDs and names are alternatives,
+// so each needs its own request. EC2 itself matches the * and ? wildcards.
+fn build_filters(selector: &Selector, id_prefix: &str, id_filter_name: &str) -> Vec<Vec<Filter>> {
+    let tag_filters: Vec<Filter> = selector
+        .tags
+        .iter()
+        .map(|(k, v)| build_filter(&format!("tag:{}", k), vec![v.clone()]))
+        .collect();
+
+    if selector.is_tags_only() {
+        return vec![tag_filters];
+    }
+
+    let mut requests = Vec::new();
+    let ids: Vec<String> = selector
+        .ids_with_prefix(id_prefix)
+        .map(str::to_owned)
+        .collect();
+    if !ids.is_empty() {
+        let mut filters = vec![build_filter(id_filter_name, ids)];
+        filters.extend(tag_filters.iter().cloned());
+        requests.push(filters);
+    }
+    if !selector.names.is_empty() {
+        let mut filters = vec![build_filter("tag:Name", selector.names.clone())];
+        filters.extend(tag_filters.iter().cloned());
+        requests.push(filters);
     }
+    requests
 }
 
-fn build_filter<'a, N, S>(names: N) -> Filter
-where
-    N: IntoIterator<Item = &'a S>,
-    S: AsRef<str> + 'a,
-{
+fn build_filter(name: &str, values: Vec<String>) -> Filter {
     Filter {
-        name: Some("tag:Name".to_owned()),
-        values: Some(names.into_iter().map(|x| x.as_ref().to_owned()).collect()),
+        name: Some(name.to_owned()),
+        values: Some(values),
     }
 }
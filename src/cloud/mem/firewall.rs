@@ -1,7 +1,13 @@
 use crate::cloud::Firewall;
+use crate::cloud::Selector;
+use crate::future::BoxFuture;
+use crate::iprules::IpEgressRule;
 use crate::iprules::IpIngressRule;
+use crate::iprules::IpRuleMeta;
 use failure::Error;
+use futures::future;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::rc::Rc;
@@ -14,7 +20,9 @@ pub struct MemFirewall {
 }
 
 struct MemFirewallState {
-    rules: HashSet<IpIngressRule>,
+    tags: HashMap<String, String>,
+    rules: HashMap<IpIngressRule, Option<IpRuleMeta>>,
+    egress_rules: HashMap<IpEgressRule, Option<IpRuleMeta>>,
 }
 
 impl MemFirewall {
@@ -23,10 +31,56 @@ impl MemFirewall {
             id,
             name,
             state: Rc::new(RefCell::new(MemFirewallState {
-                rules: HashSet::new(),
+                tags: HashMap::new(),
+                rules: HashMap::new(),
+                egress_rules: HashMap::new(),
             })),
         })
     }
+
+    pub fn add_tag(&self, key: &str, value: &str) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.tags.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    pub(super) fn matches(&self, selector: &Selector) -> bool {
+        let state = self.state.borrow();
+        selector.matches("sg-", &self.id, &self.name, &state.tags)
+    }
+
+    // as if created by another tool, without drawbridge metadata
+    pub fn add_external_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    where
+        R: IntoIterator<Item = &'a IpIngressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            state.rules.insert(rule.clone(), None);
+        }
+        Ok(())
+    }
+
+    pub fn list_ingress_rules(&self) -> Result<HashSet<IpIngressRule>, Error> {
+        let state = self.state.borrow();
+        Ok(state.rules.keys().cloned().collect())
+    }
+
+    pub fn add_external_egress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            state.egress_rules.insert(rule.clone(), None);
+        }
+        Ok(())
+    }
+
+    pub fn list_egress_rules(&self) -> Result<HashSet<IpEgressRule>, Error> {
+        let state = self.state.borrow();
+        Ok(state.egress_rules.keys().cloned().collect())
+    }
 }
 
 impl fmt::Debug for MemFirewall {
@@ -44,23 +98,36 @@ impl Firewall for MemFirewall {
         &self.name
     }
 
-    fn list_ingress_rules(&self) -> Result<HashSet<IpIngressRule>, Error> {
+    fn describe_ingress_rules(&self) -> BoxFuture<HashMap<IpIngressRule, Option<IpRuleMeta>>> {
         let state = self.state.borrow();
-        Ok(state.rules.iter().cloned().collect())
+        Box::new(future::ok(state.rules.clone()))
     }
 
-    fn add_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    fn add_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>,
     {
         let mut state = self.state.borrow_mut();
         for rule in rules {
-            state.rules.insert(*rule);
+            state.rules.insert(rule.clone(), Some(meta.clone()));
         }
-        Ok(())
+        Box::new(future::ok(()))
+    }
+
+    fn update_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpIngressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            if let Some(existing_meta) = state.rules.get_mut(rule) {
+                *existing_meta = Some(meta.clone());
+            }
+        }
+        Box::new(future::ok(()))
     }
 
-    fn remove_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    fn remove_ingress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>,
     {
@@ -68,6 +135,46 @@ impl Firewall for MemFirewall {
         for rule in rules {
             state.rules.remove(rule);
         }
-        Ok(())
+        Box::new(future::ok(()))
+    }
+
+    fn describe_egress_rules(&self) -> BoxFuture<HashMap<IpEgressRule, Option<IpRuleMeta>>> {
+        let state = self.state.borrow();
+        Box::new(future::ok(state.egress_rules.clone()))
+    }
+
+    fn add_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            state.egress_rules.insert(rule.clone(), Some(meta.clone()));
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn update_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            if let Some(existing_meta) = state.egress_rules.get_mut(rule) {
+                *existing_meta = Some(meta.clone());
+            }
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn remove_egress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>,
+    {
+        let mut state = self.state.borrow_mut();
+        for rule in rules {
+            state.egress_rules.remove(rule);
+        }
+        Box::new(future::ok(()))
     }
 }
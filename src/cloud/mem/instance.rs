@@ -1,11 +1,20 @@
+use crate::cloud::DnsAddress;
+use crate::cloud::DnsOptions;
 use crate::cloud::Instance;
 use crate::cloud::InstanceRunningState;
+use crate::cloud::InstanceStateCode;
+use crate::cloud::InstanceStatus;
 use crate::cloud::InstanceType;
+use crate::cloud::Selector;
 use crate::dns::DnsTarget;
+use crate::future::BoxFuture;
 use failure::Error;
+use futures::future;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::rc::Rc;
 
 #[derive(Clone)]
@@ -13,20 +22,51 @@ pub struct MemInstance {
     id: String,
     name: String,
     fqdn: Option<String>,
+    private_fqdn: Option<String>,
+    vpc_id: String,
     state: Rc<RefCell<MemInstanceState>>,
 }
 
 struct MemInstanceState {
+    tags: HashMap<String, String>,
     instance_type: InstanceType,
     ip_addr: Ipv4Addr,
+    ipv6_addr: Option<Ipv6Addr>,
+    // an error stands for invalid DNS tags
+    dns_options: Result<DnsOptions, String>,
     is_running: bool,
 }
 
+impl MemInstanceState {
+    // There are no DNS names or Elastic IPs in memory, so only the choice
+    // between public and private addresses is honoured
+    fn addrs(&self) -> Vec<DnsTarget> {
+        if let Ok(DnsOptions {
+            address: DnsAddress::Private,
+            ..
+        }) = self.dns_options
+        {
+            return self.private_addrs();
+        }
+        let mut addrs = vec![DnsTarget::A(self.ip_addr)];
+        addrs.extend(self.ipv6_addr.map(DnsTarget::Aaaa));
+        addrs
+    }
+
+    // Distinct from, but derived from, the public address
+    fn private_addrs(&self) -> Vec<DnsTarget> {
+        let [_, b, c, d] = self.ip_addr.octets();
+        vec![DnsTarget::A(Ipv4Addr::new(10, b, c, d))]
+    }
+}
+
 impl MemInstance {
     pub(super) fn new(
         id: String,
         name: String,
         fqdn: Option<String>,
+        private_fqdn: Option<String>,
+        vpc_id: String,
         instance_type: InstanceType,
         ip_addr: Ipv4Addr,
     ) -> Result<MemInstance, Error> {
@@ -34,20 +74,55 @@ impl MemInstance {
             id,
             name,
             fqdn,
+            private_fqdn,
+            vpc_id,
             state: Rc::new(RefCell::new(MemInstanceState {
+                tags: HashMap::new(),
                 instance_type,
                 ip_addr,
+                ipv6_addr: None,
+                dns_options: Ok(DnsOptions::default()),
                 is_running: false,
             })),
         })
     }
 
+    pub fn add_tag(&self, key: &str, value: &str) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.tags.insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    pub(super) fn matches(&self, selector: &Selector) -> bool {
+        let state = self.state.borrow();
+        selector.matches("i-", &self.id, &self.name, &state.tags)
+    }
+
+    pub fn assign_ipv6_addr(&self, ipv6_addr: Ipv6Addr) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.ipv6_addr = Some(ipv6_addr);
+        Ok(())
+    }
+
+    pub fn set_dns_options(&self, dns_options: DnsOptions) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.dns_options = Ok(dns_options);
+        Ok(())
+    }
+
+    pub fn set_invalid_dns_options(&self, error: &str) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        state.dns_options = Err(error.to_owned());
+        Ok(())
+    }
+
     pub fn try_get_running_state(&self) -> Result<Option<InstanceRunningState>, Error> {
         let state = self.state.borrow();
         if state.is_running {
             Ok(Some(InstanceRunningState {
                 instance_type: state.instance_type.clone(),
-                addr: DnsTarget::A(state.ip_addr),
+                addrs: state.addrs(),
+                private_addrs: state.private_addrs(),
             }))
         } else {
             Ok(None)
@@ -74,31 +149,82 @@ impl Instance for MemInstance {
         self.fqdn.as_ref().map(String::as_ref)
     }
 
-    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> Result<(), Error> {
+    fn private_fqdn(&self) -> Option<&str> {
+        self.private_fqdn.as_deref()
+    }
+
+    fn vpc_id(&self) -> Option<&str> {
+        Some(&self.vpc_id)
+    }
+
+    fn dns_options(&self) -> Result<DnsOptions, Error> {
+        match self.state.borrow().dns_options {
+            Ok(dns_options) => Ok(dns_options),
+            Err(ref error) => bail!("{}", error),
+        }
+    }
+
+    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> BoxFuture<()> {
         let mut state = self.state.borrow_mut();
         if state.instance_type == *instance_type {
-            Ok(())
+            Box::new(future::ok(()))
         } else if !state.is_running {
             state.instance_type = instance_type.clone();
-            Ok(())
+            Box::new(future::ok(()))
         } else {
-            Err(format_err!("instance must be stopped to change its type"))
+            Box::new(future::err(format_err!(
+                "instance must be stopped to change its type"
+            )))
         }
     }
 
-    fn ensure_running(&self) -> Result<InstanceRunningState, Error> {
+    fn poll_running(&self) -> BoxFuture<Option<InstanceRunningState>> {
         let mut state = self.state.borrow_mut();
         let running_state = InstanceRunningState {
             instance_type: state.instance_type.clone(),
-            addr: DnsTarget::A(state.ip_addr),
+            addrs: state.addrs(),
+            private_addrs: state.private_addrs(),
         };
         state.is_running = true;
-        Ok(running_state)
+        Box::new(future::ok(Some(running_state)))
     }
 
-    fn ensure_stopped(&self) -> Result<(), Error> {
+    fn poll_stopped(&self) -> BoxFuture<Option<()>> {
         let mut state = self.state.borrow_mut();
         state.is_running = false;
-        Ok(())
+        Box::new(future::ok(Some(())))
+    }
+
+    // State changes are immediate in memory
+    fn request_running(&self) -> BoxFuture<()> {
+        self.state.borrow_mut().is_running = true;
+        Box::new(future::ok(()))
+    }
+
+    fn request_stopped(&self) -> BoxFuture<()> {
+        self.state.borrow_mut().is_running = false;
+        Box::new(future::ok(()))
+    }
+
+    fn get_status(&self) -> BoxFuture<InstanceStatus> {
+        let state = self.state.borrow();
+        Box::new(future::ok(InstanceStatus {
+            state: if state.is_running {
+                InstanceStateCode::Running
+            } else {
+                InstanceStateCode::Stopped
+            },
+            instance_type: state.instance_type.clone(),
+            addrs: if state.is_running {
+                state.addrs()
+            } else {
+                Vec::new()
+            },
+            private_addrs: if state.is_running {
+                state.private_addrs()
+            } else {
+                Vec::new()
+            },
+        }))
     }
 }
@@ -7,17 +7,21 @@ use crate::cloud::Cloud;
 use crate::cloud::Firewall;
 use crate::cloud::Instance;
 use crate::cloud::InstanceType;
+use crate::cloud::Selector;
+use crate::future::BoxFuture;
 use failure::Error;
+use futures::future;
 use ipnet::Ipv4AddrRange;
 use ipnet::Ipv4Net;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::net::Ipv4Addr;
 use std::ops::Range;
 use std::rc::Rc;
 use std::u32;
 
+pub const MEM_VPC_ID: &str = "vpc-1";
+
 pub struct MemCloud {
     state: Rc<RefCell<MemCloudState>>,
 }
@@ -43,7 +47,8 @@ impl MemCloud {
 
     pub fn create_firewall(&self, name: &str) -> Result<MemFirewall, Error> {
         let mut state = self.state.borrow_mut();
-        let value = MemFirewall::new(state.fresh_id()?, name.to_owned())?;
+        let id = format!("sg-{}", state.fresh_id()?);
+        let value = MemFirewall::new(id, name.to_owned())?;
         state.firewalls.insert(value.id().to_owned(), value.clone());
         Ok(value)
     }
@@ -53,12 +58,26 @@ impl MemCloud {
         name: &str,
         fqdn: Option<&str>,
         instance_type: &InstanceType,
+    ) -> Result<MemInstance, Error> {
+        self.create_instance_with_private_fqdn(name, fqdn, None, instance_type)
+    }
+
+    // All instances are in the same VPC
+    pub fn create_instance_with_private_fqdn(
+        &self,
+        name: &str,
+        fqdn: Option<&str>,
+        private_fqdn: Option<&str>,
+        instance_type: &InstanceType,
     ) -> Result<MemInstance, Error> {
         let mut state = self.state.borrow_mut();
+        let id = format!("i-{}", state.fresh_id()?);
         let value = MemInstance::new(
-            state.fresh_id()?,
+            id,
             name.to_owned(),
             fqdn.map(|x| x.to_owned()),
+            private_fqdn.map(|x| x.to_owned()),
+            MEM_VPC_ID.to_owned(),
             instance_type.clone(),
             state.fresh_ip_addr()?,
         )?;
@@ -84,35 +103,25 @@ impl Cloud for MemCloud {
     type Firewall = MemFirewall;
     type Instance = MemInstance;
 
-    fn list_firewalls<'a, N, S>(&self, names: N) -> Result<Vec<MemFirewall>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a,
-    {
+    fn list_firewalls(&self, selector: &Selector) -> BoxFuture<Vec<MemFirewall>> {
         let state = self.state.borrow();
-        let names: HashSet<&str> = names.into_iter().map(AsRef::as_ref).collect();
         let xs = state
             .firewalls
             .values()
-            .filter(|x| names.contains(x.name()))
+            .filter(|x| x.matches(selector))
             .cloned()
             .collect();
-        Ok(xs)
+        Box::new(future::ok(xs))
     }
 
-    fn list_instances<'a, N, S>(&self, names: N) -> Result<Vec<MemInstance>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a,
-    {
+    fn list_instances(&self, selector: &Selector) -> BoxFuture<Vec<MemInstance>> {
         let state = self.state.borrow();
-        let names: HashSet<&str> = names.into_iter().map(AsRef::as_ref).collect();
         let xs = state
             .instances
             .values()
-            .filter(|x| names.contains(x.name()))
+            .filter(|x| x.matches(selector))
             .cloned()
             .collect();
-        Ok(xs)
+        Box::new(future::ok(xs))
     }
 }
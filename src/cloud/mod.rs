@@ -1,51 +1,81 @@
 pub mod aws;
 #[cfg(test)]
 pub mod mem;
+mod selector;
+
+pub use crate::cloud::selector::Selector;
 
 use crate::dns::DnsTarget;
+use crate::dns::DEFAULT_TTL;
+use crate::dns::MAX_TTL;
+use crate::future::BoxFuture;
+use crate::iprules::IpEgressRule;
 use crate::iprules::IpIngressRule;
+use crate::iprules::IpRuleMeta;
 use failure::Error;
-use std::collections::HashSet;
+use serde::Serialize;
+use serde::Serializer;
+use std::collections::HashMap;
 use std::fmt;
 use std::str;
 
 pub trait Cloud {
-    type Firewall: Firewall;
-    type Instance: Instance;
+    type Firewall: Firewall + 'static;
+    type Instance: Instance + 'static;
 
-    fn list_firewalls<'a, N, S>(&self, names: N) -> Result<Vec<Self::Firewall>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a;
-    fn list_instances<'a, N, S>(&self, names: N) -> Result<Vec<Self::Instance>, Error>
-    where
-        N: IntoIterator<Item = &'a S>,
-        S: AsRef<str> + 'a;
+    fn list_firewalls(&self, selector: &Selector) -> BoxFuture<Vec<Self::Firewall>>;
+    fn list_instances(&self, selector: &Selector) -> BoxFuture<Vec<Self::Instance>>;
 }
 
 pub trait Firewall: fmt::Debug {
     fn id(&self) -> &str;
     fn name(&self) -> &str;
-    fn list_ingress_rules(&self) -> Result<HashSet<IpIngressRule>, Error>;
-    fn add_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    // rules that were not created by drawbridge have no metadata
+    fn describe_ingress_rules(&self) -> BoxFuture<HashMap<IpIngressRule, Option<IpRuleMeta>>>;
+    fn add_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpIngressRule>;
+    fn update_ingress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>;
-    fn remove_ingress_rules<'a, R>(&self, rules: R) -> Result<(), Error>
+    fn remove_ingress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
     where
         R: IntoIterator<Item = &'a IpIngressRule>;
+    fn describe_egress_rules(&self) -> BoxFuture<HashMap<IpEgressRule, Option<IpRuleMeta>>>;
+    fn add_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>;
+    fn update_egress_rules<'a, R>(&self, rules: R, meta: &IpRuleMeta) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>;
+    fn remove_egress_rules<'a, R>(&self, rules: R) -> BoxFuture<()>
+    where
+        R: IntoIterator<Item = &'a IpEgressRule>;
 }
 
 pub trait Instance: fmt::Debug {
     fn id(&self) -> &str;
     fn name(&self) -> &str;
+    // bound to the public addresses in a public DNS zone
     fn fqdn(&self) -> Option<&str>;
+    // bound to the private addresses in a private DNS zone associated with the VPC
+    fn private_fqdn(&self) -> Option<&str>;
+    fn vpc_id(&self) -> Option<&str>;
+    // fails for an instance whose DNS tags are invalid, which fails only that instance
+    fn dns_options(&self) -> Result<DnsOptions, Error>;
     // requires the instance to be stopped
-    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> Result<(), Error>;
-    fn ensure_running(&self) -> Result<InstanceRunningState, Error>;
-    fn ensure_stopped(&self) -> Result<(), Error>;
+    fn try_ensure_instance_type(&self, instance_type: &InstanceType) -> BoxFuture<()>;
+    // requests the state change if needed, returning Some once the state is reached.
+    // Called repeatedly until then, so that many instances may be polled together.
+    fn poll_running(&self) -> BoxFuture<Option<InstanceRunningState>>;
+    fn poll_stopped(&self) -> BoxFuture<Option<()>>;
+    // requests the state change if needed, without waiting for it
+    fn request_running(&self) -> BoxFuture<()>;
+    fn request_stopped(&self) -> BoxFuture<()>;
+    fn get_status(&self) -> BoxFuture<InstanceStatus>;
 }
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+#[derive(Clone, Hash, PartialEq, Eq, Serialize)]
 pub struct InstanceType(String);
 
 impl InstanceType {
@@ -69,5 +99,165 @@ impl fmt::Debug for InstanceType {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InstanceRunningState {
     pub instance_type: InstanceType,
-    pub addr: DnsTarget,
+    pub addrs: Vec<DnsTarget>,
+    pub private_addrs: Vec<DnsTarget>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstanceStateCode {
+    Pending,
+    Running,
+    Terminating, // called "shutting-down" by AWS
+    Terminated,
+    Stopping,
+    Stopped,
+    Unknown(u8),
+}
+
+impl fmt::Display for InstanceStateCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            InstanceStateCode::Pending => write!(f, "pending"),
+            InstanceStateCode::Running => write!(f, "running"),
+            InstanceStateCode::Terminating => write!(f, "shutting-down"),
+            InstanceStateCode::Terminated => write!(f, "terminated"),
+            InstanceStateCode::Stopping => write!(f, "stopping"),
+            InstanceStateCode::Stopped => write!(f, "stopped"),
+            InstanceStateCode::Unknown(x) => write!(f, "unknown ({})", x),
+        }
+    }
+}
+
+// Reported as the name shown by AWS, e.g. "running"
+impl Serialize for InstanceStateCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstanceStatus {
+    pub state: InstanceStateCode,
+    pub instance_type: InstanceType,
+    // empty unless the instance is running
+    pub addrs: Vec<DnsTarget>,
+    pub private_addrs: Vec<DnsTarget>,
+}
+
+// How an instance's hostnames are bound, set by its DnsTtl, DnsRecordType
+// and DnsTarget tags. The record type and address apply to the public
+// hostname, as the private hostname is always bound to the private address.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DnsOptions {
+    pub ttl: Option<u64>,
+    // if not set, a CNAME is used unless the instance has IPv6 addresses
+    pub record_type: Option<DnsRecordType>,
+    pub address: DnsAddress,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DnsRecordType {
+    // the addresses, including any IPv6 addresses as AAAA records
+    A,
+    // the DNS name that AWS assigns to the address
+    Cname,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DnsAddress {
+    #[default]
+    Public,
+    Private,
+    // the public address, which must be an Elastic IP so that it survives a restart
+    ElasticIp,
+}
+
+impl DnsOptions {
+    pub fn from_tags(
+        ttl: Option<&str>,
+        record_type: Option<&str>,
+        address: Option<&str>,
+    ) -> Result<DnsOptions, Error> {
+        let ttl = match ttl {
+            Some(ttl) => {
+                let secs: u64 = ttl
+                    .parse()
+                    .map_err(|_e| format_err!("DnsTtl is not a number of seconds: {}", ttl))?;
+                if secs > MAX_TTL {
+                    bail!("DnsTtl out of range, as the most is {}: {}", MAX_TTL, ttl);
+                }
+                Some(secs)
+            }
+            None => None,
+        };
+        let record_type = match record_type {
+            Some("A") => Some(DnsRecordType::A),
+            Some("CNAME") => Some(DnsRecordType::Cname),
+            // Route 53 alias records may target e.g. load balancers, but not instances
+            Some("ALIAS") => bail!("DnsRecordType ALIAS is not supported for instances"),
+            Some(x) => bail!("DnsRecordType is not one of A, CNAME or ALIAS: {}", x),
+            None => None,
+        };
+        let address = match address {
+            Some("public") | None => DnsAddress::Public,
+            Some("private") => DnsAddress::Private,
+            Some("elastic-ip") => DnsAddress::ElasticIp,
+            Some(x) => bail!(
+                "DnsTarget is not one of public, private or elastic-ip: {}",
+                x
+            ),
+        };
+        Ok(DnsOptions {
+            ttl,
+            record_type,
+            address,
+        })
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl.unwrap_or(DEFAULT_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_instance_state_code() {
+        let json = serde_json::to_string(&[
+            InstanceStateCode::Running,
+            InstanceStateCode::Terminating,
+            InstanceStateCode::Unknown(7),
+        ])
+        .unwrap();
+        assert_eq!(r#"["running","shutting-down","unknown (7)"]"#, json);
+    }
+
+    #[test]
+    fn test_dns_options_from_tags() {
+        assert_eq!(
+            DnsOptions::default(),
+            DnsOptions::from_tags(None, None, None).unwrap()
+        );
+        assert_eq!(DEFAULT_TTL, DnsOptions::default().ttl());
+
+        let options = DnsOptions::from_tags(Some("300"), Some("A"), Some("elastic-ip")).unwrap();
+        assert_eq!(300, options.ttl());
+        assert_eq!(Some(DnsRecordType::A), options.record_type);
+        assert_eq!(DnsAddress::ElasticIp, options.address);
+
+        assert!(DnsOptions::from_tags(Some("1m"), None, None).is_err());
+        let options = DnsOptions::from_tags(Some("2147483647"), None, None).unwrap();
+        assert_eq!(2_147_483_647, options.ttl());
+        let err = DnsOptions::from_tags(Some("2147483648"), None, None).unwrap_err();
+        assert_eq!(
+            "DnsTtl out of range, as the most is 2147483647: 2147483648",
+            err.to_string()
+        );
+        assert!(DnsOptions::from_tags(Some("9223372036854775808"), None, None).is_err());
+        assert!(DnsOptions::from_tags(None, Some("ALIAS"), None).is_err());
+        assert!(DnsOptions::from_tags(None, Some("MX"), None).is_err());
+        assert!(DnsOptions::from_tags(None, None, Some("ipv6")).is_err());
+    }
 }
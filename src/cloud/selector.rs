@@ -0,0 +1,188 @@
+#[cfg(test)]
+use std::collections::HashMap;
+
+// Which firewalls and instances a command applies to. A resource is selected if
+// it matches any of the IDs or names, and all of the tags. Names and tag values
+// may contain the wildcards * and ?, as in EC2 filters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    pub ids: Vec<String>,
+    pub names: Vec<String>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl Selector {
+    // Terms that look like resource IDs, e.g. i-0123abcd or sg-0123abcd, are
+    // treated as IDs, and all other terms as names
+    pub fn new<I, S>(terms: I, tags: Vec<(String, String)>) -> Selector
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let (ids, names) = terms
+            .into_iter()
+            .map(Into::into)
+            .partition(|term: &String| is_id(term));
+        Selector { ids, names, tags }
+    }
+
+    // The IDs of resources of one kind, e.g. "sg-" for security groups
+    pub fn ids_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.ids
+            .iter()
+            .map(String::as_str)
+            .filter(move |id| id.starts_with(prefix))
+    }
+
+    // If only tags were given, all resources with those tags are selected
+    pub fn is_tags_only(&self) -> bool {
+        self.ids.is_empty() && self.names.is_empty()
+    }
+
+    // The IDs and names that match none of the given (id, name) pairs
+    pub fn unmatched_terms<'a>(&'a self, resources: &[(&str, &str)]) -> Vec<&'a str> {
+        let unmatched_ids = self
+            .ids
+            .iter()
+            .filter(|x| !resources.iter().any(|&(id, _)| id == x.as_str()));
+        let unmatched_names = self
+            .names
+            .iter()
+            .filter(|x| !resources.iter().any(|&(_, name)| glob_matches(x, name)));
+        unmatched_ids
+            .chain(unmatched_names)
+            .map(String::as_str)
+            .collect()
+    }
+
+    // The names without wildcards that match more than one of the given (id, name) pairs
+    pub fn duplicate_names<'a>(&'a self, resources: &[(&str, &str)]) -> Vec<&'a str> {
+        self.names
+            .iter()
+            .filter(|x| !x.contains(&['*', '?'][..]))
+            .filter(|x| {
+                resources
+                    .iter()
+                    .filter(|&&(_, name)| name == x.as_str())
+                    .count()
+                    > 1
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    // EC2 does this matching itself, so this is only needed in memory
+    #[cfg(test)]
+    pub fn matches(
+        &self,
+        id_prefix: &str,
+        id: &str,
+        name: &str,
+        tags: &HashMap<String, String>,
+    ) -> bool {
+        let matches_terms = self.is_tags_only()
+            || self.ids_with_prefix(id_prefix).any(|x| x == id)
+            || self.names.iter().any(|x| glob_matches(x, name));
+        let matches_tags = self.tags.iter().all(|(k, v)| match tags.get(k) {
+            Some(value) => glob_matches(v, value),
+            None => false,
+        });
+        matches_terms && matches_tags
+    }
+}
+
+fn is_id(term: &str) -> bool {
+    let suffix = match term.find('-') {
+        Some(i) if &term[..i] == "i" || &term[..i] == "sg" => &term[i + 1..],
+        _ => return false,
+    };
+    !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// * matches any sequence of characters, and ? matches any single character
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // where to resume after the most recent *, if a later match fails
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_i)) => {
+                    backtrack = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_classifies_terms() {
+        let selector = Selector::new(vec!["web-*", "i-0123abcd", "sg-42", "i-web"], vec![]);
+        assert_eq!(vec!["i-0123abcd", "sg-42"], selector.ids);
+        assert_eq!(vec!["web-*", "i-web"], selector.names);
+        assert_eq!(
+            vec!["sg-42"],
+            selector.ids_with_prefix("sg-").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("web", "web"));
+        assert!(!glob_matches("web", "web-1"));
+        assert!(glob_matches("web-*", "web-1"));
+        assert!(glob_matches("web-*", "web-"));
+        assert!(!glob_matches("web-*", "db-1"));
+        assert!(glob_matches("*-1", "web-1"));
+        assert!(glob_matches("w?b-*-?", "web-eu-1"));
+        assert!(glob_matches("*a*b*", "xaybz"));
+        assert!(!glob_matches("*a*b", "xaybz"));
+    }
+
+    #[test]
+    fn test_unmatched_terms_and_duplicate_names() {
+        let selector = Selector::new(vec!["web-*", "db", "cache", "i-1", "i-2"], vec![]);
+        let resources = [("i-1", "web-1"), ("i-3", "db"), ("i-4", "db")];
+        assert_eq!(vec!["i-2", "cache"], selector.unmatched_terms(&resources));
+        assert_eq!(vec!["db"], selector.duplicate_names(&resources));
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut tags = HashMap::new();
+        tags.insert("env".to_owned(), "staging".to_owned());
+
+        let selector = Selector::new(vec!["web-*"], vec![("env".to_owned(), "stag*".to_owned())]);
+        assert!(selector.matches("i-", "i-1", "web-1", &tags));
+        assert!(!selector.matches("i-", "i-1", "db-1", &tags));
+        assert!(!selector.matches("i-", "i-1", "web-1", &HashMap::new()));
+
+        let selector = Selector::new(vec!["i-1", "sg-1"], vec![]);
+        assert!(selector.matches("i-", "i-1", "web-1", &tags));
+        assert!(!selector.matches("sg-", "sg-2", "web-1", &tags));
+
+        let selector = Selector::new(
+            Vec::<String>::new(),
+            vec![("env".to_owned(), "prod".to_owned())],
+        );
+        assert!(!selector.matches("i-", "i-1", "web-1", &tags));
+    }
+}
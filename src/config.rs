@@ -0,0 +1,168 @@
+use failure::Error;
+use failure::ResultExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+const BUILTIN_PROTOCOL_ALIASES: &[(&str, &str)] = &[
+    ("ssh", "22/tcp"),
+    ("mosh", "60000-61000/udp"),
+    ("http", "80/tcp"),
+    ("https", "443/tcp"),
+    ("rdp", "3389/tcp"),
+    ("postgres", "5432/tcp"),
+    ("jupyter", "8888/tcp"),
+    ("wireguard", "51820/udp"),
+    ("ping", "echo-request/icmp,echo-request/icmpv6"),
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // Protocol aliases, e.g. dev = "ssh,8080-8090/tcp", which take precedence over the built-ins
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    // AWS profiles, as for --profile and --dns-profile, which take precedence
+    profile: Option<String>,
+    dns_profile: Option<String>,
+    // Named targets for the up and down commands
+    #[serde(default)]
+    targets: HashMap<String, Target>,
+}
+
+// The resources and defaults bundled under a name, e.g. [targets.devbox]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    // names or IDs, which may contain * and ?
+    #[serde(default)]
+    pub instances: Vec<String>,
+    #[serde(default)]
+    pub firewalls: Vec<String>,
+    // protocols or aliases, and source addresses (or self), as for open
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<String>,
+    pub instance_type: Option<String>,
+    pub region: Option<String>,
+    pub profile: Option<String>,
+}
+
+impl Config {
+    // Without an explicit path, the default config file is optional
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        match path {
+            Some(path) => Config::load_from(path),
+            None => match default_path() {
+                Some(ref path) if path.exists() => Config::load_from(path),
+                _ => Ok(Config::default()),
+            },
+        }
+    }
+
+    fn load_from(path: &Path) -> Result<Config, Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|_e| format!("failed to read config file: {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|_e| format!("failed to parse config file: {}", path.display()))?;
+        Ok(config)
+    }
+
+    // A comma-separated list of protocols and aliases
+    pub fn protocol_alias(&self, name: &str) -> Option<&str> {
+        self.aliases.get(name).map(String::as_str).or_else(|| {
+            BUILTIN_PROTOCOL_ALIASES
+                .iter()
+                .find(|&&(k, _)| k == name)
+                .map(|&(_, v)| v)
+        })
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn dns_profile(&self) -> Option<&str> {
+        self.dns_profile.as_deref()
+    }
+
+    pub fn target(&self, name: &str) -> Result<&Target, Error> {
+        match self.targets.get(name) {
+            Some(target) => Ok(target),
+            None => bail!("no such target in the config file: {}", name),
+        }
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("drawbridge").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_aliases() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            ssh = "2222/tcp"
+            dev = "ssh,8080-8090/tcp"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("2222/tcp"), config.protocol_alias("ssh"));
+        assert_eq!(Some("ssh,8080-8090/tcp"), config.protocol_alias("dev"));
+        assert_eq!(Some("80/tcp"), config.protocol_alias("http"));
+        assert_eq!(None, config.protocol_alias("22/tcp"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = toml::from_str("profile = \"dev\"\ndns_profile = \"dns\"\n").unwrap();
+        assert_eq!(Some("dev"), config.profile());
+        assert_eq!(Some("dns"), config.dns_profile());
+    }
+
+    #[test]
+    fn test_targets() {
+        let config: Config = toml::from_str(
+            r#"
+            [targets.devbox]
+            instances = ["devbox"]
+            firewalls = ["devbox-*"]
+            protocols = ["ssh", "mosh"]
+            sources = ["self"]
+            instance_type = "t3.large"
+            region = "eu-west-1"
+            "#,
+        )
+        .unwrap();
+
+        let target = config.target("devbox").unwrap();
+        assert_eq!(vec!["devbox"], target.instances);
+        assert_eq!(vec!["devbox-*"], target.firewalls);
+        assert_eq!(vec!["ssh", "mosh"], target.protocols);
+        assert_eq!(vec!["self"], target.sources);
+        assert_eq!(Some("t3.large"), target.instance_type.as_deref());
+        assert_eq!(Some("eu-west-1"), target.region.as_deref());
+        assert_eq!(None, target.profile);
+        assert!(config.target("other").is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[alias]\nssh = \"22/tcp\"").is_err());
+        assert!(toml::from_str::<Config>("[targets.x]\ninstance = [\"x\"]").is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use crate::retry::retry;
+use failure::Error;
+use failure::ResultExt;
+use futures::future;
+use futures::future::FutureResult;
+use rusoto_core::AwsCredentials;
+use rusoto_core::CredentialsError;
+use rusoto_core::DefaultCredentialsProvider;
+use rusoto_core::HttpClient;
+use rusoto_core::ProvideAwsCredentials;
+use rusoto_core::Region;
+use rusoto_sts::AssumeRoleRequest;
+use rusoto_sts::Sts;
+use rusoto_sts::StsClient;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio_core::reactor::Core;
+
+// Guards against a profile that is, through other profiles, its own source
+const MAX_CHAIN_LENGTH: usize = 8;
+
+// Credentials resolved once, up front, as resolving them may prompt for an MFA code.
+// Assumed roles last an hour by default, which is longer than any command.
+#[derive(Clone)]
+pub struct ProfileCredentials(AwsCredentials);
+
+impl ProvideAwsCredentials for ProfileCredentials {
+    type Future = FutureResult<AwsCredentials, CredentialsError>;
+
+    fn credentials(&self) -> Self::Future {
+        future::ok(self.0.clone())
+    }
+}
+
+// Resolves the credentials of a profile in ~/.aws/config and ~/.aws/credentials,
+// assuming any roles on the way, as the AWS CLI does
+pub fn load_profile(name: &str, core: &mut Core) -> Result<ProfileCredentials, Error> {
+    let profiles = Profiles::load()?;
+    let source = profiles.source(name)?;
+    let credentials = resolve(&source, core)
+        .with_context(|_e| format!("failed to get credentials for profile: {}", name))?;
+    Ok(ProfileCredentials(credentials))
+}
+
+// Resolves the credentials of each distinct profile once, so that the same profile
+// given twice prompts for an MFA code, and assumes its role, only once
+pub fn load_profiles(
+    names: &[Option<&str>],
+    core: &mut Core,
+) -> Result<Vec<Option<ProfileCredentials>>, Error> {
+    load_profiles_with(names, |name| load_profile(name, core))
+}
+
+fn load_profiles_with<F>(
+    names: &[Option<&str>],
+    mut load: F,
+) -> Result<Vec<Option<ProfileCredentials>>, Error>
+where
+    F: FnMut(&str) -> Result<ProfileCredentials, Error>,
+{
+    let mut loaded: HashMap<&str, ProfileCredentials> = HashMap::new();
+    let mut credentials = Vec::new();
+    for name in names {
+        credentials.push(match *name {
+            Some(name) => {
+                if !loaded.contains_key(name) {
+                    loaded.insert(name, load(name)?);
+                }
+                Some(loaded[name].clone())
+            }
+            None => None,
+        });
+    }
+    Ok(credentials)
+}
+
+// Where the credentials of a profile come from
+#[derive(Debug, PartialEq, Eq)]
+enum Source {
+    Keys {
+        key: String,
+        secret: String,
+        token: Option<String>,
+    },
+    // the environment, or instance or container metadata
+    DefaultChain,
+    AssumeRole {
+        role_arn: String,
+        source: Box<Source>,
+        mfa_serial: Option<String>,
+        external_id: Option<String>,
+    },
+}
+
+// The sections of the config and credentials files, by profile name
+#[derive(Debug, Default)]
+struct Profiles {
+    config: HashMap<String, HashMap<String, String>>,
+    credentials: HashMap<String, HashMap<String, String>>,
+}
+
+impl Profiles {
+    fn load() -> Result<Profiles, Error> {
+        let config = match aws_path("AWS_CONFIG_FILE", "config") {
+            Some(ref path) if path.exists() => {
+                let content = fs::read_to_string(path)
+                    .with_context(|_e| format!("failed to read AWS config: {}", path.display()))?;
+                parse_config(&content)
+            }
+            _ => HashMap::new(),
+        };
+        let credentials = match aws_path("AWS_SHARED_CREDENTIALS_FILE", "credentials") {
+            Some(ref path) if path.exists() => {
+                let content = fs::read_to_string(path).with_context(|_e| {
+                    format!("failed to read AWS credentials: {}", path.display())
+                })?;
+                parse_ini(&content)
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Profiles {
+            config,
+            credentials,
+        })
+    }
+
+    fn source(&self, name: &str) -> Result<Source, Error> {
+        self.source_at_depth(name, 0)
+    }
+
+    fn source_at_depth(&self, name: &str, depth: usize) -> Result<Source, Error> {
+        if depth >= MAX_CHAIN_LENGTH {
+            bail!("too many source profiles, which may be circular: {}", name);
+        }
+        let empty = HashMap::new();
+        let config = self.config.get(name).unwrap_or(&empty);
+        let credentials = self.credentials.get(name).unwrap_or(&empty);
+        if !self.config.contains_key(name) && !self.credentials.contains_key(name) {
+            bail!("could not find AWS profile: {}", name);
+        }
+        // Keys in the credentials file take precedence, as in the AWS CLI
+        let setting = |key: &str| credentials.get(key).or_else(|| config.get(key)).cloned();
+
+        if let Some(role_arn) = config.get("role_arn") {
+            let source = match (
+                config.get("source_profile"),
+                config.get("credential_source"),
+            ) {
+                // A profile may hold its own keys as well as assume a role with them
+                (Some(source_profile), _) if source_profile == name => Source::Keys {
+                    key: setting("aws_access_key_id")
+                        .ok_or_else(|| format_err!("expected keys in AWS profile: {}", name))?,
+                    secret: setting("aws_secret_access_key")
+                        .ok_or_else(|| format_err!("expected keys in AWS profile: {}", name))?,
+                    token: setting("aws_session_token"),
+                },
+                (Some(source_profile), _) => self.source_at_depth(source_profile, depth + 1)?,
+                (None, Some(_)) => Source::DefaultChain,
+                (None, None) => bail!(
+                    "expected source_profile or credential_source in AWS profile: {}",
+                    name
+                ),
+            };
+            return Ok(Source::AssumeRole {
+                role_arn: role_arn.clone(),
+                source: Box::new(source),
+                mfa_serial: config.get("mfa_serial").cloned(),
+                external_id: config.get("external_id").cloned(),
+            });
+        }
+
+        match (
+            setting("aws_access_key_id"),
+            setting("aws_secret_access_key"),
+        ) {
+            (Some(key), Some(secret)) => Ok(Source::Keys {
+                key,
+                secret,
+                token: setting("aws_session_token"),
+            }),
+            _ => bail!("expected keys or role_arn in AWS profile: {}", name),
+        }
+    }
+}
+
+fn aws_path(env_var: &str, file_name: &str) -> Option<PathBuf> {
+    match env::var_os(env_var) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(
+            PathBuf::from(env::var_os("HOME")?)
+                .join(".aws")
+                .join(file_name),
+        ),
+    }
+}
+
+// Sections other than the default are named e.g. [profile dev]
+fn parse_config(content: &str) -> HashMap<String, HashMap<String, String>> {
+    parse_ini(content)
+        .into_iter()
+        .map(|(k, v)| (k.trim_start_matches("profile ").trim().to_owned(), v))
+        .collect()
+}
+
+// Only as much INI as the AWS files use: [sections] of key = value, and # or ; comments
+fn parse_ini(content: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut section = None;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_owned();
+            sections.entry(name.clone()).or_default();
+            section = Some(name);
+        } else if let (Some(ref name), Some(i)) = (&section, line.find('=')) {
+            let key = line[..i].trim().to_owned();
+            let value = line[i + 1..].trim().to_owned();
+            sections.entry(name.clone()).or_default().insert(key, value);
+        }
+    }
+    sections
+}
+
+fn resolve(source: &Source, core: &mut Core) -> Result<AwsCredentials, Error> {
+    match *source {
+        Source::Keys {
+            ref key,
+            ref secret,
+            ref token,
+        } => Ok(AwsCredentials::new(
+            key.as_str(),
+            secret.as_str(),
+            token.clone(),
+            None,
+        )),
+        Source::DefaultChain => {
+            let provider = DefaultCredentialsProvider::new(&core.handle())?;
+            let credentials = core
+                .run(provider.credentials())
+                .context("failed to get default AWS credentials")?;
+            Ok(credentials)
+        }
+        Source::AssumeRole {
+            ref role_arn,
+            ref source,
+            ref mfa_serial,
+            ref external_id,
+        } => {
+            let source_credentials = resolve(source, core)?;
+            let token_code = match *mfa_serial {
+                Some(ref mfa_serial) => Some(prompt_mfa_code(mfa_serial)?),
+                None => None,
+            };
+            assume_role(
+                core,
+                &source_credentials,
+                role_arn,
+                mfa_serial.as_ref().zip(token_code.as_ref()),
+                external_id.as_deref(),
+            )
+            .with_context(|_e| format!("failed to assume role: {}", role_arn))
+            .map_err(Error::from)
+        }
+    }
+}
+
+// Progress is printed to stderr, so the prompt is too
+fn prompt_mfa_code(mfa_serial: &str) -> Result<String, Error> {
+    eprint!("Enter MFA code for {}: ", mfa_serial);
+    io::stderr().flush()?;
+    let mut code = String::new();
+    io::stdin()
+        .read_line(&mut code)
+        .context("failed to read MFA code")?;
+    Ok(code.trim().to_owned())
+}
+
+// The session is named after the time, so that it may be found in CloudTrail
+fn assume_role(
+    core: &mut Core,
+    credentials: &AwsCredentials,
+    role_arn: &str,
+    mfa: Option<(&String, &String)>,
+    external_id: Option<&str>,
+) -> Result<AwsCredentials, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the epoch");
+    let handle = core.handle();
+    let sts = StsClient::new(
+        HttpClient::new(&handle)?,
+        ProfileCredentials(credentials.clone()),
+        Region::UsEast1,
+    );
+    let req = AssumeRoleRequest {
+        role_arn: role_arn.to_owned(),
+        role_session_name: format!("drawbridge-{}", now.as_secs()),
+        serial_number: mfa.map(|(serial_number, _)| serial_number.clone()),
+        token_code: mfa.map(|(_, token_code)| token_code.clone()),
+        external_id: external_id.map(str::to_owned),
+        ..Default::default()
+    };
+    let resp = core.run(retry(&handle, move || sts.assume_role(&req)))?;
+    let credentials = resp
+        .credentials
+        .ok_or_else(|| format_err!("expected credentials in response"))?;
+    Ok(AwsCredentials::new(
+        credentials.access_key_id,
+        credentials.secret_access_key,
+        Some(credentials.session_token),
+        None,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(config: &str, credentials: &str) -> Profiles {
+        Profiles {
+            config: parse_config(config),
+            credentials: parse_ini(credentials),
+        }
+    }
+
+    #[test]
+    fn test_source_of_keys() {
+        let profiles = profiles(
+            "",
+            "[default]\naws_access_key_id = AKIA1\naws_secret_access_key = s1\n\
+             # a comment\n[dev]\naws_access_key_id=AKIA2\naws_secret_access_key=s2\n",
+        );
+        assert_eq!(
+            Source::Keys {
+                key: "AKIA2".to_owned(),
+                secret: "s2".to_owned(),
+                token: None,
+            },
+            profiles.source("dev").unwrap()
+        );
+        assert!(profiles.source("prod").is_err());
+    }
+
+    #[test]
+    fn test_source_of_role_chain() {
+        let profiles = profiles(
+            "[profile dns]\nrole_arn = arn:aws:iam::2:role/dns\nsource_profile = admin\n\
+             external_id = x\n\
+             [profile admin]\nrole_arn = arn:aws:iam::1:role/admin\nsource_profile = default\n\
+             mfa_serial = arn:aws:iam::1:mfa/me\n",
+            "[default]\naws_access_key_id = AKIA1\naws_secret_access_key = s1\n",
+        );
+        assert_eq!(
+            Source::AssumeRole {
+                role_arn: "arn:aws:iam::2:role/dns".to_owned(),
+                source: Box::new(Source::AssumeRole {
+                    role_arn: "arn:aws:iam::1:role/admin".to_owned(),
+                    source: Box::new(Source::Keys {
+                        key: "AKIA1".to_owned(),
+                        secret: "s1".to_owned(),
+                        token: None,
+                    }),
+                    mfa_serial: Some("arn:aws:iam::1:mfa/me".to_owned()),
+                    external_id: None,
+                }),
+                mfa_serial: None,
+                external_id: Some("x".to_owned()),
+            },
+            profiles.source("dns").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_source_of_circular_chain() {
+        let profiles = profiles(
+            "[profile a]\nrole_arn = arn:aws:iam::1:role/a\nsource_profile = b\n\
+             [profile b]\nrole_arn = arn:aws:iam::1:role/b\nsource_profile = a\n",
+            "",
+        );
+        assert!(profiles.source("a").is_err());
+    }
+
+    #[test]
+    fn test_load_profiles_resolves_each_once() {
+        let mut loads = 0;
+        let credentials = load_profiles_with(&[Some("dev"), Some("dev"), None], |name| {
+            loads += 1;
+            Ok(ProfileCredentials(AwsCredentials::new(
+                format!("AKIA{}", loads),
+                name,
+                None,
+                None,
+            )))
+        })
+        .unwrap();
+        assert_eq!(1, loads);
+        let keys: Vec<Option<&str>> = credentials
+            .iter()
+            .map(|x| x.as_ref().map(|x| x.0.aws_access_key_id()))
+            .collect();
+        assert_eq!(vec![Some("AKIA1"), Some("AKIA1"), None], keys);
+    }
+}
@@ -1,43 +1,104 @@
 use crate::dns::DnsTarget;
 use crate::dns::DnsZone;
+use crate::dns::MAX_TTL;
+use crate::future::BoxFuture;
+use crate::retry::retry;
 use failure::Error;
 use failure::ResultExt;
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
 use rusoto_route53::Change;
 use rusoto_route53::ChangeBatch;
 use rusoto_route53::ChangeResourceRecordSetsRequest;
+use rusoto_route53::GetHostedZoneRequest;
 use rusoto_route53::ListHostedZonesRequest;
 use rusoto_route53::ListResourceRecordSetsRequest;
 use rusoto_route53::ResourceRecord;
 use rusoto_route53::ResourceRecordSet;
 use rusoto_route53::Route53;
+use std::cell::RefCell;
 use std::fmt;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::rc::Rc;
+use std::str::FromStr;
+use tokio_core::reactor::Handle;
 
+#[derive(Clone)]
 pub struct AwsDnsZone {
     id: String,
     name: String,
+    private: bool,
+    // fetched when first needed, as the listing does not include them
+    vpc_ids: Rc<RefCell<Option<Vec<String>>>>,
     client: Rc<dyn Route53>,
+    handle: Handle,
 }
 
 impl AwsDnsZone {
-    pub(super) fn list(client: &Rc<dyn Route53>) -> Result<Vec<AwsDnsZone>, Error> {
+    pub(super) fn list(client: &Rc<dyn Route53>, handle: &Handle) -> BoxFuture<Vec<AwsDnsZone>> {
         let req = ListHostedZonesRequest {
             ..Default::default()
         };
-        let resp = client
-            .list_hosted_zones(&req)
-            .sync()
-            .with_context(|_e| format!("failed to list hosted zones: {:?}", req))?;
-        let mut values = Vec::new();
-        for hz in resp.hosted_zones {
-            let value = AwsDnsZone {
-                id: hz.id.trim_left_matches("/hostedzone/").to_owned(),
-                name: hz.name,
-                client: Rc::clone(client),
-            };
-            values.push(value);
-        }
-        Ok(values)
+        let client = Rc::clone(client);
+        let handle = handle.clone();
+        Box::new(future::loop_fn(
+            (req, Vec::new()),
+            move |(mut req, mut values): (_, Vec<AwsDnsZone>)| {
+                let (route53, attempt) = (Rc::clone(&client), req.clone());
+                let (client, handle) = (Rc::clone(&client), handle.clone());
+                retry(&handle, move || route53.list_hosted_zones(&attempt)).then(
+                    move |result| -> Result<_, Error> {
+                        let resp = result
+                            .with_context(|_e| format!("failed to list hosted zones: {:?}", req))?;
+                        for hz in resp.hosted_zones {
+                            let id = hz.id.trim_left_matches("/hostedzone/").to_owned();
+                            let private = hz.config.and_then(|x| x.private_zone).unwrap_or(false);
+                            let value = AwsDnsZone {
+                                id,
+                                name: hz.name,
+                                private,
+                                vpc_ids: Rc::default(),
+                                client: Rc::clone(&client),
+                                handle: handle.clone(),
+                            };
+                            values.push(value);
+                        }
+                        // At most 100 zones are returned at a time
+                        match resp.next_marker {
+                            Some(next_marker) if resp.is_truncated => {
+                                req.marker = Some(next_marker);
+                                Ok(Loop::Continue((req, values)))
+                            }
+                            _ => Ok(Loop::Break(values)),
+                        }
+                    },
+                )
+            },
+        ))
+    }
+
+    fn list_vpc_ids(&self) -> BoxFuture<Vec<String>> {
+        let req = GetHostedZoneRequest {
+            id: self.id.clone(),
+        };
+        let client = Rc::clone(&self.client);
+        let id = self.id.clone();
+        Box::new(
+            retry(&self.handle, move || client.get_hosted_zone(&req)).then(
+                move |result| -> Result<_, Error> {
+                    let resp =
+                        result.with_context(|_e| format!("failed to get hosted zone: {}", id))?;
+                    Ok(resp
+                        .vp_cs
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|vpc| vpc.vpc_id)
+                        .collect())
+                },
+            ),
+        )
     }
 }
 
@@ -56,34 +117,103 @@ impl DnsZone for AwsDnsZone {
         &self.name
     }
 
-    fn bind(&self, fqdn: &str, target: DnsTarget) -> Result<(), Error> {
-        let (type_, value) = match target {
-            DnsTarget::A(addr) => ("A", addr.to_string()),
-            DnsTarget::Cname(name) => ("CNAME", name),
-        };
-        let desired = ResourceRecordSet {
-            name: fqdn.to_owned(),
-            resource_records: Some(vec![ResourceRecord { value }]),
-            type_: type_.to_owned(),
-            ttl: Some(60),
-            ..Default::default()
-        };
-        self.change_record_set("UPSERT", desired)?;
-        Ok(())
+    fn is_private(&self) -> bool {
+        self.private
     }
 
-    fn unbind(&self, fqdn: &str) -> Result<(), Error> {
-        for type_ in &["A", "CNAME"] {
-            if let Some(existing) = self.find_record_set(fqdn, type_)? {
-                self.change_record_set("DELETE", existing)?;
+    fn is_associated_with(&self, vpc_id: &str) -> BoxFuture<bool> {
+        if !self.private {
+            return Box::new(future::ok(false));
+        }
+        if let Some(ref vpc_ids) = *self.vpc_ids.borrow() {
+            return Box::new(future::ok(vpc_ids.iter().any(|x| x == vpc_id)));
+        }
+        let cache = Rc::clone(&self.vpc_ids);
+        let vpc_id = vpc_id.to_owned();
+        Box::new(self.list_vpc_ids().map(move |vpc_ids| {
+            let associated = vpc_ids.contains(&vpc_id);
+            *cache.borrow_mut() = Some(vpc_ids);
+            associated
+        }))
+    }
+
+    fn bind(&self, fqdn: &str, targets: &[DnsTarget], ttl: u64) -> BoxFuture<Option<String>> {
+        let mut upserts = Vec::new();
+        let mut finds = Vec::new();
+        for type_ in RECORD_TYPES {
+            let resource_records: Vec<ResourceRecord> = targets
+                .iter()
+                .filter(|target| target.record_type() == *type_)
+                .map(|target| ResourceRecord {
+                    value: to_value(target),
+                })
+                .collect();
+            if !resource_records.is_empty() {
+                let desired = ResourceRecordSet {
+                    name: fqdn.to_owned(),
+                    resource_records: Some(resource_records),
+                    type_: (*type_).to_owned(),
+                    ttl: Some(ttl.min(MAX_TTL) as i64),
+                    ..Default::default()
+                };
+                upserts.push(change("UPSERT", desired));
+            } else {
+                finds.push(self.find_record_set(fqdn, type_));
             }
         }
-        Ok(())
+        let zone = self.clone();
+        let fqdn = fqdn.to_owned();
+        Box::new(future::join_all(finds).and_then(move |existing| {
+            // Delete first, as e.g. a CNAME cannot coexist with an A record
+            let mut changes: Vec<Change> = existing
+                .into_iter()
+                .flatten()
+                .map(|existing| change("DELETE", existing))
+                .collect();
+            changes.extend(upserts);
+            zone.change_record_sets(&fqdn, changes)
+        }))
+    }
+
+    fn unbind(&self, fqdn: &str) -> BoxFuture<Option<String>> {
+        let finds: Vec<_> = RECORD_TYPES
+            .iter()
+            .map(|type_| self.find_record_set(fqdn, type_))
+            .collect();
+        let zone = self.clone();
+        let fqdn = fqdn.to_owned();
+        Box::new(future::join_all(finds).and_then(move |existing| {
+            let deletes = existing
+                .into_iter()
+                .flatten()
+                .map(|existing| change("DELETE", existing))
+                .collect();
+            zone.change_record_sets(&fqdn, deletes)
+        }))
+    }
+
+    fn lookup(&self, fqdn: &str) -> BoxFuture<Vec<DnsTarget>> {
+        let finds: Vec<_> = RECORD_TYPES
+            .iter()
+            .map(|type_| self.find_record_set(fqdn, type_))
+            .collect();
+        Box::new(future::join_all(finds).and_then(|existing| {
+            let mut targets = Vec::new();
+            for (type_, existing) in RECORD_TYPES.iter().zip(existing) {
+                for rr in existing
+                    .and_then(|x| x.resource_records)
+                    .unwrap_or_default()
+                {
+                    targets.push(from_value(type_, rr.value)?);
+                }
+            }
+            Ok(targets)
+        }))
     }
 }
 
 impl AwsDnsZone {
-    fn find_record_set(&self, fqdn: &str, type_: &str) -> Result<Option<ResourceRecordSet>, Error> {
+    fn find_record_set(&self, fqdn: &str, type_: &str) -> BoxFuture<Option<ResourceRecordSet>> {
         let req = ListResourceRecordSetsRequest {
             hosted_zone_id: self.id.clone(),
             start_record_name: Some(fqdn.to_owned()),
@@ -91,30 +221,90 @@ impl AwsDnsZone {
             max_items: Some("1".to_owned()), // ...String?
             ..Default::default()
         };
-        let resp = self
-            .client
-            .list_resource_record_sets(&req)
-            .sync()
-            .with_context(|_e| format!("failed to find existing DNS entry: {}", fqdn))?;
-        Ok(resp.resource_record_sets.into_iter().next())
+        let client = Rc::clone(&self.client);
+        let fqdn = fqdn.to_owned();
+        let type_ = type_.to_owned();
+        Box::new(
+            retry(&self.handle, move || client.list_resource_record_sets(&req)).then(
+                move |result| -> Result<_, Error> {
+                    let resp = result.with_context(|_e| {
+                        format!("failed to find existing DNS entry: {}", fqdn)
+                    })?;
+                    // The listing starts at the given name and type, so the first result
+                    // may belong to a different record if the requested one does not exist
+                    Ok(resp
+                        .resource_record_sets
+                        .into_iter()
+                        .next()
+                        .filter(|rrs| rrs.type_ == type_ && same_fqdn(&rrs.name, &fqdn)))
+                },
+            ),
+        )
     }
 
-    fn change_record_set(&self, action: &str, record_set: ResourceRecordSet) -> Result<(), Error> {
-        let fqdn = record_set.name.clone();
+    // The ID of the change, if one was needed
+    fn change_record_sets(&self, fqdn: &str, changes: Vec<Change>) -> BoxFuture<Option<String>> {
+        if changes.is_empty() {
+            return Box::new(future::ok(None));
+        }
         let req = ChangeResourceRecordSetsRequest {
             hosted_zone_id: self.id.clone(),
             change_batch: ChangeBatch {
                 comment: None,
-                changes: vec![Change {
-                    action: action.to_owned(),
-                    resource_record_set: record_set,
-                }],
+                changes,
             },
         };
-        self.client
-            .change_resource_record_sets(&req)
-            .sync()
-            .with_context(|_e| format!("failed to {} DNS entry: {}", action, fqdn))?;
-        Ok(())
+        let client = Rc::clone(&self.client);
+        let fqdn = fqdn.to_owned();
+        Box::new(
+            retry(&self.handle, move || {
+                client.change_resource_record_sets(&req)
+            })
+            .then(move |result| -> Result<_, Error> {
+                let resp =
+                    result.with_context(|_e| format!("failed to change DNS entry: {}", fqdn))?;
+                let change_id = resp.change_info.id.trim_start_matches("/change/");
+                Ok(Some(change_id.to_owned()))
+            }),
+        )
+    }
+}
+
+const RECORD_TYPES: &[&str] = &["A", "AAAA", "CNAME"];
+
+fn change(action: &str, record_set: ResourceRecordSet) -> Change {
+    Change {
+        action: action.to_owned(),
+        resource_record_set: record_set,
     }
 }
+
+fn to_value(target: &DnsTarget) -> String {
+    match *target {
+        DnsTarget::A(addr) => addr.to_string(),
+        DnsTarget::Aaaa(addr) => addr.to_string(),
+        DnsTarget::Cname(ref name) => name.clone(),
+    }
+}
+
+fn from_value(type_: &str, value: String) -> Result<DnsTarget, Error> {
+    let target = match type_ {
+        "A" => DnsTarget::A(
+            Ipv4Addr::from_str(&value)
+                .with_context(|_e| format!("not an IPv4 address: {}", value))?,
+        ),
+        "AAAA" => DnsTarget::Aaaa(
+            Ipv6Addr::from_str(&value)
+                .with_context(|_e| format!("not an IPv6 address: {}", value))?,
+        ),
+        "CNAME" => DnsTarget::Cname(value),
+        x => bail!("unsupported DNS record type: {}", x),
+    };
+    Ok(target)
+}
+
+// Route 53 returns fully qualified names with a trailing dot
+fn same_fqdn(x: &str, y: &str) -> bool {
+    x.trim_end_matches('.')
+        .eq_ignore_ascii_case(y.trim_end_matches('.'))
+}
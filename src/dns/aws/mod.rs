@@ -1,23 +1,47 @@
+use crate::credentials::ProfileCredentials;
 use crate::dns::aws::dns_zone::AwsDnsZone;
 use crate::dns::Dns;
+use crate::future::BoxFuture;
+use crate::retry::retry;
 use failure::Error;
+use failure::ResultExt;
+use futures::future;
+use futures::Future;
+use rusoto_core::DefaultCredentialsProvider;
+use rusoto_core::HttpClient;
 use rusoto_core::Region;
+use rusoto_route53::GetChangeRequest;
 use rusoto_route53::Route53;
 use rusoto_route53::Route53Client;
+use std::cell::RefCell;
 use std::rc::Rc;
+use tokio_core::reactor::Handle;
 
 mod dns_zone;
 
 pub struct AwsDns {
     client: Rc<dyn Route53>,
+    // listed once per command, rather than for each hostname
+    zones: Rc<RefCell<Option<Vec<AwsDnsZone>>>>,
+    handle: Handle,
 }
 
 impl AwsDns {
-    pub fn new() -> Result<AwsDns, Error> {
+    // The zones may be in another account than the instances, so have their own profile
+    pub fn new(handle: &Handle, credentials: Option<ProfileCredentials>) -> Result<AwsDns, Error> {
         let region = Region::UsEast1;
-        let route53 = Route53Client::simple(region);
+        let dispatcher = HttpClient::new(handle)?;
+        let client: Rc<dyn Route53> = match credentials {
+            Some(credentials) => Rc::new(Route53Client::new(dispatcher, credentials, region)),
+            None => {
+                let credentials = DefaultCredentialsProvider::new(handle)?;
+                Rc::new(Route53Client::new(dispatcher, credentials, region))
+            }
+        };
         Ok(AwsDns {
-            client: Rc::new(route53),
+            client,
+            zones: Rc::default(),
+            handle: handle.clone(),
         })
     }
 }
@@ -25,7 +49,39 @@ impl AwsDns {
 impl Dns for AwsDns {
     type DnsZone = AwsDnsZone;
 
-    fn list_zones(&self) -> Result<Vec<AwsDnsZone>, Error> {
-        AwsDnsZone::list(&self.client)
+    fn list_zones(&self) -> BoxFuture<Vec<AwsDnsZone>> {
+        if let Some(ref zones) = *self.zones.borrow() {
+            return Box::new(future::ok(zones.clone()));
+        }
+        let cache = Rc::clone(&self.zones);
+        Box::new(
+            AwsDnsZone::list(&self.client, &self.handle).map(move |zones| {
+                *cache.borrow_mut() = Some(zones.clone());
+                zones
+            }),
+        )
+    }
+
+    // Changes are PENDING until they have reached all of the zone's name servers
+    fn poll_change(&self, change_id: &str) -> BoxFuture<Option<()>> {
+        let req = GetChangeRequest {
+            id: change_id.to_owned(),
+        };
+        let client = Rc::clone(&self.client);
+        let change_id = change_id.to_owned();
+        Box::new(retry(&self.handle, move || client.get_change(&req)).then(
+            move |result| -> Result<_, Error> {
+                let resp =
+                    result.with_context(|_e| format!("failed to get DNS change: {}", change_id))?;
+                eprintln!(
+                    "DNS change status: {} {}",
+                    change_id, resp.change_info.status
+                );
+                match resp.change_info.status.as_str() {
+                    "INSYNC" => Ok(Some(())),
+                    _ => Ok(None),
+                }
+            },
+        ))
     }
 }
@@ -1,6 +1,8 @@
 use crate::dns::DnsTarget;
 use crate::dns::DnsZone;
+use crate::future::BoxFuture;
 use failure::Error;
+use futures::future;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
@@ -10,27 +12,36 @@ use std::rc::Rc;
 pub struct MemDnsZone {
     id: String,
     name: String,
+    // empty for a public zone
+    vpc_ids: Vec<String>,
     state: Rc<RefCell<MemDnsZoneState>>,
 }
 
 struct MemDnsZoneState {
-    records: HashMap<String, DnsTarget>,
+    // may contain records of several types, e.g. A and AAAA
+    records: HashMap<String, Vec<DnsTarget>>,
+    ttls: HashMap<String, u64>,
 }
 
 impl MemDnsZone {
-    pub(super) fn new(id: String, name: String) -> Result<MemDnsZone, Error> {
+    pub(super) fn new(id: String, name: String, vpc_ids: Vec<String>) -> Result<MemDnsZone, Error> {
         Ok(MemDnsZone {
             id,
             name,
+            vpc_ids,
             state: Rc::new(RefCell::new(MemDnsZoneState {
                 records: HashMap::new(),
+                ttls: HashMap::new(),
             })),
         })
     }
 
-    pub fn lookup(&self, fqdn: &str) -> Result<Option<DnsTarget>, Error> {
-        let state = self.state.borrow();
-        Ok(state.records.get(fqdn).cloned())
+    fn change_id(&self, fqdn: &str) -> String {
+        format!("{}/{}", self.id, fqdn)
+    }
+
+    pub fn lookup_ttl(&self, fqdn: &str) -> Option<u64> {
+        self.state.borrow().ttls.get(fqdn).cloned()
     }
 }
 
@@ -49,15 +60,45 @@ impl DnsZone for MemDnsZone {
         &self.name
     }
 
-    fn bind(&self, fqdn: &str, target: DnsTarget) -> Result<(), Error> {
+    fn is_private(&self) -> bool {
+        !self.vpc_ids.is_empty()
+    }
+
+    fn is_associated_with(&self, vpc_id: &str) -> BoxFuture<bool> {
+        Box::new(future::ok(self.vpc_ids.iter().any(|x| x == vpc_id)))
+    }
+
+    // Changes are visible immediately in memory, but are still given IDs,
+    // so that MemDns may report them as pending
+    fn bind(&self, fqdn: &str, targets: &[DnsTarget], ttl: u64) -> BoxFuture<Option<String>> {
+        let has_cname = targets.iter().any(|x| x.record_type() == "CNAME");
+        if has_cname && targets.len() > 1 {
+            return Box::new(future::err(format_err!(
+                "CNAME cannot coexist with other records: {}",
+                fqdn
+            )));
+        }
         let mut state = self.state.borrow_mut();
-        state.records.insert(fqdn.to_owned(), target);
-        Ok(())
+        state.records.insert(fqdn.to_owned(), targets.to_vec());
+        state.ttls.insert(fqdn.to_owned(), ttl);
+        Box::new(future::ok(Some(self.change_id(fqdn))))
     }
 
-    fn unbind(&self, fqdn: &str) -> Result<(), Error> {
+    fn unbind(&self, fqdn: &str) -> BoxFuture<Option<String>> {
         let mut state = self.state.borrow_mut();
-        state.records.remove(fqdn);
-        Ok(())
+        match state.records.remove(fqdn) {
+            Some(_) => {
+                state.ttls.remove(fqdn);
+                Box::new(future::ok(Some(self.change_id(fqdn))))
+            }
+            None => Box::new(future::ok(None)),
+        }
+    }
+
+    fn lookup(&self, fqdn: &str) -> BoxFuture<Vec<DnsTarget>> {
+        let state = self.state.borrow();
+        Box::new(future::ok(
+            state.records.get(fqdn).cloned().unwrap_or_default(),
+        ))
     }
 }
@@ -3,7 +3,9 @@ mod dns_zone;
 pub use crate::dns::mem::dns_zone::MemDnsZone;
 use crate::dns::Dns;
 use crate::dns::DnsZone;
+use crate::future::BoxFuture;
 use failure::Error;
+use futures::future;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::Range;
@@ -17,6 +19,8 @@ pub struct MemDns {
 struct MemDnsState {
     ids: Range<u32>,
     dns_zones: HashMap<String, MemDnsZone>,
+    // if not set, changes never reach all name servers
+    changes_in_sync: bool,
 }
 
 impl MemDns {
@@ -25,16 +29,28 @@ impl MemDns {
             state: Rc::new(RefCell::new(MemDnsState {
                 ids: 0..u32::MAX,
                 dns_zones: HashMap::new(),
+                changes_in_sync: true,
             })),
         })
     }
 
     pub fn create_dns_zone(&self, name: &str) -> Result<MemDnsZone, Error> {
         let mut state = self.state.borrow_mut();
-        let value = MemDnsZone::new(state.fresh_id()?, name.to_owned())?;
+        let value = MemDnsZone::new(state.fresh_id()?, name.to_owned(), vec![])?;
         state.dns_zones.insert(value.id().to_owned(), value.clone());
         Ok(value)
     }
+
+    pub fn create_private_dns_zone(&self, name: &str, vpc_id: &str) -> Result<MemDnsZone, Error> {
+        let mut state = self.state.borrow_mut();
+        let value = MemDnsZone::new(state.fresh_id()?, name.to_owned(), vec![vpc_id.to_owned()])?;
+        state.dns_zones.insert(value.id().to_owned(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set_changes_in_sync(&self, changes_in_sync: bool) {
+        self.state.borrow_mut().changes_in_sync = changes_in_sync;
+    }
 }
 
 impl MemDnsState {
@@ -49,9 +65,17 @@ impl MemDnsState {
 impl Dns for MemDns {
     type DnsZone = MemDnsZone;
 
-    fn list_zones(&self) -> Result<Vec<MemDnsZone>, Error> {
+    fn list_zones(&self) -> BoxFuture<Vec<MemDnsZone>> {
         let state = self.state.borrow();
         let xs = state.dns_zones.values().cloned().collect();
-        Ok(xs)
+        Box::new(future::ok(xs))
+    }
+
+    fn poll_change(&self, _change_id: &str) -> BoxFuture<Option<()>> {
+        if self.state.borrow().changes_in_sync {
+            Box::new(future::ok(Some(())))
+        } else {
+            Box::new(future::ok(None))
+        }
     }
 }
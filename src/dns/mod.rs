@@ -2,47 +2,124 @@ pub mod aws;
 #[cfg(test)]
 pub mod mem;
 
-use failure::Error;
+use crate::future::BoxFuture;
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
+use serde::Serialize;
+use std::cmp;
 use std::fmt;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::str;
+use std::vec;
+
+// How long resolvers may cache a record, in seconds, unless an instance sets its own
+pub const DEFAULT_TTL: u64 = 60;
+// The most that Route 53 accepts
+pub const MAX_TTL: u64 = 2_147_483_647;
 
 pub trait Dns {
-    type DnsZone: DnsZone;
-
-    fn list_zones(&self) -> Result<Vec<Self::DnsZone>, Error>;
-
-    fn find_authoritative_zone(&self, name: &str) -> Result<Self::DnsZone, Error> {
-        let parts: Vec<&str> = name.split_terminator('.').collect();
-        let zones = self.list_zones()?;
-        zones
-            .into_iter()
-            .filter(|zone| {
-                let zone_parts: Vec<&str> = zone.name().split_terminator('.').collect();
-                parts.ends_with(&zone_parts)
-            })
-            .max_by_key(|zone| zone.name().len())
-            .ok_or_else(|| format_err!("could not find authoritative DNS zone for: {}", name))
+    type DnsZone: DnsZone + 'static;
+
+    fn list_zones(&self) -> BoxFuture<Vec<Self::DnsZone>>;
+
+    // Some once a change made by bind or unbind has reached all name servers.
+    // Called repeatedly until then, so that many changes may be polled together.
+    fn poll_change(&self, change_id: &str) -> BoxFuture<Option<()>>;
+
+    // A public zone, or if a VPC is given, a private zone associated with that VPC,
+    // so that a public and a private zone may have the same name
+    fn find_authoritative_zone(
+        &self,
+        name: &str,
+        vpc_id: Option<&str>,
+    ) -> BoxFuture<Self::DnsZone> {
+        let name = name.to_owned();
+        let vpc_id = vpc_id.map(str::to_owned);
+        Box::new(self.list_zones().and_then(move |zones| {
+            let parts: Vec<&str> = name.split_terminator('.').collect();
+            let mut zones: Vec<Self::DnsZone> = zones
+                .into_iter()
+                .filter(|zone| zone.is_private() == vpc_id.is_some())
+                .filter(|zone| {
+                    let zone_parts: Vec<&str> = zone.name().split_terminator('.').collect();
+                    parts.ends_with(&zone_parts)
+                })
+                .collect();
+            // The most specific first, and in turn, so that only those VPC
+            // associations are checked
+            zones.sort_by_key(|zone| cmp::Reverse(zone.name().len()));
+            future::loop_fn(
+                zones.into_iter(),
+                move |mut zones| -> BoxFuture<Loop<Self::DnsZone, vec::IntoIter<Self::DnsZone>>> {
+                    match (zones.next(), vpc_id.as_deref()) {
+                        (Some(zone), None) => Box::new(future::ok(Loop::Break(zone))),
+                        (Some(zone), Some(vpc_id)) => {
+                            Box::new(zone.is_associated_with(vpc_id).map(move |associated| {
+                                if associated {
+                                    Loop::Break(zone)
+                                } else {
+                                    Loop::Continue(zones)
+                                }
+                            }))
+                        }
+                        (None, Some(vpc_id)) => Box::new(future::err(format_err!(
+                            "could not find private DNS zone for: {} in VPC: {}",
+                            name,
+                            vpc_id
+                        ))),
+                        (None, None) => Box::new(future::err(format_err!(
+                            "could not find authoritative DNS zone for: {}",
+                            name
+                        ))),
+                    }
+                },
+            )
+        }))
     }
 }
 
 pub trait DnsZone: fmt::Debug {
     fn id(&self) -> &str;
     fn name(&self) -> &str;
-    fn bind(&self, fqdn: &str, target: DnsTarget) -> Result<(), Error>;
-    fn unbind(&self, fqdn: &str) -> Result<(), Error>;
+    // private zones are resolvable only within their associated VPCs
+    fn is_private(&self) -> bool;
+    // whether a private zone is resolvable within the VPC
+    fn is_associated_with(&self, vpc_id: &str) -> BoxFuture<bool>;
+    // replaces any existing records for the name, without waiting for the change
+    // to reach all name servers. Returns the change to poll, if one was made.
+    fn bind(&self, fqdn: &str, targets: &[DnsTarget], ttl: u64) -> BoxFuture<Option<String>>;
+    fn unbind(&self, fqdn: &str) -> BoxFuture<Option<String>>;
+    fn lookup(&self, fqdn: &str) -> BoxFuture<Vec<DnsTarget>>;
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "value")]
 pub enum DnsTarget {
     A(Ipv4Addr),
-    // TODO: Aaaa(Ipv6Addr),
+    #[serde(rename = "AAAA")]
+    Aaaa(Ipv6Addr),
+    #[serde(rename = "CNAME")]
     Cname(String),
 }
 
+impl DnsTarget {
+    pub fn record_type(&self) -> &'static str {
+        match *self {
+            DnsTarget::A(_) => "A",
+            DnsTarget::Aaaa(_) => "AAAA",
+            DnsTarget::Cname(_) => "CNAME",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use failure::Error;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
     // TODO(ques_in_main)
 
@@ -52,37 +129,87 @@ mod tests {
     }
 
     fn test_find_authoritative_zone_impl() -> Result<(), Error> {
-        let dns = TestDns {
-            zones: vec![
-                TestDnsZone::new("example.com"),
-                TestDnsZone::new("sub1.example.com"),
-                TestDnsZone::new("sub2.example.com"),
-                TestDnsZone::new("unrelated.sub1.example.com"),
-                TestDnsZone::new("unrelated.sub2.example.com"),
-                TestDnsZone::new("unrelated.sub3.example.com"),
-                TestDnsZone::new("unrelated-sub1.example.com"),
-                TestDnsZone::new("unrelated-sub2.example.com"),
-                TestDnsZone::new("unrelated-sub3.example.com"),
-                TestDnsZone::new("example.net"),
-            ],
-        };
+        let dns = TestDns::new(vec![
+            TestDnsZone::new("example.com"),
+            TestDnsZone::new("sub1.example.com"),
+            TestDnsZone::new("sub2.example.com"),
+            TestDnsZone::new("unrelated.sub1.example.com"),
+            TestDnsZone::new("unrelated.sub2.example.com"),
+            TestDnsZone::new("unrelated.sub3.example.com"),
+            TestDnsZone::new("unrelated-sub1.example.com"),
+            TestDnsZone::new("unrelated-sub2.example.com"),
+            TestDnsZone::new("unrelated-sub3.example.com"),
+            TestDnsZone::new("example.net"),
+        ]);
 
         assert_eq!(
             "example.com",
-            dns.find_authoritative_zone("x.example.com")?.name()
+            dns.find_authoritative_zone("x.example.com", None)
+                .wait()?
+                .name()
         );
         assert_eq!(
             "sub1.example.com",
-            dns.find_authoritative_zone("x.sub1.example.com")?.name()
+            dns.find_authoritative_zone("x.sub1.example.com", None)
+                .wait()?
+                .name()
         );
         assert_eq!(
             "sub2.example.com",
-            dns.find_authoritative_zone("x.sub2.example.com")?.name()
+            dns.find_authoritative_zone("x.sub2.example.com", None)
+                .wait()?
+                .name()
         );
         // There is no sub3.example.com, so example.com is authoritative
         assert_eq!(
             "example.com",
-            dns.find_authoritative_zone("x.sub3.example.com")?.name()
+            dns.find_authoritative_zone("x.sub3.example.com", None)
+                .wait()?
+                .name()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_private_zone() {
+        test_find_private_zone_impl().unwrap();
+    }
+
+    fn test_find_private_zone_impl() -> Result<(), Error> {
+        let dns = TestDns::new(vec![
+            TestDnsZone::new_private("example.com", "vpc-1"),
+            TestDnsZone::new("example.com"),
+            TestDnsZone::new_private("example.com", "vpc-2"),
+            TestDnsZone::new_private("internal.example.com", "vpc-2"),
+        ]);
+
+        let zone = dns
+            .find_authoritative_zone("x.internal.example.com", None)
+            .wait()?;
+        assert_eq!(("example.com", false), (zone.name(), zone.is_private()));
+        assert_eq!(0, dns.checks.get());
+        let zone = dns
+            .find_authoritative_zone("x.internal.example.com", Some("vpc-1"))
+            .wait()?;
+        assert_eq!("example.com", zone.name());
+        assert!(zone.is_associated_with("vpc-1").wait()?);
+        let zone = dns
+            .find_authoritative_zone("x.internal.example.com", Some("vpc-2"))
+            .wait()?;
+        assert_eq!("internal.example.com", zone.name());
+        // Only the zones whose names match are checked, most specific first
+        dns.checks.set(0);
+        dns.find_authoritative_zone("x.internal.example.com", Some("vpc-2"))
+            .wait()?;
+        assert_eq!(1, dns.checks.get());
+        let err = dns
+            .find_authoritative_zone("x.example.com", Some("vpc-3"))
+            .wait()
+            .unwrap_err();
+        assert_eq!(
+            "could not find private DNS zone for: x.example.com in VPC: vpc-3",
+            err.to_string()
         );
 
         Ok(())
@@ -91,24 +218,55 @@ mod tests {
     #[derive(Debug)]
     struct TestDns {
         zones: Vec<TestDnsZone>,
+        // how many VPC associations have been checked
+        checks: Rc<Cell<usize>>,
+    }
+
+    impl TestDns {
+        fn new(zones: Vec<TestDnsZone>) -> TestDns {
+            let checks = Rc::new(Cell::new(0));
+            let zones = zones
+                .into_iter()
+                .map(|zone| TestDnsZone {
+                    checks: Rc::clone(&checks),
+                    ..zone
+                })
+                .collect();
+            TestDns { zones, checks }
+        }
     }
 
     impl Dns for TestDns {
         type DnsZone = TestDnsZone;
-        fn list_zones(&self) -> Result<Vec<Self::DnsZone>, Error> {
-            Ok(self.zones.clone())
+        fn list_zones(&self) -> BoxFuture<Vec<Self::DnsZone>> {
+            Box::new(future::ok(self.zones.clone()))
+        }
+        fn poll_change(&self, _change_id: &str) -> BoxFuture<Option<()>> {
+            unimplemented!();
         }
     }
 
     #[derive(Debug, Clone)]
     struct TestDnsZone {
         name: String,
+        vpc_ids: Vec<String>,
+        checks: Rc<Cell<usize>>,
     }
 
     impl TestDnsZone {
         fn new(name: &str) -> TestDnsZone {
             TestDnsZone {
                 name: name.to_owned(),
+                vpc_ids: vec![],
+                checks: Rc::default(),
+            }
+        }
+
+        fn new_private(name: &str, vpc_id: &str) -> TestDnsZone {
+            TestDnsZone {
+                name: name.to_owned(),
+                vpc_ids: vec![vpc_id.to_owned()],
+                checks: Rc::default(),
             }
         }
     }
@@ -120,10 +278,25 @@ mod tests {
         fn name(&self) -> &str {
             &self.name
         }
-        fn bind(&self, _fqdn: &str, _target: DnsTarget) -> Result<(), Error> {
+        fn is_private(&self) -> bool {
+            !self.vpc_ids.is_empty()
+        }
+        fn is_associated_with(&self, vpc_id: &str) -> BoxFuture<bool> {
+            self.checks.set(self.checks.get() + 1);
+            Box::new(future::ok(self.vpc_ids.iter().any(|x| x == vpc_id)))
+        }
+        fn bind(
+            &self,
+            _fqdn: &str,
+            _targets: &[DnsTarget],
+            _ttl: u64,
+        ) -> BoxFuture<Option<String>> {
+            unimplemented!();
+        }
+        fn unbind(&self, _fqdn: &str) -> BoxFuture<Option<String>> {
             unimplemented!();
         }
-        fn unbind(&self, _fqdn: &str) -> Result<(), Error> {
+        fn lookup(&self, _fqdn: &str) -> BoxFuture<Vec<DnsTarget>> {
             unimplemented!();
         }
     }
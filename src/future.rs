@@ -0,0 +1,17 @@
+use failure::Error;
+use futures::future;
+use futures::Future;
+
+// Cloud and DNS operations are futures, so that many may be in flight together on
+// the one reactor. Each owns what it needs, rather than borrowing what made it.
+pub type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error>>;
+
+// Runs the futures together, keeping the result of each,
+// so that one failing does not stop the others
+pub fn join_results<T, I>(futures: I) -> impl Future<Item = Vec<Result<T, Error>>, Error = Error>
+where
+    T: 'static,
+    I: IntoIterator<Item = BoxFuture<T>>,
+{
+    future::join_all(futures.into_iter().map(|x| x.then(Ok)))
+}
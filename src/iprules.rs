@@ -2,6 +2,11 @@ use ipnet::IpNet;
 use std::fmt;
 use std::result;
 use std::str;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const MAX_EXPIRY_SECS: u64 = 253_402_300_799;
 
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct IpPortRange(pub u16, pub u16);
@@ -43,19 +48,90 @@ impl str::FromStr for IpPortRange {
     }
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+// Well-known ICMP types, which may be given by name instead of number
+const ICMP_TYPE_NAMES: &[(&str, u8)] = &[
+    ("echo-reply", 0),
+    ("destination-unreachable", 3),
+    ("redirect", 5),
+    ("echo-request", 8),
+    ("time-exceeded", 11),
+];
+
+const ICMPV6_TYPE_NAMES: &[(&str, u8)] = &[
+    ("destination-unreachable", 1),
+    ("packet-too-big", 2),
+    ("time-exceeded", 3),
+    ("echo-request", 128),
+    ("echo-reply", 129),
+];
+
+#[derive(Clone, Hash, PartialEq, Eq)]
 pub enum IpProtocol {
     Tcp(IpPortRange),
     Udp(IpPortRange),
+    // A missing type or code matches any type or code
+    Icmp {
+        icmp_type: Option<u8>,
+        code: Option<u8>,
+    },
+    Icmpv6 {
+        icmp_type: Option<u8>,
+        code: Option<u8>,
+    },
+    All,
+    // Any other IP protocol number, e.g. 50 for ESP
+    Other(u8),
+    // A protocol found on a firewall that drawbridge cannot model, which is left alone
+    Unknown(String),
+}
+
+impl IpProtocol {
+    // ICMP only carries IPv4, and ICMPv6 only IPv6
+    pub fn applies_to(&self, ip_cidr: &IpNet) -> bool {
+        match *self {
+            IpProtocol::Icmp { .. } => ip_cidr.network().is_ipv4(),
+            IpProtocol::Icmpv6 { .. } => ip_cidr.network().is_ipv6(),
+            _ => true,
+        }
+    }
 }
 
 impl fmt::Display for IpProtocol {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            &IpProtocol::Tcp(ref range) => write!(f, "{}/tcp", range),
-            &IpProtocol::Udp(ref range) => write!(f, "{}/udp", range),
+        match *self {
+            IpProtocol::Tcp(ref range) => write!(f, "{}/tcp", range),
+            IpProtocol::Udp(ref range) => write!(f, "{}/udp", range),
+            IpProtocol::Icmp { icmp_type, code } => {
+                fmt_icmp(f, "icmp", ICMP_TYPE_NAMES, icmp_type, code)
+            }
+            IpProtocol::Icmpv6 { icmp_type, code } => {
+                fmt_icmp(f, "icmpv6", ICMPV6_TYPE_NAMES, icmp_type, code)
+            }
+            IpProtocol::All => write!(f, "all"),
+            IpProtocol::Other(number) => write!(f, "proto-{}", number),
+            IpProtocol::Unknown(ref x) => write!(f, "unknown ({})", x),
+        }
+    }
+}
+
+fn fmt_icmp(
+    f: &mut fmt::Formatter<'_>,
+    protocol: &str,
+    names: &[(&str, u8)],
+    icmp_type: Option<u8>,
+    code: Option<u8>,
+) -> fmt::Result {
+    if let Some(icmp_type) = icmp_type {
+        match names.iter().find(|&&(_, x)| x == icmp_type) {
+            Some(&(name, _)) => write!(f, "{}", name)?,
+            None => write!(f, "{}", icmp_type)?,
         }
+        if let Some(code) = code {
+            write!(f, ":{}", code)?;
+        }
+        write!(f, "/")?;
     }
+    write!(f, "{}", protocol)
 }
 
 impl fmt::Debug for IpProtocol {
@@ -83,21 +159,254 @@ impl str::FromStr for IpProtocol {
                     let range = parts[0].parse().map_err(|_| ParseIpProtocolError(()))?;
                     Ok(IpProtocol::Udp(range))
                 }
+                "icmp" => {
+                    let (icmp_type, code) = parse_icmp(parts[0], ICMP_TYPE_NAMES)?;
+                    Ok(IpProtocol::Icmp {
+                        icmp_type: Some(icmp_type),
+                        code,
+                    })
+                }
+                "icmpv6" => {
+                    let (icmp_type, code) = parse_icmp(parts[0], ICMPV6_TYPE_NAMES)?;
+                    Ok(IpProtocol::Icmpv6 {
+                        icmp_type: Some(icmp_type),
+                        code,
+                    })
+                }
                 _ => Err(ParseIpProtocolError(())),
             }
         } else {
-            Err(ParseIpProtocolError(()))
+            match s {
+                "icmp" => Ok(IpProtocol::Icmp {
+                    icmp_type: None,
+                    code: None,
+                }),
+                "icmpv6" => Ok(IpProtocol::Icmpv6 {
+                    icmp_type: None,
+                    code: None,
+                }),
+                "all" => Ok(IpProtocol::All),
+                _ if s.starts_with("proto-") => {
+                    let number = s["proto-".len()..]
+                        .parse()
+                        .map_err(|_| ParseIpProtocolError(()))?;
+                    Ok(IpProtocol::Other(number))
+                }
+                _ => Err(ParseIpProtocolError(())),
+            }
         }
     }
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
-pub struct IpIngressRule(pub IpNet, pub IpProtocol);
+// Either type or type:code, where type may be a name
+fn parse_icmp(
+    s: &str,
+    names: &[(&str, u8)],
+) -> result::Result<(u8, Option<u8>), ParseIpProtocolError> {
+    let mut parts = s.splitn(2, ':');
+    let icmp_type = parts.next().expect("at least one part");
+    let icmp_type = match names.iter().find(|&&(name, _)| name == icmp_type) {
+        Some(&(_, x)) => x,
+        None => icmp_type.parse().map_err(|_| ParseIpProtocolError(()))?,
+    };
+    let code = match parts.next() {
+        Some(code) => Some(code.parse().map_err(|_| ParseIpProtocolError(()))?),
+        None => None,
+    };
+    Ok((icmp_type, code))
+}
+
+// Drawbridge only creates rules with CIDR sources, but firewalls may also
+// allow traffic from other security groups or from managed prefix lists
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub enum IpSource {
+    Cidr(IpNet),
+    Group {
+        group_id: String,
+        user_id: Option<String>,
+    },
+    PrefixList(String),
+    // A source found on a firewall that drawbridge cannot model, which is left alone
+    Unknown(String),
+}
+
+impl fmt::Display for IpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            IpSource::Cidr(ref net) => write!(f, "{}", net),
+            IpSource::Group {
+                ref group_id,
+                user_id: Some(ref user_id),
+            } => write!(f, "{}/{}", user_id, group_id),
+            IpSource::Group {
+                ref group_id,
+                user_id: None,
+            } => write!(f, "{}", group_id),
+            IpSource::PrefixList(ref prefix_list_id) => write!(f, "{}", prefix_list_id),
+            IpSource::Unknown(ref x) => write!(f, "unknown ({})", x),
+        }
+    }
+}
+
+impl fmt::Debug for IpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Fail, Debug, Copy, Clone, PartialEq, Eq)]
+#[fail(display = "invalid IP source")]
+pub struct ParseIpSourceError(());
+
+impl str::FromStr for IpSource {
+    type Err = ParseIpSourceError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let parts = s.split('/').collect::<Vec<_>>();
+        match parts[..] {
+            [group_id] if group_id.starts_with("sg-") => Ok(IpSource::Group {
+                group_id: group_id.to_owned(),
+                user_id: None,
+            }),
+            [user_id, group_id] if group_id.starts_with("sg-") => Ok(IpSource::Group {
+                group_id: group_id.to_owned(),
+                user_id: Some(user_id.to_owned()),
+            }),
+            [prefix_list_id] if prefix_list_id.starts_with("pl-") => {
+                Ok(IpSource::PrefixList(prefix_list_id.to_owned()))
+            }
+            _ => {
+                let net = s.parse().map_err(|_| ParseIpSourceError(()))?;
+                Ok(IpSource::Cidr(net))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct IpIngressRule(pub IpSource, pub IpProtocol);
+
+impl IpIngressRule {
+    // Rules that drawbridge could have created, as opposed to those it merely tolerates
+    pub fn is_manageable(&self) -> bool {
+        is_manageable(&self.0, &self.1)
+    }
+}
 
 impl fmt::Debug for IpIngressRule {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let &IpIngressRule(ref net, ref protocol) = self;
-        write!(f, "{} -> {}", protocol, net)
+        let &IpIngressRule(ref source, ref protocol) = self;
+        write!(f, "{} -> {}", protocol, source)
+    }
+}
+
+// The IpSource is the destination of the outbound traffic
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct IpEgressRule(pub IpSource, pub IpProtocol);
+
+impl IpEgressRule {
+    pub fn is_manageable(&self) -> bool {
+        is_manageable(&self.0, &self.1)
+    }
+}
+
+impl fmt::Debug for IpEgressRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "egress {} -> {}", self.1, self.0)
+    }
+}
+
+fn is_manageable(source: &IpSource, protocol: &IpProtocol) -> bool {
+    match (source, protocol) {
+        (&IpSource::Cidr(_), &IpProtocol::Unknown(_)) => false,
+        (&IpSource::Cidr(_), _) => true,
+        _ => false,
+    }
+}
+
+// Attached to the rules that drawbridge creates, e.g. as the AWS rule description
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct IpRuleMeta {
+    pub owner: Option<String>,
+    pub expiry: Option<SystemTime>,
+}
+
+impl IpRuleMeta {
+    // Owners are embedded in the description, so they are restricted to
+    // the characters that AWS allows there, minus the separators we use
+    pub fn is_valid_owner(owner: &str) -> bool {
+        !owner.is_empty()
+            && owner
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-:/()#,@[]+;{}!$*".contains(c))
+    }
+
+    // Rounded down to whole seconds, which is the precision of the stored expiry.
+    // None if past 9999-12-31T23:59:59Z, the last time that RFC 3339 can represent.
+    pub fn expiry_after(now: SystemTime, duration: Duration) -> Option<SystemTime> {
+        let now = now.duration_since(UNIX_EPOCH).ok()?;
+        let secs = now.as_secs().checked_add(duration.as_secs())?;
+        if secs > MAX_EXPIRY_SECS {
+            return None;
+        }
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expiry {
+            Some(expiry) => expiry <= now,
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for IpRuleMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "drawbridge")?;
+        if let Some(ref owner) = self.owner {
+            write!(f, " owner={}", owner)?;
+        }
+        if let Some(expiry) = self.expiry {
+            write!(f, " expires={}", humantime::format_rfc3339_seconds(expiry))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for IpRuleMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Fail, Debug, Copy, Clone, PartialEq, Eq)]
+#[fail(display = "invalid IP rule metadata")]
+pub struct ParseIpRuleMetaError(());
+
+impl str::FromStr for IpRuleMeta {
+    type Err = ParseIpRuleMetaError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut parts = s.split(' ');
+        if parts.next() != Some("drawbridge") {
+            return Err(ParseIpRuleMetaError(()));
+        }
+        let mut meta = IpRuleMeta::default();
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("owner"), Some(v)) if IpRuleMeta::is_valid_owner(v) => {
+                    meta.owner = Some(v.to_owned());
+                }
+                (Some("expires"), Some(v)) => {
+                    let expiry =
+                        humantime::parse_rfc3339(v).map_err(|_| ParseIpRuleMetaError(()))?;
+                    meta.expiry = Some(expiry);
+                }
+                _ => return Err(ParseIpRuleMetaError(())),
+            }
+        }
+        Ok(meta)
     }
 }
 
@@ -121,6 +430,158 @@ mod tests {
         test_display_and_parse(IpProtocol::Udp(IpPortRange(1, 1)), "1/udp");
         test_display_and_parse(IpProtocol::Udp(IpPortRange(1, 10)), "1-10/udp");
         test_display_and_parse(IpProtocol::Udp(IpPortRange(1, 65_535)), "1-65535/udp");
+
+        test_display_and_parse(
+            IpProtocol::Icmp {
+                icmp_type: None,
+                code: None,
+            },
+            "icmp",
+        );
+        test_display_and_parse(
+            IpProtocol::Icmp {
+                icmp_type: Some(8),
+                code: None,
+            },
+            "echo-request/icmp",
+        );
+        test_display_and_parse(
+            IpProtocol::Icmp {
+                icmp_type: Some(3),
+                code: Some(4),
+            },
+            "destination-unreachable:4/icmp",
+        );
+        test_display_and_parse(
+            IpProtocol::Icmp {
+                icmp_type: Some(42),
+                code: Some(0),
+            },
+            "42:0/icmp",
+        );
+        test_display_and_parse(
+            IpProtocol::Icmpv6 {
+                icmp_type: None,
+                code: None,
+            },
+            "icmpv6",
+        );
+        test_display_and_parse(
+            IpProtocol::Icmpv6 {
+                icmp_type: Some(128),
+                code: None,
+            },
+            "echo-request/icmpv6",
+        );
+        test_display_and_parse(IpProtocol::All, "all");
+        test_display_and_parse(IpProtocol::Other(50), "proto-50");
+
+        assert_eq!(
+            "8/icmp".parse(),
+            Ok(IpProtocol::Icmp {
+                icmp_type: Some(8),
+                code: None,
+            })
+        );
+        assert!("ping/icmp".parse::<IpProtocol>().is_err());
+        assert!("8/all".parse::<IpProtocol>().is_err());
+        assert!("tcp".parse::<IpProtocol>().is_err());
+    }
+
+    #[test]
+    fn test_source_display_and_parse() {
+        test_display_and_parse(
+            IpSource::Cidr("192.0.2.0/24".parse().unwrap()),
+            "192.0.2.0/24",
+        );
+        test_display_and_parse(
+            IpSource::Cidr("2001:db8::/32".parse().unwrap()),
+            "2001:db8::/32",
+        );
+        test_display_and_parse(
+            IpSource::Group {
+                group_id: "sg-123".to_owned(),
+                user_id: None,
+            },
+            "sg-123",
+        );
+        test_display_and_parse(
+            IpSource::Group {
+                group_id: "sg-123".to_owned(),
+                user_id: Some("123456789012".to_owned()),
+            },
+            "123456789012/sg-123",
+        );
+        test_display_and_parse(IpSource::PrefixList("pl-123".to_owned()), "pl-123");
+        assert!("192.0.2.1".parse::<IpSource>().is_err());
+    }
+
+    #[test]
+    fn test_protocol_applies_to() {
+        let v4: IpNet = "192.0.2.0/24".parse().unwrap();
+        let v6: IpNet = "2001:db8::/32".parse().unwrap();
+        let icmp: IpProtocol = "echo-request/icmp".parse().unwrap();
+        let icmpv6: IpProtocol = "echo-request/icmpv6".parse().unwrap();
+        let tcp: IpProtocol = "22/tcp".parse().unwrap();
+        assert!(icmp.applies_to(&v4) && !icmp.applies_to(&v6));
+        assert!(!icmpv6.applies_to(&v4) && icmpv6.applies_to(&v6));
+        assert!(tcp.applies_to(&v4) && tcp.applies_to(&v6));
+    }
+
+    #[test]
+    fn test_rule_is_manageable() {
+        let rule = |source: &str, protocol: &str| {
+            IpIngressRule(source.parse().unwrap(), protocol.parse().unwrap())
+        };
+        assert!(rule("192.0.2.0/24", "22/tcp").is_manageable());
+        assert!(rule("192.0.2.0/24", "all").is_manageable());
+        assert!(rule("192.0.2.0/24", "proto-50").is_manageable());
+        assert!(!rule("sg-123", "22/tcp").is_manageable());
+        assert!(!rule("pl-123", "22/tcp").is_manageable());
+    }
+
+    #[test]
+    fn test_rule_meta_display_and_parse() {
+        test_display_and_parse(
+            IpRuleMeta {
+                owner: None,
+                expiry: None,
+            },
+            "drawbridge",
+        );
+        test_display_and_parse(
+            IpRuleMeta {
+                owner: None,
+                expiry: Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000)),
+            },
+            "drawbridge expires=2017-07-14T02:40:00Z",
+        );
+        test_display_and_parse(
+            IpRuleMeta {
+                owner: Some("alice@laptop".to_owned()),
+                expiry: Some(UNIX_EPOCH + Duration::from_secs(1_500_000_000)),
+            },
+            "drawbridge owner=alice@laptop expires=2017-07-14T02:40:00Z",
+        );
+        assert!("allow office".parse::<IpRuleMeta>().is_err());
+        assert!("drawbridge owner=".parse::<IpRuleMeta>().is_err());
+    }
+
+    #[test]
+    fn test_rule_meta_expiry_after() {
+        let max = Duration::from_secs(MAX_EXPIRY_SECS);
+        let expiry = IpRuleMeta::expiry_after(UNIX_EPOCH, max).unwrap();
+        assert_eq!(
+            "9999-12-31T23:59:59Z",
+            humantime::format_rfc3339_seconds(expiry).to_string()
+        );
+        let later = max + Duration::from_secs(1);
+        assert_eq!(None, IpRuleMeta::expiry_after(UNIX_EPOCH, later));
+        let now = UNIX_EPOCH + Duration::from_secs(1);
+        assert_eq!(
+            None,
+            IpRuleMeta::expiry_after(now, Duration::from_secs(u64::MAX))
+        );
     }
 
     fn test_display_and_parse<V>(v: V, s: &str)
@@ -3,20 +3,39 @@ extern crate failure;
 
 mod cli;
 mod cloud;
+mod config;
+mod credentials;
 mod dns;
+mod future;
 mod iprules;
+mod retry;
+mod wait;
 
 use crate::cloud::aws::AwsCloud;
 use crate::dns::aws::AwsDns;
 use clap;
 use failure::Error;
+use failure::ResultExt;
 use openssl_probe;
 use std::env;
 use std::process;
+use tokio_core::reactor::Core;
 
 fn main() {
     match run() {
-        Ok(()) => (),
+        Ok(report) => {
+            if !report.failures.is_empty() {
+                eprintln!(
+                    "Failed for {} of the selected resources",
+                    report.failures.len()
+                );
+                // A timeout only if nothing else went wrong, so that scripts may retry
+                if report.failures.iter().all(|x| x.timed_out) {
+                    process::exit(124)
+                }
+                process::exit(1)
+            }
+        }
         Err(error) => {
             // TODO(NLL)
             {
@@ -24,20 +43,49 @@ fn main() {
                     err.exit();
                 }
             }
-            eprintln!("{}", error);
+            eprintln!("{}", cli::describe_error(&error));
+            // As for timeout(1), so that scripts may retry
+            if wait::is_timeout(&error) {
+                process::exit(124)
+            }
             process::exit(1)
         }
     }
 }
 
-fn run() -> Result<(), Error> {
+fn run() -> Result<cli::Report, Error> {
     // For e.g. Termux support on Android
     openssl_probe::init_ssl_cert_env_vars();
 
-    let cmd = cli::parse_from_safe(env::args_os())?;
+    // One reactor for all requests, so that they may be made together
+    let mut core = Core::new().context("failed to create core reactor")?;
+
+    let (cmd, opts) = cli::parse_from_safe(env::args_os(), &mut core)?;
+    let command = cmd.name();
+
+    // Once the command is known, its result is printed even if it fails
+    let result = dispatch(cmd, &opts, &mut core);
+    match (opts.output, result.as_ref()) {
+        (cli::OutputFormat::Json, Ok(report)) => {
+            println!("{}", serde_json::to_string_pretty(report)?)
+        }
+        (cli::OutputFormat::Json, Err(error)) => {
+            let error_report = cli::ErrorReport::new(command, error);
+            println!("{}", serde_json::to_string_pretty(&error_report)?)
+        }
+        (cli::OutputFormat::Text, Ok(report)) => print!("{}", report),
+        // the error is printed to stderr by main
+        (cli::OutputFormat::Text, Err(_)) => {}
+    }
+
+    result
+}
 
-    let cloud = AwsCloud::new()?;
-    let dns = AwsDns::new()?;
+fn dispatch(cmd: cli::Command, opts: &cli::Options, core: &mut Core) -> Result<cli::Report, Error> {
+    let profiles = [opts.profile.as_deref(), opts.dns_profile.as_deref()];
+    let credentials = credentials::load_profiles(&profiles, core)?;
+    let cloud = AwsCloud::new(core, &opts.regions, credentials[0].clone())?;
+    let dns = AwsDns::new(&core.handle(), credentials[1].clone())?;
 
-    cli::dispatch(cmd, &cloud, &dns)
+    cli::dispatch(cmd, opts, &cloud, &dns, core)
 }
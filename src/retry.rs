@@ -0,0 +1,233 @@
+use crate::wait;
+use failure::Fail;
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
+use rusoto_ec2::AuthorizeSecurityGroupEgressError;
+use rusoto_ec2::AuthorizeSecurityGroupIngressError;
+use rusoto_ec2::DescribeInstancesError;
+use rusoto_ec2::DescribeRegionsError;
+use rusoto_ec2::DescribeSecurityGroupsError;
+use rusoto_ec2::ModifyInstanceAttributeError;
+use rusoto_ec2::RevokeSecurityGroupEgressError;
+use rusoto_ec2::RevokeSecurityGroupIngressError;
+use rusoto_ec2::StartInstancesError;
+use rusoto_ec2::StopInstancesError;
+use rusoto_ec2::UpdateSecurityGroupRuleDescriptionsEgressError;
+use rusoto_ec2::UpdateSecurityGroupRuleDescriptionsIngressError;
+use rusoto_route53::ChangeResourceRecordSetsError;
+use rusoto_route53::GetChangeError;
+use rusoto_route53::GetHostedZoneError;
+use rusoto_route53::ListHostedZonesError;
+use rusoto_route53::ListResourceRecordSetsError;
+use rusoto_sts::AssumeRoleError;
+use std::cmp;
+use std::fmt;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_INTERVAL: Duration = Duration::from_secs(8);
+
+// The error codes with which AWS asks us to slow down or try again
+const RETRYABLE_CODES: &[&str] = &[
+    "RequestLimitExceeded",
+    "Throttling",
+    "ThrottlingException",
+    "RequestThrottled",
+    "InternalError",
+    "InternalFailure",
+    "ServiceUnavailable",
+    "Unavailable",
+];
+
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+// Most AWS error codes are not modelled by rusoto, so are found in the raw response
+fn has_retryable_code(body: &str) -> bool {
+    RETRYABLE_CODES
+        .iter()
+        .any(|code| body.contains(&format!("<Code>{}</Code>", code)))
+}
+
+// Network errors are retried too, unless @impl is given false for a request
+// that cannot safely be repeated, as are any extra variants given
+macro_rules! impl_retryable {
+    ($error:ident $(, $variant:ident)*) => {
+        impl_retryable!(@impl $error, true $(, $variant)*);
+    };
+    (@impl $error:ident, $dispatch:expr $(, $variant:ident)*) => {
+        impl Retryable for $error {
+            fn is_retryable(&self) -> bool {
+                match *self {
+                    $error::HttpDispatch(_) => $dispatch,
+                    $error::Unknown(ref body) => has_retryable_code(body),
+                    $($error::$variant(_) => true,)*
+                    _ => false,
+                }
+            }
+        }
+    };
+}
+
+impl_retryable!(DescribeInstancesError);
+impl_retryable!(DescribeRegionsError);
+impl_retryable!(DescribeSecurityGroupsError);
+impl_retryable!(ModifyInstanceAttributeError);
+impl_retryable!(StartInstancesError);
+impl_retryable!(StopInstancesError);
+impl_retryable!(UpdateSecurityGroupRuleDescriptionsEgressError);
+impl_retryable!(UpdateSecurityGroupRuleDescriptionsIngressError);
+// After a network error, the rules may have been changed already, and
+// authorizing a rule that exists, or revoking one that does not, fails
+impl_retryable!(@impl AuthorizeSecurityGroupEgressError, false);
+impl_retryable!(@impl AuthorizeSecurityGroupIngressError, false);
+impl_retryable!(@impl RevokeSecurityGroupEgressError, false);
+impl_retryable!(@impl RevokeSecurityGroupIngressError, false);
+// Route 53 rejects changes to a zone while an earlier change is being processed
+impl_retryable!(ChangeResourceRecordSetsError, PriorRequestNotComplete);
+impl_retryable!(GetChangeError);
+impl_retryable!(GetHostedZoneError);
+impl_retryable!(ListHostedZonesError);
+impl_retryable!(ListResourceRecordSetsError);
+impl_retryable!(AssumeRoleError);
+
+// The error from the final attempt
+#[derive(Debug)]
+pub struct RetryError<E> {
+    attempts: u32,
+    error: E,
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.attempts {
+            1 => write!(f, "{} (after 1 attempt)", self.error),
+            n => write!(f, "{} (after {} attempts)", self.error, n),
+        }
+    }
+}
+
+// The final error is shown in the message, so is not also a cause
+impl<E: Fail> Fail for RetryError<E> {}
+
+// Calls f until its future succeeds, fails with an error that is not retryable,
+// or has been called MAX_ATTEMPTS times. The wait in between is on the reactor,
+// so that other requests carry on meanwhile.
+pub fn retry<T, E, F, R>(handle: &Handle, f: F) -> Box<dyn Future<Item = T, Error = RetryError<E>>>
+where
+    T: 'static,
+    E: Retryable + fmt::Display + 'static,
+    F: FnMut() -> R + 'static,
+    R: Future<Item = T, Error = E> + 'static,
+{
+    let handle = handle.clone();
+    Box::new(future::loop_fn(
+        (f, 1, INITIAL_INTERVAL),
+        move |(mut f, attempts, interval)| {
+            let handle = handle.clone();
+            f().then(move |result| -> Box<dyn Future<Item = _, Error = _>> {
+                match result {
+                    Ok(value) => Box::new(future::ok(Loop::Break(value))),
+                    Err(error) => {
+                        if attempts >= MAX_ATTEMPTS || !error.is_retryable() {
+                            return Box::new(future::err(RetryError { attempts, error }));
+                        }
+                        eprintln!("Retrying after error (attempt {}): {}", attempts, error);
+                        let next_interval = cmp::min(interval * 2, MAX_INTERVAL);
+                        // A failure to sleep is no reason not to try again
+                        Box::new(
+                            wait::sleep(&handle, wait::jitter(interval)).then(move |_| {
+                                Ok(Loop::Continue((f, attempts + 1, next_interval)))
+                            }),
+                        )
+                    }
+                }
+            })
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::HttpDispatchError;
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    fn throttling_error() -> DescribeInstancesError {
+        DescribeInstancesError::Unknown(
+            "<Response><Errors><Error><Code>RequestLimitExceeded</Code>\
+             <Message>Request limit exceeded.</Message></Error></Errors></Response>"
+                .to_owned(),
+        )
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(throttling_error().is_retryable());
+        assert!(!DescribeInstancesError::Unknown(
+            "<Response><Errors><Error><Code>InvalidInstanceID.NotFound</Code>\
+             </Error></Errors></Response>"
+                .to_owned()
+        )
+        .is_retryable());
+        assert!(!DescribeInstancesError::Validation("bad".to_owned()).is_retryable());
+        assert!(
+            ChangeResourceRecordSetsError::PriorRequestNotComplete("busy".to_owned())
+                .is_retryable()
+        );
+        assert!(
+            !ChangeResourceRecordSetsError::InvalidChangeBatch("bad".to_owned()).is_retryable()
+        );
+
+        let network_error = || HttpDispatchError::from(io::Error::other("reset"));
+        assert!(DescribeInstancesError::HttpDispatch(network_error()).is_retryable());
+        assert!(!AuthorizeSecurityGroupIngressError::HttpDispatch(network_error()).is_retryable());
+        assert!(!RevokeSecurityGroupEgressError::HttpDispatch(network_error()).is_retryable());
+        assert!(AuthorizeSecurityGroupIngressError::Unknown(
+            "<Response><Errors><Error><Code>RequestLimitExceeded</Code>\
+             </Error></Errors></Response>"
+                .to_owned()
+        )
+        .is_retryable());
+    }
+
+    #[test]
+    fn test_retry_succeeds_after_retryable_error() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&attempts);
+        let value = core
+            .run(retry(&core.handle(), move || {
+                counter.set(counter.get() + 1);
+                match counter.get() {
+                    1 => future::err(throttling_error()),
+                    n => future::ok(n),
+                }
+            }))
+            .unwrap();
+        assert_eq!(2, value);
+        assert_eq!(2, attempts.get());
+    }
+
+    #[test]
+    fn test_retry_gives_up_on_other_errors() {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&attempts);
+        let err = core
+            .run(retry(&core.handle(), move || {
+                counter.set(counter.get() + 1);
+                future::err::<(), _>(DescribeInstancesError::Validation("bad".to_owned()))
+            }))
+            .unwrap_err();
+        assert_eq!(1, attempts.get());
+        assert_eq!("bad (after 1 attempt)", err.to_string());
+    }
+}
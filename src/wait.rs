@@ -0,0 +1,124 @@
+use failure::Error;
+use failure::Fail;
+use futures::future;
+use futures::Future;
+use rand::Rng;
+use std::cmp;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+use tokio_core::reactor::Core;
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Timeout;
+
+const INITIAL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(16);
+
+// Distinguished from other errors so that it may have its own exit code
+#[derive(Debug)]
+pub struct TimeoutError {
+    what: String,
+    timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {} waiting for {}",
+            humantime::format_duration(self.timeout),
+            self.what
+        )
+    }
+}
+
+impl Fail for TimeoutError {}
+
+// Whether the error, or any of its causes, is a timeout
+pub fn is_timeout(error: &Error) -> bool {
+    error
+        .causes()
+        .any(|cause| cause.downcast_ref::<TimeoutError>().is_some())
+}
+
+pub fn timeout_error(what: &str, timeout: Duration) -> Error {
+    TimeoutError {
+        what: what.to_owned(),
+        timeout,
+    }
+    .into()
+}
+
+// Sleeps between rounds of polling, with exponential backoff, until the deadline.
+// The jitter avoids polling in lockstep with other clients.
+pub struct Backoff {
+    deadline: Instant,
+    interval: Duration,
+}
+
+impl Backoff {
+    pub fn new(timeout: Duration) -> Backoff {
+        Backoff {
+            deadline: Instant::now() + timeout,
+            interval: INITIAL_INTERVAL,
+        }
+    }
+
+    // Returns false, without sleeping, once the deadline has passed
+    pub fn sleep(&mut self, core: &mut Core) -> Result<bool, Error> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Ok(false);
+        }
+        let duration = cmp::min(jitter(self.interval), self.deadline - now);
+        self.interval = cmp::min(self.interval * 2, MAX_INTERVAL);
+        core.run(sleep(&core.handle(), duration))?;
+        Ok(true)
+    }
+}
+
+// Sleeps on the reactor, rather than blocking it, so that other futures may proceed
+pub fn sleep(handle: &Handle, duration: Duration) -> impl Future<Item = (), Error = Error> {
+    future::result(Timeout::new(duration, handle))
+        .flatten()
+        .from_err()
+}
+
+// A random duration between half the interval and the whole interval
+pub fn jitter(interval: Duration) -> Duration {
+    let half_millis = interval.as_secs() * 1000 / 2 + u64::from(interval.subsec_millis()) / 2;
+    let extra_millis = rand::thread_rng().gen_range(0, half_millis + 1);
+    Duration::from_millis(half_millis + extra_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_sleeps_until_deadline() {
+        let mut core = Core::new().unwrap();
+        let mut backoff = Backoff::new(Duration::from_millis(10));
+        assert!(backoff.sleep(&mut core).unwrap());
+        assert!(!backoff.sleep(&mut core).unwrap());
+    }
+
+    #[test]
+    fn test_timeout_error() {
+        let err = timeout_error("instance to be running", Duration::from_millis(10));
+        assert!(is_timeout(&err));
+        assert_eq!(
+            "timed out after 10ms waiting for instance to be running",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        for _ in 0..100 {
+            let x = jitter(Duration::from_secs(4));
+            assert!(x >= Duration::from_secs(2));
+            assert!(x <= Duration::from_secs(4));
+        }
+    }
+}